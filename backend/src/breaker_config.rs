@@ -0,0 +1,148 @@
+//! Circuit-breaker tuning, loaded from a TOML file: a default config
+//! plus overrides keyed by host glob patterns, so operators can give
+//! known-flaky hosts a higher threshold and critical ones a fast trip
+//! without recompiling.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use rebe_shell::ssh::BreakerConfig;
+
+/// Breaker knobs as they appear in the file; omitted fields fall back
+/// to the library defaults (or, in an override, the file's default).
+#[derive(Debug, Default, Deserialize)]
+struct BreakerSettings {
+    failure_threshold: Option<u32>,
+    cooldown_secs: Option<u64>,
+}
+
+impl BreakerSettings {
+    fn apply(&self, base: BreakerConfig) -> BreakerConfig {
+        BreakerConfig {
+            failure_threshold: self.failure_threshold.unwrap_or(base.failure_threshold),
+            cooldown: self
+                .cooldown_secs
+                .map(Duration::from_secs)
+                .unwrap_or(base.cooldown),
+        }
+    }
+}
+
+/// Shape of the file: top-level defaults plus `[[override]]` tables,
+/// each with a `pattern` and the settings it imposes.
+#[derive(Debug, Deserialize)]
+struct BreakerFile {
+    #[serde(flatten)]
+    default: BreakerSettings,
+    #[serde(default, rename = "override")]
+    overrides: Vec<OverrideEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OverrideEntry {
+    /// Host glob (`*`, `?`) the settings apply to.
+    pattern: String,
+    #[serde(flatten)]
+    settings: BreakerSettings,
+}
+
+/// Parsed tuning, ready to hand to
+/// [`CircuitBreaker`](rebe_shell::ssh::CircuitBreaker).
+#[derive(Debug)]
+pub struct BreakerTuning {
+    pub default: BreakerConfig,
+    pub overrides: Vec<(String, BreakerConfig)>,
+}
+
+impl BreakerTuning {
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let file: BreakerFile = toml::from_str(text)?;
+        let default = file.default.apply(BreakerConfig::default());
+        let overrides = file
+            .overrides
+            .iter()
+            .map(|o| (o.pattern.clone(), o.settings.apply(default)))
+            .collect();
+        Ok(Self { default, overrides })
+    }
+}
+
+/// Load and parse the breaker tuning at `path`.
+pub fn load_breaker_config(path: impl AsRef<Path>) -> anyhow::Result<BreakerTuning> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading breaker config {}", path.display()))?;
+    BreakerTuning::from_toml(&text)
+        .with_context(|| format!("parsing breaker config {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rebe_shell::ssh::{BreakerState, CircuitBreaker, HostKey};
+
+    const FILE: &str = r#"
+        failure_threshold = 3
+        cooldown_secs = 10
+
+        [[override]]
+        pattern = "flaky*.example"
+        failure_threshold = 20
+
+        [[override]]
+        pattern = "db?.example"
+        failure_threshold = 1
+        cooldown_secs = 300
+    "#;
+
+    #[test]
+    fn parses_defaults_and_overrides() {
+        let tuning = BreakerTuning::from_toml(FILE).unwrap();
+        assert_eq!(tuning.default.failure_threshold, 3);
+        assert_eq!(tuning.default.cooldown, Duration::from_secs(10));
+
+        // An override only states what it changes; the rest comes from
+        // the file's default.
+        let (pattern, flaky) = &tuning.overrides[0];
+        assert_eq!(pattern, "flaky*.example");
+        assert_eq!(flaky.failure_threshold, 20);
+        assert_eq!(flaky.cooldown, Duration::from_secs(10));
+
+        let (_, db) = &tuning.overrides[1];
+        assert_eq!(db.failure_threshold, 1);
+        assert_eq!(db.cooldown, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn matching_hosts_get_the_pattern_config() {
+        let tuning = BreakerTuning::from_toml(FILE).unwrap();
+        let breaker =
+            CircuitBreaker::new(tuning.default).with_overrides(tuning.overrides);
+
+        // db1 matches `db?.example`: one failure trips it.
+        let db = HostKey::new("db1.example", 22, "ops");
+        breaker.record_failure(&db);
+        assert_eq!(breaker.state(&db), BreakerState::Open);
+
+        // An unmatched host uses the file default of 3.
+        let web = HostKey::new("web1.example", 22, "ops");
+        breaker.record_failure(&web);
+        breaker.record_failure(&web);
+        assert_eq!(breaker.state(&web), BreakerState::Closed);
+        breaker.record_failure(&web);
+        assert_eq!(breaker.state(&web), BreakerState::Open);
+    }
+
+    #[test]
+    fn empty_file_means_library_defaults() {
+        let tuning = BreakerTuning::from_toml("").unwrap();
+        assert_eq!(
+            tuning.default.failure_threshold,
+            BreakerConfig::default().failure_threshold
+        );
+        assert!(tuning.overrides.is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+//! Thing discovery: a configurable registry of known things, filtered
+//! and probed for reachability on demand.
+
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// How long one reachability probe may take. Probes run concurrently,
+/// so a slow or dead thing delays discovery by at most this much.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// One registered thing.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegistryEntry {
+    pub name: String,
+    /// `host:port`, probed over TCP.
+    pub endpoint: String,
+    pub provides: Vec<String>,
+    /// Things this entry serves; empty means everyone.
+    #[serde(default)]
+    pub for_things: Vec<String>,
+}
+
+/// Shape of the TOML registry file: a list of `[[things]]` tables.
+#[derive(Debug, Deserialize)]
+struct RegistryFile {
+    things: Vec<RegistryEntry>,
+}
+
+/// A discovered thing, with the probe outcome attached.
+#[derive(Debug, Serialize)]
+pub struct Thing {
+    pub name: String,
+    pub endpoint: String,
+    pub provides: Vec<String>,
+    pub reachable: bool,
+}
+
+/// The set of things this backend can discover.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    entries: Vec<RegistryEntry>,
+}
+
+impl Registry {
+    /// Load the registry from the TOML file named by
+    /// `REBE_REGISTRY_FILE`, falling back to the built-in localhost
+    /// entries for development setups.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("REBE_REGISTRY_FILE") {
+            Ok(path) => {
+                let text = std::fs::read_to_string(&path)
+                    .with_context(|| format!("reading registry file {path}"))?;
+                Self::from_toml(&text).with_context(|| format!("parsing registry file {path}"))
+            }
+            Err(_) => Ok(Self::default_local()),
+        }
+    }
+
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let file: RegistryFile = toml::from_str(text)?;
+        Ok(Self {
+            entries: file.things,
+        })
+    }
+
+    /// The localhost things a development stack runs.
+    pub fn default_local() -> Self {
+        Self {
+            entries: vec![
+                RegistryEntry {
+                    name: "rebe-registry".to_string(),
+                    endpoint: "localhost:3031".to_string(),
+                    provides: vec!["registry".to_string()],
+                    for_things: Vec::new(),
+                },
+                RegistryEntry {
+                    name: "rebe-agent".to_string(),
+                    endpoint: "localhost:8080".to_string(),
+                    provides: vec!["terminal".to_string(), "ssh".to_string()],
+                    for_things: Vec::new(),
+                },
+            ],
+        }
+    }
+
+    /// Filter the registry and probe the matches concurrently.
+    ///
+    /// Unreachable things are still returned (with `reachable: false`)
+    /// so callers get partial results rather than an error when part of
+    /// the deployment is down.
+    pub async fn discover(&self, capability: Option<&str>, for_thing: Option<&str>) -> Vec<Thing> {
+        let probes = self
+            .entries
+            .iter()
+            .filter(|entry| {
+                capability.is_none_or(|c| entry.provides.iter().any(|p| p == c))
+                    && for_thing.is_none_or(|t| {
+                        entry.for_things.is_empty() || entry.for_things.iter().any(|f| f == t)
+                    })
+            })
+            .map(|entry| async move {
+                let connect = tokio::net::TcpStream::connect(&entry.endpoint);
+                let reachable = matches!(
+                    tokio::time::timeout(PROBE_TIMEOUT, connect).await,
+                    Ok(Ok(_))
+                );
+                Thing {
+                    name: entry.name.clone(),
+                    endpoint: entry.endpoint.clone(),
+                    provides: entry.provides.clone(),
+                    reachable,
+                }
+            });
+        futures::future::join_all(probes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> Registry {
+        Registry::from_toml(
+            r#"
+            [[things]]
+            name = "registry"
+            endpoint = "127.0.0.1:1"
+            provides = ["registry"]
+
+            [[things]]
+            name = "agent"
+            endpoint = "127.0.0.1:1"
+            provides = ["terminal", "ssh"]
+            for_things = ["frontend"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn filters_by_capability_and_for_thing() {
+        let registry = registry();
+
+        let all = registry.discover(None, None).await;
+        assert_eq!(all.len(), 2);
+
+        let ssh = registry.discover(Some("ssh"), None).await;
+        assert_eq!(ssh.len(), 1);
+        assert_eq!(ssh[0].name, "agent");
+
+        // The agent only serves "frontend"; others don't see it.
+        let for_frontend = registry.discover(None, Some("frontend")).await;
+        assert_eq!(for_frontend.len(), 2);
+        let for_other = registry.discover(None, Some("migrator")).await;
+        assert_eq!(for_other.len(), 1);
+        assert_eq!(for_other[0].name, "registry");
+    }
+
+    #[tokio::test]
+    async fn probes_report_partial_reachability() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let up = listener.local_addr().unwrap();
+        let registry = Registry::from_toml(&format!(
+            r#"
+            [[things]]
+            name = "up"
+            endpoint = "{up}"
+            provides = ["registry"]
+
+            [[things]]
+            name = "down"
+            endpoint = "127.0.0.1:1"
+            provides = ["registry"]
+            "#,
+        ))
+        .unwrap();
+
+        let things = registry.discover(None, None).await;
+        assert_eq!(things.len(), 2);
+        assert!(things.iter().find(|t| t.name == "up").unwrap().reachable);
+        assert!(!things.iter().find(|t| t.name == "down").unwrap().reachable);
+    }
+}
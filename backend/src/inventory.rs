@@ -0,0 +1,132 @@
+//! Host inventory: the fleet's hosts, loaded from a TOML file, for
+//! batch operations (pool warmup, bulk execution) that need a host
+//! list rather than one ad-hoc target.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use rebe_shell::ssh::HostKey;
+
+/// One host in the inventory file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InventoryHost {
+    pub name: String,
+    /// Hostname or address the SSH connection goes to.
+    pub addr: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub user: String,
+    /// Private key used to authenticate as `user`, for key-based
+    /// setups.
+    pub key_path: Option<PathBuf>,
+    /// Free-form group labels (`web`, `db`, `canary`) for addressing a
+    /// subset of the fleet.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+/// Shape of the TOML inventory file: a list of `[[host]]` tables.
+#[derive(Debug, Deserialize)]
+struct InventoryFile {
+    host: Vec<InventoryHost>,
+}
+
+/// A parsed host inventory.
+#[derive(Debug, Clone)]
+pub struct Inventory {
+    hosts: Vec<InventoryHost>,
+}
+
+impl Inventory {
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let file: InventoryFile = toml::from_str(text)?;
+        Ok(Self { hosts: file.host })
+    }
+
+    pub fn hosts(&self) -> &[InventoryHost] {
+        &self.hosts
+    }
+
+    /// Every host as a pool key, in file order.
+    pub fn host_keys(&self) -> Vec<HostKey> {
+        self.hosts
+            .iter()
+            .map(|h| HostKey::new(h.addr.clone(), h.port, h.user.clone()))
+            .collect()
+    }
+
+    /// The pool keys of hosts labelled with `group`.
+    pub fn group(&self, group: &str) -> Vec<HostKey> {
+        self.hosts
+            .iter()
+            .filter(|h| h.groups.iter().any(|g| g == group))
+            .map(|h| HostKey::new(h.addr.clone(), h.port, h.user.clone()))
+            .collect()
+    }
+}
+
+/// Load and parse the inventory at `path`.
+pub fn load_inventory(path: impl AsRef<Path>) -> anyhow::Result<Inventory> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading inventory file {}", path.display()))?;
+    Inventory::from_toml(&text)
+        .with_context(|| format!("parsing inventory file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+        [[host]]
+        name = "web1"
+        addr = "web1.example"
+        user = "deploy"
+        groups = ["web"]
+
+        [[host]]
+        name = "db1"
+        addr = "db1.example"
+        port = 2222
+        user = "ops"
+        key_path = "/etc/rebe/keys/db1"
+        groups = ["db", "canary"]
+    "#;
+
+    #[test]
+    fn parses_hosts_with_defaults_and_key_paths() {
+        let inventory = Inventory::from_toml(FILE).unwrap();
+        assert_eq!(
+            inventory.host_keys(),
+            vec![
+                HostKey::new("web1.example", 22, "deploy"),
+                HostKey::new("db1.example", 2222, "ops"),
+            ]
+        );
+        let db1 = &inventory.hosts()[1];
+        assert_eq!(db1.key_path.as_deref(), Some(Path::new("/etc/rebe/keys/db1")));
+    }
+
+    #[test]
+    fn groups_select_a_subset() {
+        let inventory = Inventory::from_toml(FILE).unwrap();
+        assert_eq!(
+            inventory.group("canary"),
+            vec![HostKey::new("db1.example", 2222, "ops")]
+        );
+        assert!(inventory.group("nonexistent").is_empty());
+    }
+
+    #[test]
+    fn load_inventory_names_the_file_in_errors() {
+        let err = load_inventory("/nonexistent/inventory.toml").unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/inventory.toml"));
+    }
+}
@@ -0,0 +1,2424 @@
+//! The rebe-shell backend: an HTTP/WebSocket server exposing local PTY
+//! sessions, pooled SSH execution, and thing discovery.
+
+mod breaker_config;
+mod discovery;
+mod inventory;
+mod policy;
+mod router;
+mod shell_profiles;
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{Message, WebSocket};
+use axum::extract::{Query, Request, State, WebSocketUpgrade};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::extract::Path;
+use axum::routing::{delete, get, post};
+use anyhow::Context as _;
+use axum::{Json, Router};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use discovery::{Registry, Thing};
+use policy::CommandPolicy;
+use router::{CommandRouter, DefaultRouter, RoutedCommand, SshCommand};
+use rebe_shell::events::EventBus;
+use rebe_shell::execute::{retry_with_breaker, Executor};
+use rebe_shell::protocol::{CommandRequest, CommandResponse, RetryPolicy};
+use rebe_shell::pty::{PtyManager, SessionOptions};
+use rebe_shell::ssh::{
+    AuthMethod, BreakerState, CircuitBreaker, HostKey, SSHPool, SshError, StreamEvent,
+};
+
+struct AppState {
+    pty_manager: PtyManager,
+    ssh_pool: Arc<SSHPool>,
+    executor: Executor,
+    registry: Registry,
+    policy: CommandPolicy,
+    /// Decides whether a completed input line runs locally or through
+    /// the SSH pool; swap in a custom impl to add routing rules.
+    command_router: Box<dyn CommandRouter>,
+    breaker: CircuitBreaker,
+    /// Named shells selectable at session creation via `shell_profile`.
+    shell_profiles: shell_profiles::ShellProfiles,
+    /// Bus the pool, breaker, and PTY manager publish lifecycle events
+    /// to; `/api/events` streams it to subscribers.
+    events: Arc<EventBus>,
+    /// Bearer token required on every route when set.
+    auth_token: Option<String>,
+    /// Fired once on SIGTERM/SIGINT so live sessions can say goodbye.
+    shutdown: broadcast::Sender<()>,
+    commands_executed: AtomicU64,
+    commands_failed: AtomicU64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        None => {}
+        Some("--exec") => return exec_once().await,
+        Some(other) => anyhow::bail!(
+            "unknown argument {other:?}; run with no arguments to serve, \
+             or --exec to execute one JSON request from stdin"
+        ),
+    }
+    tracing_subscriber::fmt::init();
+
+    let auth_token = std::env::var("REBE_AUTH_TOKEN").ok();
+    if auth_token.is_none() {
+        warn!("REBE_AUTH_TOKEN not set; the backend is running without authentication");
+    }
+
+    let events = Arc::new(EventBus::default());
+    let pool_config = rebe_shell::ssh::PoolConfig {
+        max_output_bytes: max_output_bytes(),
+        ..rebe_shell::ssh::PoolConfig::default()
+    };
+    let audit = audit_log()?;
+    let mut ssh_pool = SSHPool::with_config(pool_config).with_events(events.clone());
+    if let Some(log) = &audit {
+        ssh_pool = ssh_pool.with_audit(log.clone());
+    }
+    let ssh_pool = Arc::new(ssh_pool);
+    let ssh_auth = AuthMethod::Password(std::env::var("REBE_SSH_PASSWORD").unwrap_or_default());
+    let warmup_auth = ssh_auth.clone();
+    let preview_root = std::env::var("REBE_PREVIEW_ROOT")
+        .map(Into::into)
+        .unwrap_or_else(|_| std::env::temp_dir());
+
+    let mut pty_manager = match std::env::var("REBE_MAX_SESSIONS").ok().and_then(|v| v.parse().ok()) {
+        Some(max) => PtyManager::with_limit(max),
+        None => PtyManager::new(),
+    };
+    if let Some(log) = &audit {
+        pty_manager = pty_manager.with_audit(log.clone());
+    }
+
+    let state = Arc::new(AppState {
+        pty_manager: pty_manager.with_events(events.clone()),
+        ssh_pool: ssh_pool.clone(),
+        executor: Executor::new(preview_root, ssh_pool, ssh_auth)?,
+        registry: Registry::from_env()?,
+        policy: CommandPolicy::from_env()?,
+        command_router: Box::new(DefaultRouter),
+        breaker: breaker()?.with_events(events.clone()),
+        shell_profiles: shell_profiles()?,
+        events,
+        auth_token,
+        shutdown: broadcast::channel(1).0,
+        commands_executed: AtomicU64::new(0),
+        commands_failed: AtomicU64::new(0),
+    });
+
+    // Pre-connect to the inventoried fleet so the first command to
+    // each host doesn't pay the handshake cost.
+    if let Ok(path) = std::env::var("REBE_INVENTORY_FILE") {
+        let inventory = inventory::load_inventory(&path)?;
+        info!("loaded inventory of {} hosts from {path}", inventory.hosts().len());
+        for host in inventory.hosts() {
+            if host.key_path.is_some() {
+                warn!(
+                    "inventory host {}: key_path is not supported yet, \
+                     using the configured password auth",
+                    host.name
+                );
+            }
+        }
+        let state = state.clone();
+        tokio::spawn(async move {
+            // `REBE_WARMUP_GROUP` restricts warmup to one group of the
+            // fleet; everything otherwise.
+            let keys = match std::env::var("REBE_WARMUP_GROUP") {
+                Ok(group) => inventory.group(&group),
+                Err(_) => inventory.host_keys(),
+            };
+            let total = keys.len();
+            let outcomes = state
+                .ssh_pool
+                .warmup(keys, &warmup_auth, WARMUP_CONCURRENCY)
+                .await;
+            let warmed = outcomes.iter().filter(|(_, o)| o.is_ok()).count();
+            info!("pool warmup: {warmed}/{total} hosts connected");
+            for (key, outcome) in outcomes {
+                if let Err(e) = outcome {
+                    warn!("warmup of {key} failed: {e:#}");
+                }
+            }
+        });
+    }
+
+    {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                ticker.tick().await;
+                for id in state.pty_manager.reap_idle(SESSION_IDLE_TIMEOUT).await {
+                    info!("reaped idle pty session {id}");
+                }
+            }
+        });
+    }
+
+    let app = router(state.clone());
+
+    let addr = bind_addr(std::env::var("REBE_BIND_ADDR").ok())?;
+    let handle = axum_server::Handle::new();
+    {
+        let handle = handle.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            shutdown_signal(state).await;
+            handle.graceful_shutdown(Some(std::time::Duration::from_secs(5)));
+        });
+    }
+
+    // TLS when a cert/key pair is configured, plain HTTP otherwise so
+    // local development keeps working. Clients use wss:// automatically
+    // once the page is served over https.
+    match (std::env::var("REBE_TLS_CERT"), std::env::var("REBE_TLS_KEY")) {
+        (Ok(cert), Ok(key)) => {
+            let tls = axum_server::tls_rustls::RustlsConfig::from_pem_file(&cert, &key)
+                .await
+                .with_context(|| format!("loading TLS cert {cert} / key {key}"))?;
+            info!("listening on https://{addr}");
+            axum_server::bind_rustls(addr, tls)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        _ => {
+            warn!(
+                "listening on http://{addr} without TLS; \
+                 set REBE_TLS_CERT and REBE_TLS_KEY in production"
+            );
+            axum_server::bind(addr)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
+
+    // WebSocket handlers have said goodbye and closed their sessions;
+    // sweep up anything that remains.
+    state.pty_manager.close_all().await;
+    state.ssh_pool.shutdown().await;
+    info!("shutdown complete");
+    Ok(())
+}
+
+/// All interfaces; use `REBE_BIND_ADDR=127.0.0.1:3000` for
+/// local-only exposure or to run several backends on one host.
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:3000";
+
+/// Concurrent handshakes during inventory warmup: enough to bring a
+/// sizeable fleet up quickly without a thundering herd at startup.
+const WARMUP_CONCURRENCY: usize = 8;
+
+/// How long a session may sit with no attached client before the
+/// reaper closes it.
+const SESSION_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// How often the WebSocket writer pings the client, so intermediaries
+/// with idle timeouts see traffic.
+const WS_PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// A connection with no pong (or any other client traffic) for this
+/// long is considered dead and torn down.
+const WS_PONG_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(90);
+
+/// How long a line-buffered connection holds a partial output line
+/// before flushing it anyway, so prompts still appear promptly.
+const LINE_FLUSH_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How often the output pump polls for child exit while the PTY is
+/// quiet, so a session whose shell exited tears down promptly instead
+/// of waiting for the client to disconnect.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The effective bind address: `REBE_BIND_ADDR` when set (and valid),
+/// [`DEFAULT_BIND_ADDR`] otherwise.
+fn bind_addr(var: Option<String>) -> anyhow::Result<std::net::SocketAddr> {
+    let raw = var.unwrap_or_else(|| DEFAULT_BIND_ADDR.to_string());
+    raw.parse()
+        .with_context(|| format!("REBE_BIND_ADDR {raw:?} is not a valid socket address"))
+}
+
+/// Resolves when SIGTERM or SIGINT arrives, after notifying live
+/// sessions so they can drain.
+async fn shutdown_signal(state: Arc<AppState>) {
+    let ctrl_c = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        let mut term =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("installing SIGTERM handler");
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c.await;
+    }
+    info!("shutdown signal received; draining sessions");
+    let _ = state.shutdown.send(());
+}
+
+fn router(state: Arc<AppState>) -> Router {
+    let protected = Router::new()
+        .route("/api/execute", post(execute_command))
+        .route("/api/ssh/execute", post(ssh_execute))
+        .route("/api/sessions", get(list_sessions).post(create_session))
+        .route("/api/sessions/{id}", delete(close_session))
+        .route(
+            "/api/sessions/{id}/record",
+            post(start_session_recording).delete(stop_session_recording),
+        )
+        .route("/api/sessions/{id}/history", get(session_history))
+        .route("/api/discover", get(discover_things))
+        .route("/api/events", get(event_stream))
+        .route("/api/ssh/pool", get(pool_stats))
+        .route("/metrics", get(metrics))
+        .route("/ws", get(ws_handler))
+        .layer(middleware::from_fn_with_state(state.clone(), require_auth));
+
+    Router::new()
+        .route("/health", get(health))
+        .route("/health/live", get(health))
+        .route("/health/ready", get(health_ready))
+        .merge(protected)
+        .with_state(state)
+}
+
+/// Reject requests that don't present the configured bearer token.
+///
+/// WebSocket upgrades may pass the token as a `?token=` query
+/// parameter instead, since browsers cannot set headers on upgrade
+/// requests.
+async fn require_auth(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = state.auth_token.as_deref() else {
+        return next.run(request).await;
+    };
+
+    let header_ok = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|token| token == expected);
+
+    let query_ok = request
+        .uri()
+        .query()
+        .map(|q| {
+            q.split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .any(|(k, v)| k == "token" && v == expected)
+        })
+        .unwrap_or(false);
+
+    if header_ok || query_ok {
+        next.run(request).await
+    } else {
+        (StatusCode::UNAUTHORIZED, "missing or invalid token").into_response()
+    }
+}
+
+/// Pure liveness (`/health`, `/health/live`): the process is up and
+/// serving requests. Never probes subsystems, so orchestrators can
+/// tell "dead" from "degraded".
+async fn health() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Deep readiness (`/health/ready`): spawn-and-close a real PTY
+/// session, and run a trivial command against the canary host named
+/// by `REBE_HEALTH_SSH_CANARY` when one is configured. Answers 503
+/// with per-subsystem details when anything is broken, so a degraded
+/// backend actually shows up in orchestration instead of staying
+/// green.
+async fn health_ready(State(state): State<Arc<AppState>>) -> Response {
+    let mut ready = true;
+    let pty = match readiness_pty_check(&state).await {
+        Ok(()) => "ok".to_string(),
+        Err(e) => {
+            ready = false;
+            format!("{e:#}")
+        }
+    };
+    let ssh_canary = match std::env::var("REBE_HEALTH_SSH_CANARY") {
+        Err(_) => "skipped".to_string(),
+        Ok(target) => match readiness_ssh_check(&state, &target).await {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                ready = false;
+                format!("{e:#}")
+            }
+        },
+    };
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(serde_json::json!({
+            "status": if ready { "ready" } else { "degraded" },
+            "checks": { "pty": pty, "ssh_canary": ssh_canary },
+        })),
+    )
+        .into_response()
+}
+
+/// Spawn and close a throwaway session: exercises the PTY device, the
+/// default shell, and the reader/writer threads end to end.
+async fn readiness_pty_check(state: &AppState) -> anyhow::Result<()> {
+    let id = state.pty_manager.create_session(24, 80).await?;
+    state.pty_manager.close(&id).await
+}
+
+/// One trivial command against the canary host through the pool, with
+/// the usual credentials; fails on unreachability, auth trouble, or a
+/// nonzero exit.
+async fn readiness_ssh_check(state: &AppState, target: &str) -> anyhow::Result<()> {
+    let key = rebe_shell::ssh::HostKey::parse(target)?;
+    let auth = AuthMethod::Password(std::env::var("REBE_SSH_PASSWORD").unwrap_or_default());
+    let output = state
+        .ssh_pool
+        .exec_with_timeout(&key, &auth, "true", Duration::from_secs(5))
+        .await?;
+    if output.exit_status != 0 {
+        anyhow::bail!("canary {target} exited {}", output.exit_status);
+    }
+    Ok(())
+}
+
+/// Prometheus text-format scrape of session, pool, and breaker state.
+async fn metrics(State(state): State<Arc<AppState>>) -> String {
+    let mut out = String::with_capacity(1024);
+
+    let sessions = state.pty_manager.session_count().await;
+    out.push_str("# TYPE rebe_pty_sessions gauge\n");
+    let _ = writeln!(out, "rebe_pty_sessions {sessions}");
+
+    out.push_str("# TYPE rebe_ssh_pool_connections gauge\n");
+    out.push_str("# TYPE rebe_ssh_pool_in_use gauge\n");
+    out.push_str("# TYPE rebe_ssh_connection_alive gauge\n");
+    for stats in state.ssh_pool.stats().await {
+        let host = &stats.host;
+        let _ = writeln!(
+            out,
+            "rebe_ssh_pool_connections{{host=\"{host}\"}} {}",
+            stats.connections
+        );
+        let _ = writeln!(out, "rebe_ssh_pool_in_use{{host=\"{host}\"}} {}", stats.in_use);
+        let _ = writeln!(
+            out,
+            "rebe_ssh_connection_alive{{host=\"{host}\"}} {}",
+            stats.alive as u8
+        );
+    }
+
+    let mut created = 0u64;
+    let mut reused = 0u64;
+    let mut samples: Vec<Duration> = Vec::new();
+    for metrics in state.ssh_pool.detailed_stats().values() {
+        created += metrics.created;
+        reused += metrics.reused;
+        samples.extend_from_slice(&metrics.handshake_samples);
+    }
+    out.push_str("# TYPE rebe_ssh_connections_created_total counter\n");
+    let _ = writeln!(out, "rebe_ssh_connections_created_total {created}");
+    out.push_str("# TYPE rebe_ssh_connections_reused_total counter\n");
+    let _ = writeln!(out, "rebe_ssh_connections_reused_total {reused}");
+    out.push_str("# TYPE rebe_ssh_reuse_ratio gauge\n");
+    let total = created + reused;
+    let ratio = if total == 0 {
+        0.0
+    } else {
+        reused as f64 / total as f64
+    };
+    let _ = writeln!(out, "rebe_ssh_reuse_ratio {ratio}");
+    if !samples.is_empty() {
+        out.push_str("# TYPE rebe_ssh_handshake_seconds summary\n");
+        for quantile in ["0.5", "0.99"] {
+            let latency = percentile(&mut samples, quantile.parse().expect("literal quantile"));
+            let _ = writeln!(
+                out,
+                "rebe_ssh_handshake_seconds{{quantile=\"{quantile}\"}} {}",
+                latency.as_secs_f64()
+            );
+        }
+    }
+
+    out.push_str("# TYPE rebe_circuit_breaker_state gauge\n");
+    for (host, breaker_state) in state.breaker.states() {
+        let value = match breaker_state {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        };
+        let _ = writeln!(out, "rebe_circuit_breaker_state{{host=\"{host}\"}} {value}");
+    }
+
+    out.push_str("# TYPE rebe_commands_executed_total counter\n");
+    let _ = writeln!(
+        out,
+        "rebe_commands_executed_total {}",
+        state.commands_executed.load(Ordering::Relaxed)
+    );
+    out.push_str("# TYPE rebe_commands_failed_total counter\n");
+    let _ = writeln!(
+        out,
+        "rebe_commands_failed_total {}",
+        state.commands_failed.load(Ordering::Relaxed)
+    );
+
+    out
+}
+
+/// Nearest-rank `q`-th percentile (0 < `q` <= 1) of latency samples.
+/// Sorts in place; `samples` must be non-empty.
+fn percentile(samples: &mut [Duration], q: f64) -> Duration {
+    samples.sort_unstable();
+    let rank = ((q * samples.len() as f64).ceil() as usize).clamp(1, samples.len());
+    samples[rank - 1]
+}
+
+#[derive(Serialize)]
+struct PoolHostStats {
+    /// `user@host:port`.
+    host: String,
+    connections: usize,
+    in_use: usize,
+    alive: bool,
+    /// Workload labels attached at checkout time, one per tagged slot.
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PoolStatsResponse {
+    hosts: Vec<PoolHostStats>,
+    total_connections: usize,
+    total_in_use: usize,
+}
+
+/// Per-host and aggregate SSH pool utilization, for spotting leaks
+/// without a full metrics stack. Sits behind auth like everything
+/// else — it reveals infrastructure topology.
+async fn pool_stats(State(state): State<Arc<AppState>>) -> Json<PoolStatsResponse> {
+    let stats = state.ssh_pool.stats().await;
+    let response = PoolStatsResponse {
+        total_connections: stats.iter().map(|s| s.connections).sum(),
+        total_in_use: stats.iter().map(|s| s.in_use).sum(),
+        hosts: stats
+            .into_iter()
+            .map(|s| PoolHostStats {
+                host: s.host.to_string(),
+                connections: s.connections,
+                in_use: s.in_use,
+                alive: s.alive,
+                tags: s.tags,
+            })
+            .collect(),
+    };
+    Json(response)
+}
+
+/// Push feed of breaker, pool, and session lifecycle events as
+/// server-sent events, one JSON object per event.
+///
+/// The underlying bus is bounded and drop-oldest: a subscriber that
+/// falls behind silently misses its oldest events instead of backing
+/// up publishers.
+async fn event_stream(
+    State(state): State<Arc<AppState>>,
+) -> axum::response::sse::Sse<impl futures::Stream<Item = Result<axum::response::sse::Event, std::convert::Infallible>>>
+{
+    let rx = state.events.subscribe();
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event).expect("event serializes");
+                    return Some((Ok(axum::response::sse::Event::default().data(data)), rx));
+                }
+                // Dropped-oldest is by design; keep streaming.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    axum::response::sse::Sse::new(stream)
+        .keep_alive(axum::response::sse::KeepAlive::default())
+}
+
+// ---------------------------------------------------------------------
+// One-shot execution (--exec)
+// ---------------------------------------------------------------------
+
+/// `--exec` mode for scripts and CI: read one [`CommandRequest`] as
+/// JSON on stdin, dispatch it through the same executor the server
+/// uses, print the [`CommandResponse`] on stdout, and exit with
+/// [`exec_exit_code`]. Logs go to stderr so stdout stays parseable.
+async fn exec_once() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    let mut input = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)
+        .context("reading request from stdin")?;
+    let request: CommandRequest =
+        serde_json::from_str(&input).context("parsing CommandRequest from stdin")?;
+
+    let pool_config = rebe_shell::ssh::PoolConfig {
+        max_output_bytes: max_output_bytes(),
+        ..rebe_shell::ssh::PoolConfig::default()
+    };
+    let ssh_pool = Arc::new(SSHPool::with_config(pool_config));
+    let ssh_auth = AuthMethod::Password(std::env::var("REBE_SSH_PASSWORD").unwrap_or_default());
+    let preview_root = std::env::var("REBE_PREVIEW_ROOT")
+        .map(Into::into)
+        .unwrap_or_else(|_| std::env::temp_dir());
+    let executor = Executor::new(preview_root, ssh_pool, ssh_auth)?;
+
+    let response = match request.validate_version() {
+        Err(error) => CommandResponse {
+            version: rebe_shell::protocol::PROTOCOL_VERSION.to_string(),
+            id: request.id,
+            result: rebe_shell::protocol::CommandResult::Error { error },
+            metadata: rebe_shell::protocol::ResponseMetadata::default(),
+        },
+        Ok(()) => executor.execute(request).await,
+    };
+    println!("{}", serde_json::to_string(&response)?);
+    std::process::exit(exec_exit_code(&response));
+}
+
+/// Exit status of `--exec`: `0` for a success result, `1` for an
+/// error result, so scripts can branch without parsing the JSON.
+fn exec_exit_code(response: &CommandResponse) -> i32 {
+    match response.result {
+        rebe_shell::protocol::CommandResult::Success { .. } => 0,
+        rebe_shell::protocol::CommandResult::Error { .. } => 1,
+    }
+}
+
+// ---------------------------------------------------------------------
+// Structured protocol execution
+// ---------------------------------------------------------------------
+
+/// Execute a structured [`CommandRequest`], dispatched on its
+/// execution mode after a protocol version check. Failures are
+/// reported inside the response envelope, so this always answers 200.
+async fn execute_command(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<CommandRequest>,
+) -> Json<CommandResponse> {
+    state.commands_executed.fetch_add(1, Ordering::Relaxed);
+    if let Err(error) = request.validate_version() {
+        state.commands_failed.fetch_add(1, Ordering::Relaxed);
+        return Json(CommandResponse {
+            version: rebe_shell::protocol::PROTOCOL_VERSION.to_string(),
+            id: request.id,
+            result: rebe_shell::protocol::CommandResult::Error { error },
+            metadata: rebe_shell::protocol::ResponseMetadata::default(),
+        });
+    }
+    let response = state.executor.execute(request).await;
+    if matches!(
+        response.result,
+        rebe_shell::protocol::CommandResult::Error { .. }
+    ) {
+        state.commands_failed.fetch_add(1, Ordering::Relaxed);
+    }
+    Json(response)
+}
+
+// ---------------------------------------------------------------------
+// SSH execute (ad-hoc REST endpoint)
+// ---------------------------------------------------------------------
+
+#[derive(Debug, Deserialize)]
+struct SshExecuteRequest {
+    host: String,
+    #[serde(default = "default_ssh_port")]
+    port: u16,
+    username: String,
+    password: String,
+    command: String,
+    #[serde(default)]
+    retry_policy: RetryPolicy,
+    /// Per-attempt timeout; the library default when omitted. Capped
+    /// at the server's maximum.
+    timeout_ms: Option<u64>,
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+/// The circuit breaker, tuned from the TOML file named by
+/// `REBE_BREAKER_CONFIG` when set; library defaults otherwise.
+fn breaker() -> anyhow::Result<CircuitBreaker> {
+    match std::env::var("REBE_BREAKER_CONFIG") {
+        Ok(path) => {
+            let tuning = breaker_config::load_breaker_config(&path)?;
+            info!(
+                "loaded breaker config from {path} ({} overrides)",
+                tuning.overrides.len()
+            );
+            Ok(CircuitBreaker::new(tuning.default).with_overrides(tuning.overrides))
+        }
+        Err(_) => Ok(CircuitBreaker::default()),
+    }
+}
+
+/// The shell-profile map, from the TOML file named by
+/// `REBE_SHELL_PROFILES` when set; the built-in shells found on this
+/// host otherwise.
+fn shell_profiles() -> anyhow::Result<shell_profiles::ShellProfiles> {
+    match std::env::var("REBE_SHELL_PROFILES") {
+        Ok(path) => {
+            let profiles = shell_profiles::load_shell_profiles(&path)?;
+            info!("loaded {} shell profiles from {path}", profiles.len());
+            Ok(profiles)
+        }
+        Err(_) => Ok(shell_profiles::ShellProfiles::builtin()),
+    }
+}
+
+/// The audit trail, appending JSON lines to the file named by
+/// `REBE_AUDIT_LOG` when set; no auditing otherwise.
+/// `REBE_AUDIT_REDACT` is a comma-separated list of glob patterns;
+/// command tokens matching one are redacted before recording.
+fn audit_log() -> anyhow::Result<Option<Arc<rebe_shell::audit::AuditLog>>> {
+    match std::env::var("REBE_AUDIT_LOG") {
+        Ok(path) => {
+            let sink = rebe_shell::audit::JsonLinesSink::append(&path)?;
+            let patterns: Vec<String> = std::env::var("REBE_AUDIT_REDACT")
+                .map(|v| {
+                    v.split(',')
+                        .map(|p| p.trim().to_string())
+                        .filter(|p| !p.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default();
+            info!(
+                "auditing commands to {path} ({} redaction patterns)",
+                patterns.len()
+            );
+            Ok(Some(Arc::new(
+                rebe_shell::audit::AuditLog::new(sink).with_redaction(patterns),
+            )))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Cap on captured output per SSH command, from
+/// `REBE_MAX_OUTPUT_BYTES` (the library's 10 MiB default otherwise).
+/// Output past the cap is dropped and the response flagged truncated.
+fn max_output_bytes() -> usize {
+    std::env::var("REBE_MAX_OUTPUT_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(rebe_shell::ssh::DEFAULT_MAX_OUTPUT_BYTES)
+}
+
+/// Hard cap on a WebSocket session's total lifetime, from
+/// `REBE_MAX_SESSION_DURATION_SECS`. Unlike the idle reaper this cuts
+/// off even actively-used sessions — for kiosk and demo deployments
+/// where a shared terminal must not be held indefinitely. Unset (the
+/// normal case) means no cap.
+fn max_session_duration() -> Option<Duration> {
+    parse_session_duration(std::env::var("REBE_MAX_SESSION_DURATION_SECS").ok())
+}
+
+fn parse_session_duration(var: Option<String>) -> Option<Duration> {
+    let raw = var?;
+    match raw.parse::<u64>() {
+        Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+        _ => {
+            warn!("ignoring invalid REBE_MAX_SESSION_DURATION_SECS {raw:?}");
+            None
+        }
+    }
+}
+
+/// The longest per-command timeout a client may request, from
+/// `REBE_SSH_MAX_TIMEOUT_MS` (10 minutes by default).
+fn max_ssh_timeout() -> std::time::Duration {
+    std::env::var("REBE_SSH_MAX_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .map(std::time::Duration::from_millis)
+        .unwrap_or(std::time::Duration::from_secs(600))
+}
+
+#[derive(Debug, Serialize)]
+struct SshExecuteResponse {
+    stdout: String,
+    stderr: String,
+    exit_status: u32,
+    /// Output hit the `REBE_MAX_OUTPUT_BYTES` cap and was cut off.
+    truncated: bool,
+}
+
+async fn ssh_execute(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<SshExecuteRequest>,
+) -> Response {
+    let key = HostKey::new(req.host, req.port, req.username);
+    let auth = AuthMethod::Password(req.password);
+    state.commands_executed.fetch_add(1, Ordering::Relaxed);
+
+    let timeout = req
+        .timeout_ms
+        .map(std::time::Duration::from_millis)
+        .unwrap_or(rebe_shell::ssh::DEFAULT_COMMAND_TIMEOUT)
+        .min(max_ssh_timeout());
+
+    let pool = &state.ssh_pool;
+    let (key_ref, auth_ref, command) = (&key, &auth, req.command.as_str());
+    let (outcome, attempts) =
+        retry_with_breaker(req.retry_policy, &state.breaker, key_ref, move || {
+            pool.exec_with_timeout(key_ref, auth_ref, command, timeout)
+        })
+        .await;
+
+    match outcome {
+        Ok(output) => Json(SshExecuteResponse {
+            stdout: output.stdout_lossy().into_owned(),
+            stderr: output.stderr_lossy().into_owned(),
+            exit_status: output.exit_status,
+            truncated: output.truncated,
+        })
+        .into_response(),
+        Err(e) => {
+            state.commands_failed.fetch_add(1, Ordering::Relaxed);
+            error!("ssh execute on {key} failed after {attempts} attempts: {e:#}");
+            // Zero attempts means the breaker blocked the call outright.
+            let status = if attempts == 0 {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                match SshError::classify(&e) {
+                    Some(SshError::ConnectTimeout(_)) | Some(SshError::CommandTimeout(_)) => {
+                        StatusCode::GATEWAY_TIMEOUT
+                    }
+                    Some(SshError::PoolExhausted) => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::BAD_GATEWAY,
+                }
+            };
+            (status, format!("{e:#}")).into_response()
+        }
+    }
+}
+
+// ---------------------------------------------------------------------
+// Session management
+// ---------------------------------------------------------------------
+
+#[derive(Debug, Serialize)]
+struct SessionResponse {
+    id: String,
+    rows: u16,
+    cols: u16,
+    pid: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateSessionRequest {
+    #[serde(default = "default_rows")]
+    rows: u16,
+    #[serde(default = "default_cols")]
+    cols: u16,
+    /// `TERM` for the shell; `xterm-256color` when omitted.
+    term: Option<String>,
+    /// `LANG` for the shell; inherited when omitted.
+    lang: Option<String>,
+    /// Working directory; must exist.
+    cwd: Option<String>,
+    /// Spawn a login shell so profile files run; the backend's
+    /// `REBE_LOGIN_SHELL` default applies when omitted.
+    login_shell: Option<bool>,
+    /// Output encoding label (`shift_jis`, `latin1`, ...), transcoded
+    /// to UTF-8 for the client; `binary` passes bytes through
+    /// untouched. UTF-8 when omitted.
+    encoding: Option<String>,
+    /// Named shell profile (`bash`, `zsh`, ...) to spawn instead of
+    /// the detected default; must be configured on the backend.
+    shell_profile: Option<String>,
+    /// Client-chosen session id (a UUID), making creation idempotent:
+    /// retrying with the same id returns the existing live session
+    /// instead of spawning another shell.
+    session_id: Option<String>,
+}
+
+fn default_rows() -> u16 {
+    24
+}
+
+fn default_cols() -> u16 {
+    80
+}
+
+/// Classify a session-creation failure into a stable code the
+/// frontend can branch on.
+fn session_error_code(err: &anyhow::Error) -> &'static str {
+    let text = format!("{err:#}");
+    if text.contains("working directory") {
+        "BAD_CWD"
+    } else if text.contains("not a valid UUID") {
+        "BAD_SESSION_ID"
+    } else if text.contains("unknown output encoding") {
+        "BAD_ENCODING"
+    } else if text.contains("session limit") {
+        "SESSION_LIMIT"
+    } else if text.contains("spawning shell") || text.contains("shell") {
+        "SPAWN_FAILED"
+    } else if text.contains("pty") {
+        "PTY_FAILED"
+    } else {
+        "SESSION_CREATE_FAILED"
+    }
+}
+
+/// Friendly text for a PTY failure: the typed error's user message
+/// when the PTY layer produced one, the raw context chain otherwise.
+fn pty_user_message(err: &anyhow::Error) -> String {
+    match rebe_shell::pty::PtyError::classify(err) {
+        Some(pty_err) => pty_err.user_message(),
+        None => format!("{err:#}"),
+    }
+}
+
+/// Structured error body for session-creation failures.
+fn session_error_body(status: StatusCode, code: &str, user_message: String) -> Response {
+    (
+        status,
+        Json(serde_json::json!({ "code": code, "user_message": user_message })),
+    )
+        .into_response()
+}
+
+/// Create a PTY session over REST, so the frontend can configure the
+/// terminal before attaching to it over the WebSocket.
+async fn create_session(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateSessionRequest>,
+) -> Response {
+    let mut options = SessionOptions {
+        term: req.term,
+        lang: req.lang,
+        cwd: req.cwd.map(std::path::PathBuf::from),
+        login_shell: req.login_shell,
+        encoding: req.encoding,
+        ..SessionOptions::default()
+    };
+    if let Some(name) = &req.shell_profile {
+        match state.shell_profiles.resolve(name) {
+            Ok(profile) => {
+                options.shell = Some(profile.shell.clone());
+                options.shell_args = profile.args.clone();
+                options.env = profile.env.clone();
+            }
+            Err(e) => {
+                return session_error_body(
+                    StatusCode::BAD_REQUEST,
+                    "UNKNOWN_PROFILE",
+                    format!("{e:#}"),
+                );
+            }
+        }
+    }
+    if let Some(cwd) = &options.cwd {
+        if !cwd.is_dir() {
+            return session_error_body(
+                StatusCode::BAD_REQUEST,
+                "BAD_CWD",
+                format!("working directory {} does not exist", cwd.display()),
+            );
+        }
+    }
+    let created = match &req.session_id {
+        Some(id) => {
+            state
+                .pty_manager
+                .create_session_as(id, req.rows, req.cols, options)
+                .await
+        }
+        None => {
+            state
+                .pty_manager
+                .create_session_with(req.rows, req.cols, options)
+                .await
+        }
+    };
+    match created {
+        Ok(id) => {
+            let pid = state
+                .pty_manager
+                .list_sessions()
+                .await
+                .into_iter()
+                .find(|s| s.id == id)
+                .and_then(|s| s.pid);
+            Json(SessionResponse {
+                id,
+                rows: req.rows,
+                cols: req.cols,
+                pid,
+            })
+            .into_response()
+        }
+        Err(e) => {
+            error!("creating session over rest failed: {e:#}");
+            let code = session_error_code(&e);
+            let status = match code {
+                "SESSION_LIMIT" => StatusCode::TOO_MANY_REQUESTS,
+                "BAD_SESSION_ID" | "BAD_ENCODING" => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            session_error_body(status, code, format!("could not start a terminal: {e:#}"))
+        }
+    }
+}
+
+/// List live PTY sessions, so operators can spot stuck ones.
+async fn list_sessions(State(state): State<Arc<AppState>>) -> Json<Vec<SessionResponse>> {
+    Json(
+        state
+            .pty_manager
+            .list_sessions()
+            .await
+            .into_iter()
+            .map(|s| SessionResponse {
+                id: s.id,
+                rows: s.rows,
+                cols: s.cols,
+                pid: s.pid,
+            })
+            .collect(),
+    )
+}
+
+/// A session's recorded command lines, oldest first — the server-side
+/// history the frontend offers on up-arrow, independent of whatever
+/// the shell itself keeps.
+async fn session_history(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    match state.pty_manager.history(&id).await {
+        Ok(history) => Json(serde_json::json!({ "history": history })).into_response(),
+        Err(e) => (StatusCode::NOT_FOUND, format!("{e:#}")).into_response(),
+    }
+}
+
+/// Kill a session's shell and drop it, without restarting the backend.
+async fn close_session(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    match state.pty_manager.close(&id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (StatusCode::NOT_FOUND, format!("{e:#}")).into_response(),
+    }
+}
+
+/// Start recording a session to `<REBE_RECORDING_DIR>/<id>.cast`
+/// (temp dir by default) and report the path.
+async fn start_session_recording(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Response {
+    let dir = std::env::var("REBE_RECORDING_DIR")
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|_| std::env::temp_dir());
+    let path = dir.join(format!("{id}.cast"));
+    let file = match std::fs::File::create(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("creating {}: {e}", path.display()),
+            )
+                .into_response()
+        }
+    };
+    match state.pty_manager.start_recording(&id, Box::new(file)).await {
+        Ok(()) => Json(serde_json::json!({ "recording": path.display().to_string() }))
+            .into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+    }
+}
+
+async fn stop_session_recording(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Response {
+    match state.pty_manager.stop_recording(&id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+    }
+}
+
+// ---------------------------------------------------------------------
+// Thing discovery
+// ---------------------------------------------------------------------
+
+#[derive(Debug, Deserialize)]
+struct DiscoverParams {
+    capability: Option<String>,
+    for_thing: Option<String>,
+}
+
+/// Filter the registry and report which matches are reachable.
+async fn discover_things(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DiscoverParams>,
+) -> Json<Vec<Thing>> {
+    Json(
+        state
+            .registry
+            .discover(params.capability.as_deref(), params.for_thing.as_deref())
+            .await,
+    )
+}
+
+// ---------------------------------------------------------------------
+// PTY WebSocket
+// ---------------------------------------------------------------------
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Raw keyboard input for the terminal.
+    Input { data: String },
+    Resize { rows: u16, cols: u16 },
+    /// Select connection options from the features advertised in
+    /// [`ServerMessage::Connected`]. Omitted fields keep their
+    /// connect-time (query parameter) values; `replay` happens before
+    /// any `Init` can arrive, so it stays a query parameter.
+    Init {
+        binary: Option<bool>,
+        /// Compression codec (`zstd`), or `"off"` to disable.
+        compression: Option<String>,
+        line_buffered: Option<bool>,
+    },
+    /// A structured protocol request, answered with
+    /// [`ServerMessage::Result`] — the same pipeline as `/api/execute`
+    /// without a separate HTTP call.
+    Execute { request: CommandRequest },
+    /// A named control key, translated server-side to the bytes the
+    /// session expects, so touch clients can put `Ctrl-C` on a button
+    /// without the frontend knowing terminal byte trivia.
+    Control { action: ControlAction },
+}
+
+/// Control keys [`ClientMessage::Control`] can name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ControlAction {
+    /// End of input, `Ctrl-D`.
+    Eof,
+    /// Interrupt the foreground job, `Ctrl-C`.
+    Interrupt,
+    /// Suspend the foreground job, `Ctrl-Z`.
+    Suspend,
+    /// Redraw, `Ctrl-L`.
+    ClearScreen,
+}
+
+impl ControlAction {
+    /// The byte the session's line discipline expects. Interrupt and
+    /// suspend stay bytes rather than signals on purpose: the tty
+    /// turns them into `SIGINT`/`SIGTSTP` for the foreground process
+    /// group, which is exactly the semantics a terminal key has.
+    fn bytes(self) -> &'static [u8] {
+        match self {
+            ControlAction::Eof => &[0x04],
+            ControlAction::Interrupt => &[0x03],
+            ControlAction::Suspend => &[0x1a],
+            ControlAction::ClearScreen => &[0x0c],
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    /// First message on every connection: the session joined and the
+    /// features this backend supports, so clients negotiate via
+    /// [`ClientMessage::Init`] instead of out-of-band coordination.
+    Connected {
+        session_id: String,
+        features: Vec<String>,
+    },
+    /// Base64-encoded terminal output.
+    Output { data: String },
+    /// Base64-encoded compressed terminal output; only sent when the
+    /// client negotiated `codec` at connect time.
+    OutputCompressed { data: String, codec: String },
+    /// Terminal output as a tagged binary frame ([`BINARY_FRAME_RAW`] or
+    /// [`BINARY_FRAME_ZSTD`] followed by the payload); only sent when
+    /// the client negotiated binary framing at connect time. Never
+    /// serialized to JSON — the writer sends it as a `Binary` frame.
+    #[serde(skip)]
+    OutputBinary { frame: Vec<u8> },
+    /// The shell exited; no further output will follow.
+    Exit { code: u32 },
+    /// Reply to [`ClientMessage::Execute`].
+    Result { response: CommandResponse },
+    Status { message: String },
+    Error { message: String },
+}
+
+/// Output chunks smaller than this are never compressed: interactive
+/// echo doesn't benefit, and the framing overhead would dominate.
+const COMPRESSION_MIN_BYTES: usize = 4096;
+
+/// First byte of a binary output frame: the payload is raw terminal
+/// output.
+const BINARY_FRAME_RAW: u8 = 0x00;
+/// First byte of a binary output frame: the payload is zstd-compressed
+/// terminal output.
+const BINARY_FRAME_ZSTD: u8 = 0x01;
+
+/// Build the output message for a chunk, compressing large chunks when
+/// the client negotiated zstd. Falls back to plain output if the
+/// encoder fails. With `binary` negotiated the chunk goes out as a
+/// tagged binary frame instead, skipping the ~33% base64 inflation and
+/// the encode/decode CPU on the hot output path; control messages stay
+/// JSON either way.
+fn output_message(chunk: &[u8], compress: bool, binary: bool) -> ServerMessage {
+    let compressed = if compress && chunk.len() >= COMPRESSION_MIN_BYTES {
+        zstd::encode_all(chunk, 0).ok()
+    } else {
+        None
+    };
+    if binary {
+        let (tag, payload) = match &compressed {
+            Some(payload) => (BINARY_FRAME_ZSTD, payload.as_slice()),
+            None => (BINARY_FRAME_RAW, chunk),
+        };
+        let mut frame = Vec::with_capacity(payload.len() + 1);
+        frame.push(tag);
+        frame.extend_from_slice(payload);
+        return ServerMessage::OutputBinary { frame };
+    }
+    match compressed {
+        Some(payload) => ServerMessage::OutputCompressed {
+            data: BASE64_STANDARD.encode(payload),
+            codec: "zstd".to_string(),
+        },
+        None => ServerMessage::Output {
+            data: BASE64_STANDARD.encode(chunk),
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WsParams {
+    /// Existing session id to reattach to; a fresh session otherwise.
+    session: Option<String>,
+    /// Replay buffered scrollback before streaming live output.
+    #[serde(default)]
+    replay: bool,
+    /// Output compression the client can decode; only `zstd` is
+    /// understood, anything else is ignored.
+    compression: Option<String>,
+    /// Receive terminal output as tagged binary frames instead of
+    /// base64 JSON.
+    #[serde(default)]
+    binary: bool,
+    /// Only send complete output lines, flushing a lingering partial
+    /// line after a short pause. For clients that parse the stream
+    /// line by line; TUIs should stay unbuffered.
+    #[serde(default)]
+    line_buffered: bool,
+}
+
+/// Features advertised in [`ServerMessage::Connected`], selectable via
+/// [`ClientMessage::Init`] (or the equivalent query parameters).
+const WS_FEATURES: &[&str] = &[
+    "binary",
+    "compression",
+    "replay",
+    "structured_exec",
+    "line_buffered",
+];
+
+/// Options negotiated for one connection: seeded from the query
+/// parameters, updated by [`ClientMessage::Init`], and read by the
+/// output pump per message so a mid-session `Init` takes effect
+/// immediately.
+#[derive(Debug, Default)]
+struct ConnOptions {
+    binary: AtomicBool,
+    compress: AtomicBool,
+    line_buffered: AtomicBool,
+}
+
+async fn ws_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<WsParams>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| handle_websocket(socket, state, params))
+}
+
+async fn handle_websocket(mut socket: WebSocket, state: Arc<AppState>, params: WsParams) {
+    let compress = match params.compression.as_deref() {
+        Some("zstd") => true,
+        Some(other) => {
+            warn!("ignoring unsupported compression codec {other:?}");
+            false
+        }
+        None => false,
+    };
+    let options = Arc::new(ConnOptions {
+        binary: AtomicBool::new(params.binary),
+        compress: AtomicBool::new(compress),
+        line_buffered: AtomicBool::new(params.line_buffered),
+    });
+
+    // Reattach when the client names a live session; otherwise start a
+    // fresh one.
+    let mut attached = None;
+    if let Some(id) = params.session.as_deref() {
+        match state.pty_manager.attach_output(id).await {
+            Ok(output) => attached = Some((id.to_string(), output)),
+            Err(e) => warn!("reattach to session {id} failed, starting fresh: {e:#}"),
+        }
+    }
+    let reattached = attached.is_some();
+    let (session_id, (scrollback, mut pty_output)) = match attached {
+        Some(attach) => attach,
+        None => {
+            let id = match state.pty_manager.create_session(24, 80).await {
+                Ok(id) => id,
+                Err(e) => {
+                    error!("failed to create pty session: {e:#}");
+                    // Tell the client why before dropping the socket;
+                    // an opaque close is undebuggable from a frontend.
+                    let msg = ServerMessage::Error {
+                        message: format!(
+                            "terminal unavailable ({}): {e:#}",
+                            session_error_code(&e)
+                        ),
+                    };
+                    if let Ok(text) = serde_json::to_string(&msg) {
+                        let _ = socket.send(Message::Text(text.into())).await;
+                    }
+                    return;
+                }
+            };
+            info!("pty session {id} created");
+            match state.pty_manager.attach_output(&id).await {
+                Ok(output) => (id, output),
+                Err(e) => {
+                    error!("attaching to fresh session {id}: {e:#}");
+                    return;
+                }
+            }
+        }
+    };
+
+    let (mut ws_sink, mut ws_stream) = socket.split();
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<ServerMessage>();
+
+    // Single writer task: everything destined for the client funnels
+    // through `out_tx`. It also owns the keepalive timer, emitting a
+    // raw `Ping` frame plus a `Status` "ping" for clients whose
+    // WebSocket library hides control frames.
+    let writer = tokio::spawn(async move {
+        let mut keepalive = tokio::time::interval(WS_PING_INTERVAL);
+        keepalive.tick().await; // immediate first tick
+        loop {
+            let msg = tokio::select! {
+                msg = out_rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = keepalive.tick() => {
+                    if ws_sink.send(Message::Ping(Vec::new().into())).await.is_err() {
+                        break;
+                    }
+                    ServerMessage::Status {
+                        message: "ping".to_string(),
+                    }
+                }
+            };
+            if let ServerMessage::OutputBinary { frame } = msg {
+                if ws_sink.send(Message::Binary(frame.into())).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            let text = match serde_json::to_string(&msg) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            if ws_sink.send(Message::Text(text.into())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // First frame: who the client is attached to and what it may ask
+    // for via `Init`.
+    let _ = out_tx.send(ServerMessage::Connected {
+        session_id: session_id.clone(),
+        features: WS_FEATURES.iter().map(|f| f.to_string()).collect(),
+    });
+
+    if reattached && params.replay && !scrollback.is_empty() {
+        let _ = out_tx.send(output_message(
+            &scrollback,
+            options.compress.load(Ordering::Relaxed),
+            options.binary.load(Ordering::Relaxed),
+        ));
+    }
+
+    // Relay PTY output to the client. In line-buffered mode only
+    // complete lines go out, so a line split across read chunks never
+    // arrives as two messages; a held-back partial line (a prompt,
+    // typically) still flushes after a short quiet period.
+    let pty_out_tx = out_tx.clone();
+    let pump_state = state.clone();
+    let pump_session = session_id.clone();
+    let pump_options = options.clone();
+    let pump = tokio::spawn(async move {
+        let mut partial: Vec<u8> = Vec::new();
+        loop {
+            // Re-read per iteration so a mid-session `Init` applies to
+            // the next chunk out.
+            let binary = pump_options.binary.load(Ordering::Relaxed);
+            let compress = pump_options.compress.load(Ordering::Relaxed);
+            let line_buffered = pump_options.line_buffered.load(Ordering::Relaxed);
+            // A held-back partial line flushes quickly; otherwise the
+            // timeout is just a heartbeat to notice a dead shell.
+            let wait = if line_buffered && !partial.is_empty() {
+                LINE_FLUSH_TIMEOUT
+            } else {
+                EXIT_POLL_INTERVAL
+            };
+            let received = match tokio::time::timeout(wait, pty_output.recv()).await {
+                Ok(received) => received,
+                Err(_) => {
+                    if !partial.is_empty() {
+                        let flush = std::mem::take(&mut partial);
+                        if pty_out_tx
+                            .send(output_message(&flush, compress, binary))
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                    // Quiet with nothing buffered: if the shell has
+                    // exited its output is fully drained, so tell the
+                    // client and stop pumping.
+                    if let Ok(Some(code)) = pump_state.pty_manager.exit_status(&pump_session).await
+                    {
+                        let _ = pty_out_tx.send(ServerMessage::Exit { code });
+                        break;
+                    }
+                    continue;
+                }
+            };
+            match received {
+                Ok(chunk) => {
+                    let message = if line_buffered {
+                        partial.extend_from_slice(&chunk);
+                        match take_complete_lines(&mut partial) {
+                            Some(lines) => output_message(&lines, compress, binary),
+                            None => continue,
+                        }
+                    } else {
+                        output_message(&chunk, compress, binary)
+                    };
+                    if pty_out_tx.send(message).is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("pty output lagged; {skipped} chunks skipped");
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    // Don't swallow a final partial line at EOF.
+                    if !partial.is_empty() {
+                        let _ = pty_out_tx.send(output_message(&partial, compress, binary));
+                    }
+                    if let Ok(Some(code)) = pump_state.pty_manager.exit_status(&pump_session).await
+                    {
+                        let _ = pty_out_tx.send(ServerMessage::Exit { code });
+                    }
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut shutdown_rx = state.shutdown.subscribe();
+    let mut shutting_down = false;
+    // When configured, the session ends at this instant no matter how
+    // active it is.
+    let lifetime_limit = max_session_duration();
+    let expires_at = lifetime_limit.map(|limit| tokio::time::Instant::now() + limit);
+    let mut expired = false;
+    // Cancelled at teardown so in-flight SSH commands started from this
+    // session release their pooled connections immediately.
+    let cancel = CancellationToken::new();
+    let mut command_buffer = String::new();
+    let mut last_seen = std::time::Instant::now();
+    let mut liveness = tokio::time::interval(WS_PING_INTERVAL);
+    liveness.tick().await; // immediate first tick
+    loop {
+        let msg = tokio::select! {
+            msg = ws_stream.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = liveness.tick() => {
+                if last_seen.elapsed() > WS_PONG_TIMEOUT {
+                    warn!("no pong from client of session {session_id}; closing socket");
+                    break;
+                }
+                continue;
+            }
+            _ = shutdown_rx.recv() => {
+                shutting_down = true;
+                let _ = out_tx.send(ServerMessage::Status {
+                    message: "server shutting down; session closed".to_string(),
+                });
+                break;
+            }
+            _ = async {
+                match expires_at {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                expired = true;
+                let _ = out_tx.send(ServerMessage::Status {
+                    message: format!(
+                        "session time limit of {}s reached; closing",
+                        lifetime_limit.unwrap_or_default().as_secs()
+                    ),
+                });
+                break;
+            }
+        };
+        // Pongs and ordinary traffic both prove the client is alive.
+        last_seen = std::time::Instant::now();
+        match msg {
+            Message::Text(text) => {
+                let parsed: ClientMessage = match serde_json::from_str(&text) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        let _ = out_tx.send(ServerMessage::Error {
+                            message: format!("bad message: {e}"),
+                        });
+                        continue;
+                    }
+                };
+                match parsed {
+                    ClientMessage::Input { data } => {
+                        // Buffer until end of line so each line can be
+                        // classified before any byte reaches the local
+                        // shell. Pre-writing meant an `ssh ...` line
+                        // ran locally as well as remotely, and pasted
+                        // multi-line input interleaved.
+                        command_buffer.push_str(&data);
+                        while let Some(line) = take_line(&mut command_buffer) {
+                            if let Err(e) =
+                                state.pty_manager.push_history(&session_id, &line).await
+                            {
+                                warn!("recording history for {session_id} failed: {e:#}");
+                            }
+                            match state.command_router.route(line.trim()) {
+                                RoutedCommand::Invalid(message) => {
+                                    let _ = out_tx.send(ServerMessage::Error { message });
+                                }
+                                RoutedCommand::Ssh(cmd) => {
+                                    if let Err(violation) = state.policy.check(&cmd.command) {
+                                        let _ = out_tx.send(ServerMessage::Error {
+                                            message: format!("command not run: {violation}"),
+                                        });
+                                        continue;
+                                    }
+                                    // Spawned so the recv loop keeps
+                                    // seeing client messages (and the
+                                    // eventual close) while the
+                                    // command runs.
+                                    let state = state.clone();
+                                    let out_tx = out_tx.clone();
+                                    let cancel = cancel.clone();
+                                    tokio::spawn(async move {
+                                        handle_ssh_command(&state, &out_tx, cmd, &cancel).await;
+                                    });
+                                }
+                                RoutedCommand::Local => {
+                                    if let Err(violation) = state.policy.check(line.trim()) {
+                                        let _ = out_tx.send(ServerMessage::Error {
+                                            message: format!("command not run: {violation}"),
+                                        });
+                                        continue;
+                                    }
+                                    if let Err(e) =
+                                        state.pty_manager.write_line(&session_id, &line).await
+                                    {
+                                        warn!("pty write to {session_id} failed: {e:#}");
+                                        let _ = out_tx.send(ServerMessage::Error {
+                                            message: pty_user_message(&e),
+                                        });
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    ClientMessage::Control { action } => {
+                        if action == ControlAction::Interrupt {
+                            // The shell abandons the interrupted line,
+                            // so the classification buffer must too.
+                            command_buffer.clear();
+                        }
+                        if let Err(e) =
+                            state.pty_manager.write(&session_id, action.bytes()).await
+                        {
+                            warn!("control {action:?} to {session_id} failed: {e:#}");
+                            let _ = out_tx.send(ServerMessage::Error {
+                                message: pty_user_message(&e),
+                            });
+                        }
+                    }
+                    ClientMessage::Resize { rows, cols } => {
+                        if let Err(e) = state.pty_manager.resize(&session_id, rows, cols).await {
+                            warn!("resize of {session_id} failed: {e:#}");
+                            let _ = out_tx.send(ServerMessage::Error {
+                                message: pty_user_message(&e),
+                            });
+                        }
+                    }
+                    ClientMessage::Init {
+                        binary,
+                        compression,
+                        line_buffered,
+                    } => {
+                        if let Some(binary) = binary {
+                            options.binary.store(binary, Ordering::Relaxed);
+                        }
+                        match compression.as_deref() {
+                            Some("zstd") => options.compress.store(true, Ordering::Relaxed),
+                            Some("off") => options.compress.store(false, Ordering::Relaxed),
+                            Some(other) => {
+                                let _ = out_tx.send(ServerMessage::Error {
+                                    message: format!("unsupported compression codec {other:?}"),
+                                });
+                            }
+                            None => {}
+                        }
+                        if let Some(line_buffered) = line_buffered {
+                            options.line_buffered.store(line_buffered, Ordering::Relaxed);
+                        }
+                        let _ = out_tx.send(ServerMessage::Status {
+                            message: format!(
+                                "negotiated binary={} compression={} line_buffered={}",
+                                options.binary.load(Ordering::Relaxed),
+                                options.compress.load(Ordering::Relaxed),
+                                options.line_buffered.load(Ordering::Relaxed),
+                            ),
+                        });
+                    }
+                    ClientMessage::Execute { request } => {
+                        state.commands_executed.fetch_add(1, Ordering::Relaxed);
+                        if let Err(error) = request.validate_version() {
+                            state.commands_failed.fetch_add(1, Ordering::Relaxed);
+                            let _ = out_tx.send(ServerMessage::Result {
+                                response: CommandResponse {
+                                    version: rebe_shell::protocol::PROTOCOL_VERSION.to_string(),
+                                    id: request.id,
+                                    result: rebe_shell::protocol::CommandResult::Error { error },
+                                    metadata: rebe_shell::protocol::ResponseMetadata::default(),
+                                },
+                            });
+                            continue;
+                        }
+                        // Spawned like SSH commands, so a slow execute
+                        // doesn't stall interactive input.
+                        let state = state.clone();
+                        let out_tx = out_tx.clone();
+                        tokio::spawn(async move {
+                            let response = state.executor.execute(request).await;
+                            if matches!(
+                                response.result,
+                                rebe_shell::protocol::CommandResult::Error { .. }
+                            ) {
+                                state.commands_failed.fetch_add(1, Ordering::Relaxed);
+                            }
+                            let _ = out_tx.send(ServerMessage::Result { response });
+                        });
+                    }
+                }
+            }
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    cancel.cancel();
+    if shutting_down || expired {
+        let _ = state.pty_manager.close(&session_id).await;
+        info!("pty session {session_id} closed");
+    } else {
+        // The session outlives the socket so clients can reattach
+        // (`?session=<id>&replay=true`); once the last client detaches
+        // the idle reaper eventually closes it.
+        state.pty_manager.detach(&session_id).await;
+        info!("client detached from pty session {session_id}");
+    }
+    pump.abort();
+    drop(out_tx);
+    let _ = writer.await;
+}
+
+// ---------------------------------------------------------------------
+// Command routing
+// ---------------------------------------------------------------------
+
+/// Pop the first complete line — terminator included — off `buffer`,
+/// leaving any partial trailing input for the next chunk. Handles
+/// `\r`, `\n` and `\r\n` so pasted multi-line input splits cleanly.
+/// Split every complete line off the front of `buf`, leaving a
+/// trailing partial line (if any) in place for the next chunk.
+fn take_complete_lines(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let pos = buf.iter().rposition(|&b| b == b'\n')?;
+    Some(buf.drain(..=pos).collect())
+}
+
+fn take_line(buffer: &mut String) -> Option<String> {
+    let pos = buffer.find(['\n', '\r'])?;
+    let mut end = pos + 1;
+    if buffer.as_bytes()[pos] == b'\r' && buffer.as_bytes().get(end) == Some(&b'\n') {
+        end += 1;
+    }
+    let rest = buffer.split_off(end);
+    Some(std::mem::replace(buffer, rest))
+}
+
+/// Run a routed SSH command through the pool, relaying output to the
+/// client chunk by chunk as it arrives.
+///
+/// The `[SSH: host]` marker goes out with the first chunk only, so
+/// `tail -f`-style commands read naturally in the terminal.
+async fn handle_ssh_command(
+    state: &Arc<AppState>,
+    out_tx: &mpsc::UnboundedSender<ServerMessage>,
+    cmd: SshCommand,
+    cancel: &CancellationToken,
+) {
+    let key = HostKey::new(cmd.host.clone(), cmd.port, cmd.username.clone());
+    if !state.breaker.allow(&key) {
+        let _ = out_tx.send(ServerMessage::Error {
+            message: format!("circuit breaker open for {key}; not attempting"),
+        });
+        return;
+    }
+    let password = std::env::var("REBE_SSH_PASSWORD").unwrap_or_default();
+    let auth = AuthMethod::Password(password);
+
+    state.commands_executed.fetch_add(1, Ordering::Relaxed);
+    let mut events = match state.ssh_pool.exec_stream(&key, &auth, &cmd.command).await {
+        Ok(events) => events,
+        Err(e) => {
+            state.breaker.record_failure(&key);
+            state.commands_failed.fetch_add(1, Ordering::Relaxed);
+            let _ = out_tx.send(ServerMessage::Error {
+                message: format!("ssh {key} failed: {e:#}"),
+            });
+            return;
+        }
+    };
+
+    let timeout = cmd
+        .timeout
+        .unwrap_or(rebe_shell::ssh::DEFAULT_COMMAND_TIMEOUT)
+        .min(max_ssh_timeout());
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut first_chunk = true;
+    let mut stderr = Vec::new();
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => event,
+                None => break,
+            },
+            // Dropping `events` on cancellation closes the remote
+            // channel, returning the connection to the pool. Same for
+            // the deadline, which kills overrunning commands.
+            _ = cancel.cancelled() => return,
+            _ = tokio::time::sleep_until(deadline) => {
+                state.commands_failed.fetch_add(1, Ordering::Relaxed);
+                let _ = out_tx.send(ServerMessage::Error {
+                    message: format!("ssh {key} timed out after {timeout:?}"),
+                });
+                return;
+            }
+        };
+        match event {
+            StreamEvent::Stdout(chunk) => {
+                let payload = if first_chunk {
+                    first_chunk = false;
+                    let mut prefixed = format!("[SSH: {}] ", cmd.host).into_bytes();
+                    prefixed.extend_from_slice(&chunk);
+                    prefixed
+                } else {
+                    chunk
+                };
+                let _ = out_tx.send(ServerMessage::Output {
+                    data: BASE64_STANDARD.encode(&payload),
+                });
+            }
+            StreamEvent::Stderr(chunk) => stderr.extend_from_slice(&chunk),
+            StreamEvent::Exit(_) => break,
+        }
+    }
+    state.breaker.record_success(&key);
+    if !stderr.is_empty() {
+        let _ = out_tx.send(ServerMessage::Error {
+            message: String::from_utf8_lossy(&stderr).into_owned(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::util::ServiceExt;
+
+    fn test_state(token: Option<&str>) -> Arc<AppState> {
+        let ssh_pool = Arc::new(SSHPool::new());
+        Arc::new(AppState {
+            pty_manager: PtyManager::new(),
+            ssh_pool: ssh_pool.clone(),
+            executor: Executor::new(
+                std::env::temp_dir(),
+                ssh_pool,
+                AuthMethod::Password(String::new()),
+            )
+            .unwrap(),
+            registry: Registry::default_local(),
+            policy: CommandPolicy::allow_all(),
+            command_router: Box::new(DefaultRouter),
+            breaker: CircuitBreaker::default(),
+            shell_profiles: shell_profiles::ShellProfiles::builtin(),
+            events: Arc::new(EventBus::default()),
+            auth_token: token.map(String::from),
+            shutdown: broadcast::channel(1).0,
+            commands_executed: AtomicU64::new(0),
+            commands_failed: AtomicU64::new(0),
+        })
+    }
+
+    fn test_router(token: Option<&str>) -> Router {
+        router(test_state(token))
+    }
+
+    #[tokio::test]
+    async fn events_endpoint_streams_published_events_as_sse() {
+        let state = test_state(None);
+        let app = router(state.clone());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/events")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        // The handler subscribed when it built the stream, so an event
+        // published now must be the first data frame.
+        state.events.publish(rebe_shell::events::Event::BreakerOpened {
+            host: "ops@db1:22".into(),
+        });
+        let mut body = response.into_body().into_data_stream();
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(5), body.next())
+            .await
+            .expect("no SSE frame within 5s")
+            .unwrap()
+            .unwrap();
+        let text = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(text.contains("breaker_opened"), "unexpected frame: {text}");
+        assert!(text.contains("ops@db1:22"), "unexpected frame: {text}");
+    }
+
+    #[tokio::test]
+    async fn protected_routes_require_bearer_token() {
+        let app = test_router(Some("sekrit"));
+
+        let unauthorized = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/discover")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+
+        let authorized = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/discover")
+                    .header(header::AUTHORIZATION, "Bearer sekrit")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(authorized.status(), StatusCode::OK);
+
+        // The WebSocket upgrade path accepts the query-param form (the
+        // handshake itself fails, but it must get past auth).
+        let query_token = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/ws?token=sekrit")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_ne!(query_token.status(), StatusCode::UNAUTHORIZED);
+
+        let wrong = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/discover")
+                    .header(header::AUTHORIZATION, "Bearer nope")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn health_stays_open_without_token() {
+        let app = test_router(Some("sekrit"));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn readiness_probes_the_pty_subsystem() {
+        let app = test_router(None);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/health/ready")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(parsed["status"], "ready");
+        assert_eq!(parsed["checks"]["pty"], "ok");
+        // No canary configured in tests; the check must say so rather
+        // than failing or silently passing.
+        assert_eq!(parsed["checks"]["ssh_canary"], "skipped");
+    }
+
+    #[tokio::test]
+    async fn metrics_exposes_core_gauges() {
+        let app = test_router(None);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains("rebe_pty_sessions 0"), "{text}");
+        assert!(text.contains("rebe_commands_executed_total 0"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn pool_endpoint_reports_empty_pool_with_zero_totals() {
+        let app = test_router(None);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/ssh/pool")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["total_connections"], 0);
+        assert_eq!(json["total_in_use"], 0);
+        assert_eq!(json["hosts"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn api_execute_runs_native_command() {
+        let app = test_router(None);
+        let body = serde_json::json!({
+            "version": "1.0",
+            "id": "test-1",
+            "command": { "type": "execute", "script": "echo via-api" },
+            "config": { "mode": "native", "timeout_ms": 5000 },
+        });
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/execute")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed: CommandResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(parsed.id, "test-1");
+        match parsed.result {
+            rebe_shell::protocol::CommandResult::Success { data } => {
+                assert_eq!(data["stdout"], "via-api\n");
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert_eq!(parsed.metadata.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn api_execute_rejects_incompatible_protocol_version() {
+        let app = test_router(None);
+        let body = serde_json::json!({
+            "version": "2.0",
+            "id": "test-2",
+            "command": { "type": "execute", "script": "echo never-runs" },
+            "config": { "mode": "native", "timeout_ms": 5000 },
+        });
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/execute")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed: CommandResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(parsed.id, "test-2");
+        match parsed.result {
+            rebe_shell::protocol::CommandResult::Error { error } => {
+                assert_eq!(error.code, "UNSUPPORTED_VERSION");
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn control_actions_parse_by_name_and_map_to_control_bytes() {
+        let parsed: ClientMessage =
+            serde_json::from_value(serde_json::json!({ "type": "control", "action": "eof" }))
+                .unwrap();
+        match parsed {
+            ClientMessage::Control { action } => assert_eq!(action, ControlAction::Eof),
+            other => panic!("unexpected message: {other:?}"),
+        }
+
+        assert_eq!(ControlAction::Eof.bytes(), [0x04]);
+        assert_eq!(ControlAction::Interrupt.bytes(), [0x03]);
+        assert_eq!(ControlAction::Suspend.bytes(), [0x1a]);
+        assert_eq!(ControlAction::ClearScreen.bytes(), [0x0c]);
+
+        // Unknown actions are a parse error, not silent bytes.
+        assert!(serde_json::from_value::<ClientMessage>(
+            serde_json::json!({ "type": "control", "action": "break" })
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn ws_execute_messages_round_trip_as_json() {
+        let parsed: ClientMessage = serde_json::from_value(serde_json::json!({
+            "type": "execute",
+            "request": {
+                "version": "1.0",
+                "id": "ws-1",
+                "command": { "type": "execute", "script": "uptime" },
+                "config": { "mode": "native", "timeout_ms": 5000 },
+            },
+        }))
+        .unwrap();
+        match parsed {
+            ClientMessage::Execute { request } => assert_eq!(request.id, "ws-1"),
+            other => panic!("unexpected message: {other:?}"),
+        }
+
+        let reply = ServerMessage::Result {
+            response: CommandResponse {
+                version: "1.0".to_string(),
+                id: "ws-1".to_string(),
+                result: rebe_shell::protocol::CommandResult::Success {
+                    data: serde_json::json!({"stdout": "ok"}),
+                },
+                metadata: rebe_shell::protocol::ResponseMetadata::default(),
+            },
+        };
+        let json = serde_json::to_value(&reply).unwrap();
+        assert_eq!(json["type"], "result");
+        assert_eq!(json["response"]["id"], "ws-1");
+    }
+
+    #[test]
+    fn exec_exit_code_tracks_the_result_variant() {
+        let mut response = CommandResponse {
+            version: "1.0".to_string(),
+            id: "cli-1".to_string(),
+            result: rebe_shell::protocol::CommandResult::Success {
+                data: serde_json::json!({"stdout": "ok"}),
+            },
+            metadata: rebe_shell::protocol::ResponseMetadata::default(),
+        };
+        assert_eq!(exec_exit_code(&response), 0);
+
+        response.result = rebe_shell::protocol::CommandResult::Error {
+            error: rebe_shell::protocol::ErrorInfo {
+                code: "EXECUTION_FAILED".to_string(),
+                message: "boom".to_string(),
+            },
+        };
+        assert_eq!(exec_exit_code(&response), 1);
+    }
+
+    #[test]
+    fn output_message_compresses_only_large_negotiated_chunks() {
+        let big = vec![b'a'; COMPRESSION_MIN_BYTES * 4];
+        match output_message(&big, true, false) {
+            ServerMessage::OutputCompressed { data, codec } => {
+                assert_eq!(codec, "zstd");
+                let compressed = BASE64_STANDARD.decode(data).unwrap();
+                assert!(compressed.len() < big.len());
+                assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), big);
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+
+        // Small chunks and non-negotiating clients get plain output.
+        assert!(matches!(
+            output_message(b"ls\r\n", true, false),
+            ServerMessage::Output { .. }
+        ));
+        assert!(matches!(
+            output_message(&big, false, false),
+            ServerMessage::Output { .. }
+        ));
+    }
+
+    #[test]
+    fn output_message_frames_binary_with_a_type_tag() {
+        match output_message(b"ls\r\n", false, true) {
+            ServerMessage::OutputBinary { frame } => {
+                assert_eq!(frame[0], BINARY_FRAME_RAW);
+                assert_eq!(&frame[1..], b"ls\r\n");
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+
+        // Binary framing and zstd compose: the tag says which payload
+        // the client is holding.
+        let big = vec![b'a'; COMPRESSION_MIN_BYTES * 4];
+        match output_message(&big, true, true) {
+            ServerMessage::OutputBinary { frame } => {
+                assert_eq!(frame[0], BINARY_FRAME_ZSTD);
+                assert_eq!(zstd::decode_all(&frame[1..]).unwrap(), big);
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn session_duration_parses_only_positive_seconds() {
+        assert_eq!(parse_session_duration(None), None);
+        assert_eq!(
+            parse_session_duration(Some("900".to_string())),
+            Some(Duration::from_secs(900))
+        );
+        assert_eq!(parse_session_duration(Some("0".to_string())), None);
+        assert_eq!(parse_session_duration(Some("forever".to_string())), None);
+    }
+
+    #[test]
+    fn handshake_messages_round_trip_as_json() {
+        let connected = ServerMessage::Connected {
+            session_id: "abc".to_string(),
+            features: WS_FEATURES.iter().map(|f| f.to_string()).collect(),
+        };
+        let text = serde_json::to_string(&connected).unwrap();
+        assert!(text.contains("\"type\":\"connected\""), "{text}");
+        assert!(text.contains("\"binary\""), "{text}");
+        assert!(text.contains("\"structured_exec\""), "{text}");
+
+        let init: ClientMessage =
+            serde_json::from_str(r#"{"type":"init","binary":true,"compression":"zstd"}"#).unwrap();
+        match init {
+            ClientMessage::Init {
+                binary,
+                compression,
+                line_buffered,
+            } => {
+                assert_eq!(binary, Some(true));
+                assert_eq!(compression.as_deref(), Some("zstd"));
+                assert_eq!(line_buffered, None);
+            }
+            other => panic!("unexpected message: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn take_complete_lines_holds_back_the_partial_tail() {
+        let mut buf = b"first\nsecond\npar".to_vec();
+        assert_eq!(take_complete_lines(&mut buf).unwrap(), b"first\nsecond\n");
+        assert_eq!(buf, b"par");
+        // No newline yet: everything stays buffered.
+        assert_eq!(take_complete_lines(&mut buf), None);
+        buf.extend_from_slice(b"tial\n");
+        assert_eq!(take_complete_lines(&mut buf).unwrap(), b"partial\n");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn percentile_is_nearest_rank() {
+        let mut samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&mut samples, 0.5), Duration::from_millis(50));
+        assert_eq!(percentile(&mut samples, 0.99), Duration::from_millis(99));
+        let mut single = vec![Duration::from_millis(7)];
+        assert_eq!(percentile(&mut single, 0.5), Duration::from_millis(7));
+    }
+
+    #[test]
+    fn session_errors_map_to_stable_codes() {
+        let spawn = anyhow::anyhow!("spawning shell: No such file or directory");
+        assert_eq!(session_error_code(&spawn), "SPAWN_FAILED");
+        let no_shell = anyhow::anyhow!("no shell found; tried /bin/bash, /bin/sh");
+        assert_eq!(session_error_code(&no_shell), "SPAWN_FAILED");
+        let pty = anyhow::anyhow!("opening pty: permission denied");
+        assert_eq!(session_error_code(&pty), "PTY_FAILED");
+        let cwd = anyhow::anyhow!("working directory /x does not exist");
+        assert_eq!(session_error_code(&cwd), "BAD_CWD");
+        let limit = anyhow::anyhow!("session limit reached (8/8)");
+        assert_eq!(session_error_code(&limit), "SESSION_LIMIT");
+        let bad_id = anyhow::anyhow!("session id \"nope\" is not a valid UUID");
+        assert_eq!(session_error_code(&bad_id), "BAD_SESSION_ID");
+        let bad_encoding = anyhow::anyhow!("unknown output encoding \"klingon\"");
+        assert_eq!(session_error_code(&bad_encoding), "BAD_ENCODING");
+        assert_eq!(
+            session_error_code(&anyhow::anyhow!("out of file descriptors")),
+            "SESSION_CREATE_FAILED"
+        );
+    }
+
+    #[test]
+    fn pty_failures_surface_friendly_text() {
+        use rebe_shell::pty::PtyError;
+        let typed = anyhow::anyhow!("EIO").context(PtyError::WriteFailed);
+        assert_eq!(pty_user_message(&typed), PtyError::WriteFailed.user_message());
+        // Failures from outside the PTY layer pass through unchanged.
+        assert_eq!(pty_user_message(&anyhow::anyhow!("boom")), "boom");
+    }
+
+    #[tokio::test]
+    async fn create_session_api_validates_cwd() {
+        let app = test_router(None);
+        let body = serde_json::json!({ "cwd": "/no/such/dir" });
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/sessions")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/sessions")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        serde_json::json!({ "rows": 30, "cols": 100, "term": "vt100" })
+                            .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let created: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(created["rows"], 30);
+        assert!(created["id"].is_string());
+    }
+
+    #[tokio::test]
+    async fn create_session_api_rejects_unknown_profiles() {
+        let app = test_router(None);
+        let body = serde_json::json!({ "shell_profile": "tcsh" });
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/sessions")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(error["code"], "UNKNOWN_PROFILE");
+        assert!(
+            error["user_message"]
+                .as_str()
+                .unwrap()
+                .contains("unknown shell profile"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn session_api_lists_and_404s_on_unknown_delete() {
+        let app = test_router(None);
+
+        let list = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/sessions")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(list.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(list.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"[]");
+
+        let missing = app
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri("/api/sessions/no-such-id")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn bind_addr_parses_and_validates() {
+        assert_eq!(
+            bind_addr(None).unwrap(),
+            DEFAULT_BIND_ADDR.parse::<std::net::SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            bind_addr(Some("127.0.0.1:4000".to_string()))
+                .unwrap()
+                .port(),
+            4000
+        );
+        assert!(bind_addr(Some("not-an-addr".to_string())).is_err());
+    }
+
+    #[test]
+    fn take_line_splits_pasted_input_and_keeps_partials() {
+        let mut buffer = "echo one\r\necho two\nssh ops@db1 uptime\recho part".to_string();
+        assert_eq!(take_line(&mut buffer).unwrap(), "echo one\r\n");
+        assert_eq!(take_line(&mut buffer).unwrap(), "echo two\n");
+        assert_eq!(take_line(&mut buffer).unwrap(), "ssh ops@db1 uptime\r");
+        assert_eq!(take_line(&mut buffer), None);
+        assert_eq!(buffer, "echo part");
+    }
+}
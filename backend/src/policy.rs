@@ -0,0 +1,109 @@
+//! Allow/deny filtering of command lines before they execute.
+//!
+//! For locked-down deployments that can't run WASM preview but still
+//! need guardrails: commands matching a deny rule are rejected, and
+//! when any allow rules exist a command must match one of them.
+
+use anyhow::Context;
+use regex::Regex;
+use serde::Deserialize;
+
+/// Shape of the TOML policy file: lists of regex strings.
+#[derive(Debug, Default, Deserialize)]
+struct PolicyFile {
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+/// Compiled allow/deny rules, applied to local and SSH command lines.
+#[derive(Debug, Default)]
+pub struct CommandPolicy {
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+}
+
+impl CommandPolicy {
+    /// A policy with no rules: everything is permitted.
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Load the policy from the TOML file named by `REBE_POLICY_FILE`,
+    /// or allow everything when it is unset.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("REBE_POLICY_FILE") {
+            Ok(path) => {
+                let text = std::fs::read_to_string(&path)
+                    .with_context(|| format!("reading policy file {path}"))?;
+                Self::from_toml(&text).with_context(|| format!("parsing policy file {path}"))
+            }
+            Err(_) => Ok(Self::allow_all()),
+        }
+    }
+
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let file: PolicyFile = toml::from_str(text)?;
+        let compile = |rules: Vec<String>| -> anyhow::Result<Vec<Regex>> {
+            rules
+                .into_iter()
+                .map(|rule| Regex::new(&rule).with_context(|| format!("invalid rule {rule:?}")))
+                .collect()
+        };
+        Ok(Self {
+            allow: compile(file.allow)?,
+            deny: compile(file.deny)?,
+        })
+    }
+
+    /// Check a command line. Deny rules win over allow rules; the
+    /// `Err` carries the violation message shown to the client.
+    pub fn check(&self, command: &str) -> Result<(), String> {
+        if let Some(rule) = self.deny.iter().find(|r| r.is_match(command)) {
+            return Err(format!("blocked by deny rule {:?}", rule.as_str()));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|r| r.is_match(command)) {
+            return Err("not covered by any allow rule".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deny_rules_block_matching_commands() {
+        let policy = CommandPolicy::from_toml(
+            r#"
+            deny = ['rm\s+-rf\s+/', '^mkfs']
+            "#,
+        )
+        .unwrap();
+        assert!(policy.check("rm -rf /").is_err());
+        assert!(policy.check("mkfs.ext4 /dev/sda1").is_err());
+        assert!(policy.check("ls -la").is_ok());
+    }
+
+    #[test]
+    fn allowlist_restricts_to_matches() {
+        let policy = CommandPolicy::from_toml(
+            r#"
+            allow = ['^(ls|cat|grep)\b']
+            deny = ['/etc/shadow']
+            "#,
+        )
+        .unwrap();
+        assert!(policy.check("ls /tmp").is_ok());
+        assert!(policy.check("reboot").is_err());
+        // Deny wins even when an allow rule matches.
+        assert!(policy.check("cat /etc/shadow").is_err());
+    }
+
+    #[test]
+    fn invalid_rule_is_a_startup_error() {
+        assert!(CommandPolicy::from_toml("deny = ['(unclosed']").is_err());
+    }
+}
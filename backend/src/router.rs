@@ -0,0 +1,294 @@
+//! Pluggable routing of completed input lines.
+//!
+//! The websocket handler feeds every finished line through a
+//! [`CommandRouter`] to decide where it runs. [`DefaultRouter`]
+//! implements the stock rule — `ssh user@host cmd` goes through the
+//! pool, everything else stays local — but deployments can install
+//! their own router to add prefixes (a `k8s exec ...` rule, a
+//! WASM-preview rule) without touching the handler.
+
+use rebe_shell::ssh::{HostKey, HostKeyParseError};
+
+/// Decides where a completed input line runs.
+pub(crate) trait CommandRouter: Send + Sync {
+    fn route(&self, input: &str) -> RoutedCommand;
+}
+
+/// Where a router sent a line.
+#[derive(Debug, PartialEq)]
+pub(crate) enum RoutedCommand {
+    /// Let the local shell handle it.
+    Local,
+    /// Run through the SSH pool.
+    Ssh(SshCommand),
+    /// Claimed by a routing rule but malformed; `message` is reported
+    /// to the client and nothing runs.
+    Invalid(String),
+}
+
+/// The stock routing rule: an `ssh ` prefix goes to the pool,
+/// everything else to the local PTY.
+pub(crate) struct DefaultRouter;
+
+impl CommandRouter for DefaultRouter {
+    fn route(&self, input: &str) -> RoutedCommand {
+        match input.strip_prefix("ssh ") {
+            Some(rest) => match parse_ssh_command(rest) {
+                Ok(cmd) => RoutedCommand::Ssh(cmd),
+                Err(e) => RoutedCommand::Invalid(format!("ssh command not run: {e}")),
+            },
+            None => RoutedCommand::Local,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct SshCommand {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) username: String,
+    pub(crate) command: String,
+    /// From `--timeout=<seconds>`; the library default when absent.
+    pub(crate) timeout: Option<std::time::Duration>,
+}
+
+/// Why an `ssh ...` line could not be parsed. Reported to the client
+/// instead of silently running the line locally.
+#[derive(Debug, PartialEq, thiserror::Error)]
+enum SshParseError {
+    #[error("target {0:?} is not of the form user@host[:port]")]
+    BadTarget(String),
+    #[error("invalid port {0:?}")]
+    InvalidPort(String),
+    #[error("missing command after target")]
+    MissingCommand,
+    #[error("unterminated quote in command")]
+    UnterminatedQuote,
+    #[error("trailing backslash in command")]
+    TrailingEscape,
+    #[error("invalid timeout {0:?}: expected whole seconds")]
+    InvalidTimeout(String),
+    #[error("unknown flag --{0}")]
+    UnknownFlag(String),
+}
+
+/// Parse `[--timeout=<seconds>] user@host[:port] command...`.
+///
+/// The command part is forwarded to the remote shell verbatim (so its
+/// own quoting and colons survive), but it is tokenized first to catch
+/// unbalanced quotes before anything runs.
+fn parse_ssh_command(input: &str) -> Result<SshCommand, SshParseError> {
+    let mut input = input.trim();
+    let mut timeout = None;
+    while let Some(flag_rest) = input.strip_prefix("--") {
+        let (flag, after) = match flag_rest.split_once(char::is_whitespace) {
+            Some((flag, after)) => (flag, after.trim_start()),
+            None => (flag_rest, ""),
+        };
+        match flag.split_once('=') {
+            Some(("timeout", value)) => {
+                let seconds: u64 = value
+                    .parse()
+                    .map_err(|_| SshParseError::InvalidTimeout(value.to_string()))?;
+                timeout = Some(std::time::Duration::from_secs(seconds));
+            }
+            _ => return Err(SshParseError::UnknownFlag(flag.to_string())),
+        }
+        input = after;
+    }
+
+    let (target, command) = match input.split_once(char::is_whitespace) {
+        Some((target, rest)) => (target, rest.trim()),
+        None => (input, ""),
+    };
+
+    // The CLI route requires an explicit user; the library's
+    // current-user default would silently run commands as whoever the
+    // backend happens to run as.
+    if target.split_once('@').is_none_or(|(user, _)| user.is_empty()) {
+        return Err(SshParseError::BadTarget(target.to_string()));
+    }
+    let key = HostKey::parse(target).map_err(|e| match e.downcast_ref::<HostKeyParseError>() {
+        Some(HostKeyParseError::InvalidPort { port, .. }) => {
+            SshParseError::InvalidPort(port.clone())
+        }
+        _ => SshParseError::BadTarget(target.to_string()),
+    })?;
+
+    if command.is_empty() {
+        return Err(SshParseError::MissingCommand);
+    }
+    tokenize(command)?;
+
+    Ok(SshCommand {
+        host: key.host,
+        port: key.port,
+        username: key.username,
+        command: command.to_string(),
+        timeout,
+    })
+}
+
+/// Split `input` into whitespace-separated tokens, honoring single and
+/// double quotes and backslash escapes.
+fn tokenize(input: &str) -> Result<Vec<String>, SshParseError> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut has_token = false;
+    let mut in_single = false;
+    let mut in_double = false;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' if !in_double => {
+                in_single = !in_single;
+                has_token = true;
+            }
+            '"' if !in_single => {
+                in_double = !in_double;
+                has_token = true;
+            }
+            '\\' if !in_single => match chars.next() {
+                Some(escaped) => {
+                    current.push(escaped);
+                    has_token = true;
+                }
+                None => return Err(SshParseError::TrailingEscape),
+            },
+            c if c.is_whitespace() && !in_single && !in_double => {
+                if has_token {
+                    tokens.push(std::mem::take(&mut current));
+                    has_token = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_token = true;
+            }
+        }
+    }
+    if in_single || in_double {
+        return Err(SshParseError::UnterminatedQuote);
+    }
+    if has_token {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_router_routes_the_ssh_prefix() {
+        assert_eq!(DefaultRouter.route("ls -la"), RoutedCommand::Local);
+        assert_eq!(
+            DefaultRouter.route("ssh ops@db1:2222 uptime"),
+            RoutedCommand::Ssh(SshCommand {
+                host: "db1".to_string(),
+                port: 2222,
+                username: "ops".to_string(),
+                command: "uptime".to_string(),
+                timeout: None,
+            })
+        );
+        match DefaultRouter.route("ssh db1 uptime") {
+            RoutedCommand::Invalid(message) => {
+                assert!(message.contains("user@host"), "message: {message}")
+            }
+            other => panic!("unexpected route: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn custom_routers_can_extend_the_default_rules() {
+        // A deployment-specific router claims its own prefix and
+        // defers everything else to the stock rules.
+        struct K8sAware;
+        impl CommandRouter for K8sAware {
+            fn route(&self, input: &str) -> RoutedCommand {
+                if input.starts_with("k8s ") {
+                    RoutedCommand::Invalid("k8s routing is not configured here".to_string())
+                } else {
+                    DefaultRouter.route(input)
+                }
+            }
+        }
+
+        assert!(matches!(
+            K8sAware.route("k8s exec pod -- ls"),
+            RoutedCommand::Invalid(_)
+        ));
+        assert_eq!(K8sAware.route("ls -la"), RoutedCommand::Local);
+        assert!(matches!(
+            K8sAware.route("ssh ops@db1 uptime"),
+            RoutedCommand::Ssh(_)
+        ));
+    }
+
+    #[test]
+    fn parse_ssh_command_accepts_a_timeout_flag() {
+        let cmd = parse_ssh_command("--timeout=120 ops@db1 apt-get install -y postgres").unwrap();
+        assert_eq!(cmd.timeout, Some(std::time::Duration::from_secs(120)));
+        assert_eq!(cmd.command, "apt-get install -y postgres");
+
+        assert_eq!(
+            parse_ssh_command("--timeout=abc ops@db1 uptime"),
+            Err(SshParseError::InvalidTimeout("abc".to_string()))
+        );
+        assert_eq!(
+            parse_ssh_command("--verbose ops@db1 uptime"),
+            Err(SshParseError::UnknownFlag("verbose".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_ssh_command_preserves_quoting_and_colons() {
+        let cmd = parse_ssh_command(r#"ops@db1 echo "a b" 'c d'"#).unwrap();
+        assert_eq!(cmd.command, r#"echo "a b" 'c d'"#);
+        assert_eq!(cmd.port, 22);
+
+        // Colons in the command don't get mistaken for a port.
+        let cmd = parse_ssh_command("ops@db1 grep foo:bar /etc/passwd").unwrap();
+        assert_eq!(cmd.host, "db1");
+        assert_eq!(cmd.command, "grep foo:bar /etc/passwd");
+
+        let cmd = parse_ssh_command(r"ops@db1 touch a\ b").unwrap();
+        assert_eq!(cmd.command, r"touch a\ b");
+    }
+
+    #[test]
+    fn parse_ssh_command_rejects_malformed_input() {
+        assert_eq!(
+            parse_ssh_command("db1 uptime"),
+            Err(SshParseError::BadTarget("db1".to_string()))
+        );
+        assert_eq!(
+            parse_ssh_command("ops@db1:notaport uptime"),
+            Err(SshParseError::InvalidPort("notaport".to_string()))
+        );
+        assert_eq!(
+            parse_ssh_command("ops@db1:2222"),
+            Err(SshParseError::MissingCommand)
+        );
+        assert_eq!(
+            parse_ssh_command(r#"ops@db1 echo "unclosed"#),
+            Err(SshParseError::UnterminatedQuote)
+        );
+        assert_eq!(
+            parse_ssh_command(r"ops@db1 echo trailing\"),
+            Err(SshParseError::TrailingEscape)
+        );
+    }
+
+    #[test]
+    fn tokenize_handles_quotes_and_escapes() {
+        assert_eq!(
+            tokenize(r#"echo "a b" 'c  d' e\ f"#).unwrap(),
+            vec!["echo", "a b", "c  d", "e f"]
+        );
+        assert_eq!(tokenize("  spaced   out  ").unwrap(), vec!["spaced", "out"]);
+        assert_eq!(tokenize(r#""""#).unwrap(), vec![""]);
+    }
+}
@@ -0,0 +1,165 @@
+//! Named shell profiles, so a session can ask for `bash`, `zsh`,
+//! `fish`, or `pwsh` by name instead of taking the detected default.
+//! A built-in map covers the common shells found on the host; a TOML
+//! file can override them or add site-specific profiles (custom args,
+//! pinned environment) without recompiling.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+/// One selectable shell: the program to spawn, its arguments, and any
+/// environment it pins.
+#[derive(Debug, Clone)]
+pub struct ShellProfile {
+    pub shell: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+/// A profile as it appears in the file; `args` and `env` are optional.
+#[derive(Debug, Deserialize)]
+struct ProfileEntry {
+    shell: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+}
+
+/// The configured map, keyed by the name clients send.
+#[derive(Debug, Default)]
+pub struct ShellProfiles {
+    profiles: BTreeMap<String, ShellProfile>,
+}
+
+impl ShellProfiles {
+    /// Profiles for the well-known shells actually present on this
+    /// host. A shell that isn't installed gets no profile, so asking
+    /// for it fails up front instead of at spawn time.
+    pub fn builtin() -> Self {
+        let candidates: &[(&str, &[&str])] = &[
+            ("bash", &["/bin/bash", "/usr/bin/bash"]),
+            ("zsh", &["/bin/zsh", "/usr/bin/zsh"]),
+            ("fish", &["/usr/bin/fish", "/usr/local/bin/fish"]),
+            ("pwsh", &["/usr/bin/pwsh", "/usr/local/bin/pwsh", "/opt/microsoft/powershell/7/pwsh"]),
+        ];
+        let mut profiles = BTreeMap::new();
+        for (name, paths) in candidates {
+            if let Some(path) = paths.iter().find(|p| Path::new(p).exists()) {
+                profiles.insert(
+                    name.to_string(),
+                    ShellProfile {
+                        shell: path.to_string(),
+                        args: Vec::new(),
+                        env: Vec::new(),
+                    },
+                );
+            }
+        }
+        Self { profiles }
+    }
+
+    /// Parse a profile file and lay it over the built-ins: a file entry
+    /// under an existing name replaces that built-in wholesale.
+    pub fn from_toml(text: &str) -> anyhow::Result<Self> {
+        let entries: BTreeMap<String, ProfileEntry> = toml::from_str(text)?;
+        let mut resolved = Self::builtin();
+        for (name, entry) in entries {
+            resolved.profiles.insert(
+                name,
+                ShellProfile {
+                    shell: entry.shell,
+                    args: entry.args,
+                    env: entry.env.into_iter().collect(),
+                },
+            );
+        }
+        Ok(resolved)
+    }
+
+    /// Look up a profile by the name a client sent; the error lists
+    /// what is configured so a typo is self-diagnosing.
+    pub fn resolve(&self, name: &str) -> anyhow::Result<&ShellProfile> {
+        self.profiles.get(name).ok_or_else(|| {
+            anyhow!(
+                "unknown shell profile {name:?}; configured profiles: {}",
+                if self.profiles.is_empty() {
+                    "none".to_string()
+                } else {
+                    self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+                }
+            )
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.profiles.len()
+    }
+}
+
+/// Load and parse the shell profiles at `path`.
+pub fn load_shell_profiles(path: impl AsRef<Path>) -> anyhow::Result<ShellProfiles> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("reading shell profiles {}", path.display()))?;
+    ShellProfiles::from_toml(&text)
+        .with_context(|| format!("parsing shell profiles {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+        [bash]
+        shell = "/bin/bash"
+        args = ["--noprofile"]
+
+        [minimal]
+        shell = "/bin/sh"
+        env = { PS1 = "$ ", HISTFILE = "/dev/null" }
+    "#;
+
+    #[test]
+    fn file_entries_override_and_extend_the_builtins() {
+        let profiles = ShellProfiles::from_toml(FILE).unwrap();
+
+        // The file's bash replaces the built-in wholesale.
+        let bash = profiles.resolve("bash").unwrap();
+        assert_eq!(bash.shell, "/bin/bash");
+        assert_eq!(bash.args, vec!["--noprofile"]);
+
+        let minimal = profiles.resolve("minimal").unwrap();
+        assert_eq!(minimal.shell, "/bin/sh");
+        assert_eq!(
+            minimal.env,
+            vec![
+                ("HISTFILE".to_string(), "/dev/null".to_string()),
+                ("PS1".to_string(), "$ ".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_profiles_fail_with_the_configured_names() {
+        let profiles = ShellProfiles::from_toml(FILE).unwrap();
+        let err = profiles.resolve("ksh").unwrap_err();
+        let text = err.to_string();
+        assert!(text.contains("unknown shell profile \"ksh\""), "{text}");
+        assert!(text.contains("bash"), "{text}");
+        assert!(text.contains("minimal"), "{text}");
+    }
+
+    #[test]
+    fn builtins_only_list_installed_shells() {
+        let profiles = ShellProfiles::builtin();
+        for name in ["bash", "zsh", "fish", "pwsh"] {
+            if let Ok(profile) = profiles.resolve(name) {
+                assert!(Path::new(&profile.shell).exists(), "{}", profile.shell);
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Append-only audit trail of executed commands.
+//!
+//! Every SSH exec through the pool and every completed PTY command
+//! line becomes one [`AuditRecord`]: who ran what where, with exit
+//! code and duration where the path knows them. Records go to a
+//! pluggable [`AuditSink`] — the bundled [`JsonLinesSink`] appends
+//! JSON lines to a file, a custom sink can ship them to a SIEM —
+//! after sensitive arguments are redacted by configurable patterns.
+
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// One audited command execution.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// Who ran it: the SSH username, or the PTY session's id.
+    pub principal: String,
+    /// Where it ran: `host:port` for SSH, `local` for PTY lines.
+    pub host: String,
+    pub command: String,
+    /// `None` when the command failed before producing a status, and
+    /// for PTY lines (the shell keeps their status to itself).
+    pub exit_code: Option<u32>,
+    pub duration_ms: u64,
+}
+
+impl AuditRecord {
+    /// A record stamped with the current time.
+    pub fn now(principal: impl Into<String>, host: impl Into<String>, command: impl Into<String>) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        Self {
+            timestamp_ms,
+            principal: principal.into(),
+            host: host.into(),
+            command: command.into(),
+            exit_code: None,
+            duration_ms: 0,
+        }
+    }
+}
+
+/// Where audit records go. Implementations must not block for long:
+/// they are called inline on the execution paths.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Appends records as JSON lines to any writer, one object per line.
+pub struct JsonLinesSink {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLinesSink {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Append to the file at `path`, creating it if missing. The file
+    /// is only ever opened for append, so concurrent writers and
+    /// restarts never clobber earlier records.
+    pub fn append(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening audit log {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn record(&self, record: &AuditRecord) {
+        let mut writer = self.writer.lock().expect("audit writer poisoned");
+        let write = serde_json::to_writer(&mut *writer, record)
+            .map_err(std::io::Error::other)
+            .and_then(|()| writeln!(writer))
+            .and_then(|()| writer.flush());
+        if let Err(e) = write {
+            // The command already ran; all we can do is say the trail
+            // has a hole.
+            tracing::warn!("writing audit record failed: {e}");
+        }
+    }
+}
+
+/// A sink plus the redaction applied before records reach it.
+pub struct AuditLog {
+    sink: Box<dyn AuditSink>,
+    /// Glob patterns (`*`, `?`); any whitespace-separated token of the
+    /// command matching one is replaced wholesale.
+    redact: Vec<String>,
+}
+
+impl AuditLog {
+    pub fn new(sink: impl AuditSink + 'static) -> Self {
+        Self {
+            sink: Box::new(sink),
+            redact: Vec::new(),
+        }
+    }
+
+    /// Redact command tokens matching any of `patterns` (globs, e.g.
+    /// `--password=*` or `AWS_*`) before they are recorded.
+    pub fn with_redaction(mut self, patterns: Vec<String>) -> Self {
+        self.redact = patterns;
+        self
+    }
+
+    /// Redact and record.
+    pub fn emit(&self, mut record: AuditRecord) {
+        record.command = self.redacted(&record.command);
+        self.sink.record(&record);
+    }
+
+    fn redacted(&self, command: &str) -> String {
+        if self.redact.is_empty() {
+            return command.to_string();
+        }
+        // Token-wise: the command's own spacing is not audit-relevant,
+        // its arguments are.
+        command
+            .split_whitespace()
+            .map(|token| {
+                if self.redact.iter().any(|p| crate::ssh::glob_match(p, token)) {
+                    "[REDACTED]"
+                } else {
+                    token
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Sink capturing records in memory for assertions.
+    #[derive(Default)]
+    struct Captured(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for Arc<Captured> {
+        fn record(&self, record: &AuditRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[test]
+    fn redaction_replaces_matching_tokens_only() {
+        let captured = Arc::new(Captured::default());
+        let log = AuditLog::new(captured.clone()).with_redaction(vec![
+            "--password=*".to_string(),
+            "AWS_SECRET*".to_string(),
+        ]);
+
+        log.emit(AuditRecord::now(
+            "ops",
+            "db1:22",
+            "mysqldump --password=hunter2 --all-databases AWS_SECRET_KEY=abc",
+        ));
+
+        let records = captured.0.lock().unwrap();
+        assert_eq!(
+            records[0].command,
+            "mysqldump [REDACTED] --all-databases [REDACTED]"
+        );
+        assert_eq!(records[0].principal, "ops");
+    }
+
+    #[test]
+    fn json_lines_sink_appends_one_object_per_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let log = AuditLog::new(JsonLinesSink::append(&path).unwrap());
+        log.emit(AuditRecord::now("ops", "db1:22", "uptime"));
+        // A second opening appends rather than truncating.
+        let log = AuditLog::new(JsonLinesSink::append(&path).unwrap());
+        let mut record = AuditRecord::now("ops", "db1:22", "whoami");
+        record.exit_code = Some(0);
+        record.duration_ms = 12;
+        log.emit(record);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["command"], "uptime");
+        assert_eq!(lines[1]["exit_code"], 0);
+        assert_eq!(lines[1]["duration_ms"], 12);
+    }
+}
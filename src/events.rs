@@ -0,0 +1,106 @@
+//! Typed event bus for live operational visibility.
+//!
+//! The SSH pool, circuit breaker, and PTY manager publish lifecycle
+//! events here; subscribers (the backend's `/api/events` feed, tests)
+//! receive them as they happen. The bus is a bounded
+//! `tokio::sync::broadcast` channel: publishing never blocks, and a
+//! slow subscriber loses only its own oldest events.
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Default bus capacity; past this, the slowest subscriber starts
+/// dropping its oldest events.
+const DEFAULT_CAPACITY: usize = 256;
+
+/// One operational event. Host-carrying variants use the
+/// `user@host:port` rendering of [`HostKey`](crate::ssh::HostKey).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// The circuit breaker stopped admitting commands to a host.
+    BreakerOpened { host: String },
+    /// The breaker closed again after a successful probe.
+    BreakerClosed { host: String },
+    /// A new SSH connection was established for the pool.
+    ConnectionCreated { host: String },
+    /// A checkout was served from an existing pooled connection.
+    ConnectionReused { host: String },
+    /// A dead pooled connection was dropped.
+    ConnectionReaped { host: String },
+    /// A local PTY session spawned its shell.
+    SessionSpawned { session_id: String },
+    /// A PTY session was closed and its shell killed.
+    SessionClosed { session_id: String },
+    /// A session's output rate stayed over its configured limit for
+    /// the sustained window; fired once per flood episode.
+    OutputFlooding {
+        session_id: String,
+        bytes_per_sec: u64,
+    },
+}
+
+/// Bounded, drop-oldest fan-out of [`Event`]s.
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Publish `event` to current subscribers. Never blocks; with no
+    /// subscribers the event is simply dropped.
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_subscribers_lose_oldest_events_without_blocking_publishers() {
+        let bus = EventBus::new(2);
+        let mut rx = bus.subscribe();
+        for i in 0..4 {
+            bus.publish(Event::SessionSpawned {
+                session_id: format!("s{i}"),
+            });
+        }
+        // The two oldest were dropped; the receiver is told how many.
+        match rx.recv().await {
+            Err(broadcast::error::RecvError::Lagged(n)) => assert_eq!(n, 2),
+            other => panic!("expected lag, got {other:?}"),
+        }
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Event::SessionSpawned {
+                session_id: "s2".into()
+            }
+        );
+    }
+
+    #[test]
+    fn events_serialize_with_a_type_tag() {
+        let json = serde_json::to_value(Event::BreakerOpened {
+            host: "ops@db1:22".into(),
+        })
+        .unwrap();
+        assert_eq!(json["type"], "breaker_opened");
+        assert_eq!(json["host"], "ops@db1:22");
+    }
+}
@@ -0,0 +1,845 @@
+//! Dispatches [`CommandRequest`]s to the matching execution backend.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{
+    Command, CommandRequest, CommandResponse, CommandResult, ErrorInfo, ExecutionMode,
+    ResourceLimits, ResponseMetadata, RetryPolicy,
+};
+use crate::ssh::{AuthMethod, CircuitBreaker, HostKey, SSHPool};
+use crate::wasm::{WasmLimits, WasmRuntime};
+
+/// Run `op` up to `policy.max_attempts` times, sleeping `backoff_ms`
+/// after the first failure and doubling it on each subsequent one.
+/// Returns the final outcome and the number of attempts made.
+///
+/// Thin wrapper over [`crate::retry::with_backoff`] treating every
+/// error as retryable; callers that can tell transient errors from
+/// permanent ones should use that directly.
+pub async fn retry<T, E, F, Fut>(policy: RetryPolicy, op: F) -> (Result<T, E>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+{
+    crate::retry::with_backoff(policy.into(), |_| true, op).await
+}
+
+/// [`retry`] gated by the circuit breaker for `key`: every attempt
+/// (including retries) asks the breaker first, and each outcome is
+/// recorded, so a host that trips the breaker mid-retry stops being
+/// hammered immediately.
+///
+/// Returns zero attempts when the breaker blocked the call outright.
+pub async fn retry_with_breaker<T, F, Fut>(
+    policy: RetryPolicy,
+    breaker: &CircuitBreaker,
+    key: &HostKey,
+    mut op: F,
+) -> (anyhow::Result<T>, u32)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut backoff = Duration::from_millis(policy.backoff_ms);
+    let mut attempts = 0;
+    loop {
+        if !breaker.allow(key) {
+            return (
+                Err(anyhow::anyhow!("circuit breaker open for {key}")),
+                attempts,
+            );
+        }
+        attempts += 1;
+        match op().await {
+            Ok(value) => {
+                breaker.record_success(key);
+                return (Ok(value), attempts);
+            }
+            Err(e) => {
+                breaker.record_failure(key);
+                if attempts >= max_attempts {
+                    return (Err(e), attempts);
+                }
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+/// Routes protocol requests by [`ExecutionMode`].
+pub struct Executor {
+    wasm: WasmRuntime,
+    /// Directory exposed (read-only) to WASM preview runs.
+    preview_root: PathBuf,
+    ssh_pool: Arc<SSHPool>,
+    /// Credentials for SSH-mode requests.
+    ssh_auth: AuthMethod,
+}
+
+impl Executor {
+    pub fn new(
+        preview_root: PathBuf,
+        ssh_pool: Arc<SSHPool>,
+        ssh_auth: AuthMethod,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            wasm: WasmRuntime::new()?,
+            preview_root,
+            ssh_pool,
+            ssh_auth,
+        })
+    }
+
+    /// Execute one request and build the response envelope, applying
+    /// the request's per-attempt timeout, overall deadline, and retry
+    /// policy.
+    pub async fn execute(&self, request: CommandRequest) -> CommandResponse {
+        let started = Instant::now();
+        let timeout = Duration::from_millis(request.config.timeout_ms);
+        let deadline = request.config.deadline_ms.map(Duration::from_millis);
+        let mut policy: crate::retry::BackoffPolicy = request.config.retry_policy.into();
+        // The deadline bounds the whole retry loop: backoff sleeps
+        // that would cross it abandon the remaining attempts.
+        policy.deadline = deadline;
+
+        let req = &request;
+        let (outcome, attempts) = crate::retry::with_backoff(policy, |_| true, move || async move {
+            // Each attempt is also clipped to the remaining budget, so
+            // a slow attempt can't run past the deadline either.
+            let remaining = deadline.map(|d| d.saturating_sub(started.elapsed()));
+            let attempt_timeout = remaining.map_or(timeout, |r| timeout.min(r));
+            match tokio::time::timeout(attempt_timeout, self.dispatch(req)).await {
+                Ok(result) => result,
+                Err(_) if attempt_timeout < timeout => Err(ErrorInfo {
+                    code: "DEADLINE_EXCEEDED".to_string(),
+                    message: format!(
+                        "deadline of {}ms exhausted",
+                        req.config.deadline_ms.unwrap_or_default()
+                    ),
+                }),
+                Err(_) => Err(ErrorInfo {
+                    code: "TIMEOUT".to_string(),
+                    message: format!("command exceeded {}ms", req.config.timeout_ms),
+                }),
+            }
+        })
+        .await;
+        let result = match outcome {
+            Ok(data) => CommandResult::Success { data },
+            Err(error) => CommandResult::Error { error },
+        };
+
+        CommandResponse {
+            version: request.version,
+            id: request.id,
+            result,
+            metadata: ResponseMetadata {
+                duration_ms: started.elapsed().as_millis() as u64,
+                attempts,
+                cached: false,
+            },
+        }
+    }
+
+    async fn dispatch(&self, request: &CommandRequest) -> Result<serde_json::Value, ErrorInfo> {
+        match (&request.config.mode, &request.command) {
+            (ExecutionMode::Native, Command::Execute { script }) => {
+                self.execute_native(script, request.config.limits.as_ref())
+                    .await
+            }
+            (ExecutionMode::SSH, Command::Execute { script }) => {
+                self.execute_ssh(request, script).await
+            }
+            (ExecutionMode::Native, Command::Exec { program, args }) => {
+                self.execute_exec(program, args, request.config.limits.as_ref())
+                    .await
+            }
+            (ExecutionMode::Native, Command::SystemInfo { fields }) => {
+                self.execute_system_info(fields).await
+            }
+            (ExecutionMode::WASM, Command::Execute { script }) => {
+                self.execute_wasm_preview(script).await
+            }
+            (
+                _,
+                Command::Batch {
+                    commands,
+                    stop_on_error,
+                },
+            ) => self.execute_batch(request, commands, *stop_on_error).await,
+            (mode, _) => Err(ErrorInfo {
+                code: "UNSUPPORTED_MODE".to_string(),
+                message: format!("execution mode {mode:?} is not wired up for this command"),
+            }),
+        }
+    }
+
+    /// Batch: run the nested commands in order under the request's
+    /// config, collecting a [`CommandResult`] per command. With
+    /// `stop_on_error`, commands after the first failure are skipped
+    /// and don't appear in the results.
+    async fn execute_batch(
+        &self,
+        request: &CommandRequest,
+        commands: &[Command],
+        stop_on_error: bool,
+    ) -> Result<serde_json::Value, ErrorInfo> {
+        let mut results = Vec::with_capacity(commands.len());
+        for command in commands {
+            let mut sub = request.clone();
+            sub.command = command.clone();
+            let outcome = Box::pin(self.dispatch(&sub)).await;
+            let failed = outcome.is_err();
+            results.push(match outcome {
+                Ok(data) => CommandResult::Success { data },
+                Err(error) => CommandResult::Error { error },
+            });
+            if stop_on_error && failed {
+                break;
+            }
+        }
+        serde_json::to_value(results).map_err(|e| ErrorInfo {
+            code: "SERIALIZATION_FAILED".to_string(),
+            message: format!("encoding batch results: {e}"),
+        })
+    }
+
+    /// Native mode: spawn on the backend host without a PTY.
+    async fn execute_native(
+        &self,
+        script: &str,
+        limits: Option<&ResourceLimits>,
+    ) -> Result<serde_json::Value, ErrorInfo> {
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.arg("-c").arg(script);
+        if let Some(limits) = limits {
+            apply_limits(&mut cmd, limits)?;
+        }
+        let output = cmd.output().await.map_err(|e| ErrorInfo {
+            code: "SPAWN_FAILED".to_string(),
+            message: format!("spawning native command: {e}"),
+        })?;
+        Ok(serde_json::json!({
+            "stdout": String::from_utf8_lossy(&output.stdout),
+            "stderr": String::from_utf8_lossy(&output.stderr),
+            "exit_code": output.status.code(),
+        }))
+    }
+
+    /// Native mode, no shell: `program` is resolved through `PATH`
+    /// (`execvp`-style) and `args` reach it verbatim, so nothing in
+    /// them is expanded, split, or interpretable as shell syntax.
+    async fn execute_exec(
+        &self,
+        program: &str,
+        args: &[String],
+        limits: Option<&ResourceLimits>,
+    ) -> Result<serde_json::Value, ErrorInfo> {
+        let mut cmd = tokio::process::Command::new(program);
+        cmd.args(args);
+        if let Some(limits) = limits {
+            apply_limits(&mut cmd, limits)?;
+        }
+        let output = cmd.output().await.map_err(|e| ErrorInfo {
+            code: "SPAWN_FAILED".to_string(),
+            message: format!("spawning {program}: {e}"),
+        })?;
+        Ok(serde_json::json!({
+            "stdout": String::from_utf8_lossy(&output.stdout),
+            "stderr": String::from_utf8_lossy(&output.stderr),
+            "exit_code": output.status.code(),
+        }))
+    }
+
+    /// SSH mode: requires `config.target` (`user@host[:port]`); the
+    /// command runs through the shared pool.
+    async fn execute_ssh(
+        &self,
+        request: &CommandRequest,
+        script: &str,
+    ) -> Result<serde_json::Value, ErrorInfo> {
+        let Some(target) = &request.config.target else {
+            return Err(ErrorInfo {
+                code: "MISSING_TARGET".to_string(),
+                message: "ssh mode requires config.target (user@host[:port])".to_string(),
+            });
+        };
+        let key = parse_target(target).map_err(|e| ErrorInfo {
+            code: "BAD_TARGET".to_string(),
+            message: format!("{e:#}"),
+        })?;
+        let output = self
+            .ssh_pool
+            .exec(&key, &self.ssh_auth, script)
+            .await
+            .map_err(|e| ErrorInfo {
+                code: "SSH_FAILED".to_string(),
+                message: format!("{e:#}"),
+            })?;
+        Ok(serde_json::json!({
+            "stdout": output.stdout_lossy(),
+            "stderr": output.stderr_lossy(),
+            "exit_code": output.exit_status,
+        }))
+    }
+
+    /// SystemInfo: gather structured facts about the backend host for
+    /// the requested fields. Unknown or unreadable fields land under
+    /// `errors` keyed by field name instead of failing the request.
+    async fn execute_system_info(&self, fields: &[String]) -> Result<serde_json::Value, ErrorInfo> {
+        let mut values = serde_json::Map::new();
+        let mut errors = serde_json::Map::new();
+        for field in fields {
+            match system_info_field(field) {
+                Ok(value) => {
+                    values.insert(field.clone(), value);
+                }
+                Err(e) => {
+                    errors.insert(field.clone(), serde_json::Value::String(format!("{e:#}")));
+                }
+            }
+        }
+        Ok(serde_json::json!({ "fields": values, "errors": errors }))
+    }
+
+    /// WASM mode: `script` names a module on disk, run in the preview
+    /// sandbox with the preview root mounted read-only.
+    async fn execute_wasm_preview(&self, script: &str) -> Result<serde_json::Value, ErrorInfo> {
+        let module = tokio::fs::read(script).await.map_err(|e| ErrorInfo {
+            code: "MODULE_NOT_FOUND".to_string(),
+            message: format!("reading wasm module {script}: {e}"),
+        })?;
+        let preview = self
+            .wasm
+            .execute_preview(&module, &[], &self.preview_root, WasmLimits::default())
+            .await
+            .map_err(|e| ErrorInfo {
+                code: "WASM_EXECUTION_FAILED".to_string(),
+                message: format!("{e:#}"),
+            })?;
+        Ok(serde_json::json!({
+            "stdout": preview.stdout,
+            "stderr": preview.stderr,
+            "exit_code": preview.exit_code,
+            "filesystem_changes": preview.filesystem_changes,
+        }))
+    }
+}
+
+/// Arrange for `limits` to bind the child `cmd` is about to spawn.
+///
+/// On Unix the limits are applied in a `pre_exec` closure — between
+/// fork and exec, in the child — so they constrain the command without
+/// touching the backend process; a failure there surfaces as a spawn
+/// error. Elsewhere there is no equivalent mechanism, so the request
+/// is rejected rather than run unlimited.
+#[cfg(unix)]
+fn apply_limits(cmd: &mut tokio::process::Command, limits: &ResourceLimits) -> Result<(), ErrorInfo> {
+    fn setrlimit(resource: libc::__rlimit_resource_t, value: u64) -> std::io::Result<()> {
+        let limit = libc::rlimit {
+            rlim_cur: value as libc::rlim_t,
+            rlim_max: value as libc::rlim_t,
+        };
+        // SAFETY: passing a valid rlimit struct by reference.
+        if unsafe { libc::setrlimit(resource, &limit) } == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
+    let limits = *limits;
+    // SAFETY: the closure only makes async-signal-safe syscalls
+    // (setpriority, setrlimit) and touches no locks or allocations.
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(nice) = limits.nice {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            if let Some(seconds) = limits.cpu_seconds {
+                setrlimit(libc::RLIMIT_CPU, seconds)?;
+            }
+            if let Some(bytes) = limits.max_memory_bytes {
+                setrlimit(libc::RLIMIT_AS, bytes)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_limits(
+    _cmd: &mut tokio::process::Command,
+    _limits: &ResourceLimits,
+) -> Result<(), ErrorInfo> {
+    Err(ErrorInfo {
+        code: "UNSUPPORTED_LIMITS".to_string(),
+        message: "resource limits require a Unix backend".to_string(),
+    })
+}
+
+/// Gather one [`Command::SystemInfo`] field from the local host, as
+/// typed JSON rather than raw command output.
+fn system_info_field(field: &str) -> anyhow::Result<serde_json::Value> {
+    use std::fs::read_to_string;
+    Ok(match field {
+        "hostname" => read_to_string("/proc/sys/kernel/hostname")?.trim().into(),
+        "cpu_info" => {
+            let cpuinfo = read_to_string("/proc/cpuinfo")?;
+            let model = cpuinfo
+                .lines()
+                .find(|l| l.starts_with("model name"))
+                .and_then(|l| l.split_once(':'))
+                .map(|(_, v)| v.trim().to_string());
+            let cores = cpuinfo
+                .lines()
+                .filter(|l| l.starts_with("processor"))
+                .count();
+            serde_json::json!({ "model": model, "cores": cores })
+        }
+        "mem_total" => {
+            let meminfo = read_to_string("/proc/meminfo")?;
+            let kilobytes: u64 = meminfo
+                .lines()
+                .find(|l| l.starts_with("MemTotal:"))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .ok_or_else(|| anyhow::anyhow!("MemTotal missing from /proc/meminfo"))?
+                .parse()?;
+            serde_json::json!({ "bytes": kilobytes * 1024 })
+        }
+        "os_release" => {
+            let text = read_to_string("/etc/os-release")?;
+            let pretty_name = text
+                .lines()
+                .find_map(|l| l.strip_prefix("PRETTY_NAME="))
+                .map(|v| v.trim_matches('"').to_string());
+            serde_json::json!({ "pretty_name": pretty_name })
+        }
+        "uptime" => {
+            let seconds: f64 = read_to_string("/proc/uptime")?
+                .split_whitespace()
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("/proc/uptime is empty"))?
+                .parse()?;
+            serde_json::json!({ "seconds": seconds })
+        }
+        other => anyhow::bail!("unknown field {other:?}"),
+    })
+}
+
+/// Parse an SSH target of the form `user@host[:port]`.
+fn parse_target(target: &str) -> anyhow::Result<HostKey> {
+    let (username, hostport) = target
+        .split_once('@')
+        .ok_or_else(|| anyhow::anyhow!("target {target:?} is missing user@"))?;
+    let (host, port) = match hostport.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse()?),
+        None => (hostport, 22),
+    };
+    if username.is_empty() || host.is_empty() {
+        anyhow::bail!("target {target:?} has an empty user or host");
+    }
+    Ok(HostKey::new(host, port, username))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ExecutionConfig, RetryPolicy};
+
+    fn executor(preview_root: PathBuf) -> Executor {
+        Executor::new(
+            preview_root,
+            Arc::new(SSHPool::new()),
+            AuthMethod::Password(String::new()),
+        )
+        .unwrap()
+    }
+
+    fn request(mode: ExecutionMode, command: Command) -> CommandRequest {
+        CommandRequest {
+            version: "1.0".to_string(),
+            id: "req-1".to_string(),
+            command,
+            config: ExecutionConfig {
+                mode,
+                timeout_ms: 5_000,
+                deadline_ms: None,
+                retry_policy: RetryPolicy::default(),
+                target: None,
+                limits: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn native_mode_spawns_without_a_pty() {
+        let executor = executor(std::env::temp_dir());
+        let response = executor
+            .execute(request(
+                ExecutionMode::Native,
+                Command::Execute {
+                    script: "echo native-ok".to_string(),
+                },
+            ))
+            .await;
+        match response.result {
+            CommandResult::Success { data } => {
+                assert_eq!(data["stdout"], "native-ok\n");
+                assert_eq!(data["exit_code"], 0);
+            }
+            CommandResult::Error { error } => panic!("unexpected error: {error:?}"),
+        }
+        assert_eq!(response.metadata.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn deadline_cuts_retries_short_of_max_attempts() {
+        let executor = executor(std::env::temp_dir());
+        // SSH mode without a target fails every attempt immediately.
+        let mut request = request(
+            ExecutionMode::SSH,
+            Command::Execute {
+                script: "true".to_string(),
+            },
+        );
+        request.config.retry_policy = RetryPolicy {
+            max_attempts: 10,
+            backoff_ms: 200,
+        };
+        request.config.deadline_ms = Some(300);
+
+        let started = std::time::Instant::now();
+        let response = executor.execute(request).await;
+        match response.result {
+            CommandResult::Error { error } => assert_eq!(error.code, "MISSING_TARGET"),
+            CommandResult::Success { data } => panic!("unexpected success: {data}"),
+        }
+        // The 200ms-then-400ms backoff crosses the 300ms deadline well
+        // before ten attempts.
+        assert!(response.metadata.attempts < 10, "attempts: {}", response.metadata.attempts);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn deadline_clips_an_attempt_that_outlives_it() {
+        let executor = executor(std::env::temp_dir());
+        let mut request = request(
+            ExecutionMode::Native,
+            Command::Execute {
+                script: "sleep 5".to_string(),
+            },
+        );
+        request.config.deadline_ms = Some(200);
+
+        let response = executor.execute(request).await;
+        match response.result {
+            CommandResult::Error { error } => {
+                assert_eq!(error.code, "DEADLINE_EXCEEDED");
+                assert!(error.message.contains("200ms"), "message: {}", error.message);
+            }
+            CommandResult::Success { data } => panic!("unexpected success: {data}"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cpu_limit_terminates_a_runaway_command() {
+        let executor = executor(std::env::temp_dir());
+        let mut request = request(
+            ExecutionMode::Native,
+            Command::Execute {
+                script: "while :; do :; done".to_string(),
+            },
+        );
+        request.config.limits = Some(ResourceLimits {
+            cpu_seconds: Some(1),
+            ..Default::default()
+        });
+        let response = executor.execute(request).await;
+        match response.result {
+            CommandResult::Success { data } => {
+                // The kernel delivered SIGXCPU after one CPU second,
+                // so the shell died to a signal: no exit code.
+                assert!(data["exit_code"].is_null(), "survived the cpu limit: {data}");
+            }
+            CommandResult::Error { error } => panic!("unexpected error: {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn exec_passes_arguments_through_without_a_shell() {
+        let executor = executor(std::env::temp_dir());
+        let response = executor
+            .execute(request(
+                ExecutionMode::Native,
+                Command::Exec {
+                    program: "printf".to_string(),
+                    args: vec![
+                        "%s|".to_string(),
+                        "two words".to_string(),
+                        "$HOME".to_string(),
+                    ],
+                },
+            ))
+            .await;
+        match response.result {
+            CommandResult::Success { data } => {
+                // The embedded space and the `$HOME` survive verbatim:
+                // no shell got a chance to split or expand them.
+                assert_eq!(data["stdout"], "two words|$HOME|");
+                assert_eq!(data["exit_code"], 0);
+            }
+            CommandResult::Error { error } => panic!("unexpected error: {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn system_info_reports_known_fields_and_flags_unknown_ones() {
+        let executor = executor(std::env::temp_dir());
+        let response = executor
+            .execute(request(
+                ExecutionMode::Native,
+                Command::SystemInfo {
+                    fields: vec![
+                        "hostname".to_string(),
+                        "mem_total".to_string(),
+                        "uptime".to_string(),
+                        "bogus".to_string(),
+                    ],
+                },
+            ))
+            .await;
+        match response.result {
+            CommandResult::Success { data } => {
+                assert!(data["fields"]["hostname"].is_string());
+                assert!(data["fields"]["mem_total"]["bytes"].as_u64().unwrap() > 0);
+                assert!(data["fields"]["uptime"]["seconds"].as_f64().unwrap() > 0.0);
+                let bogus = data["errors"]["bogus"].as_str().unwrap();
+                assert!(bogus.contains("unknown field"), "{bogus}");
+            }
+            CommandResult::Error { error } => panic!("unexpected error: {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_runs_commands_in_order() {
+        let executor = executor(std::env::temp_dir());
+        let response = executor
+            .execute(request(
+                ExecutionMode::Native,
+                Command::Batch {
+                    commands: vec![
+                        Command::Execute {
+                            script: "echo first".to_string(),
+                        },
+                        Command::Execute {
+                            script: "echo second".to_string(),
+                        },
+                    ],
+                    stop_on_error: false,
+                },
+            ))
+            .await;
+        match response.result {
+            CommandResult::Success { data } => {
+                let results = data.as_array().unwrap();
+                assert_eq!(results.len(), 2);
+                assert_eq!(results[0]["data"]["stdout"], "first\n");
+                assert_eq!(results[1]["data"]["stdout"], "second\n");
+            }
+            CommandResult::Error { error } => panic!("unexpected error: {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_short_circuits_when_stop_on_error_is_set() {
+        let executor = executor(std::env::temp_dir());
+        let response = executor
+            .execute(request(
+                ExecutionMode::SSH,
+                Command::Batch {
+                    commands: vec![
+                        // No config.target: fails with MISSING_TARGET.
+                        Command::Execute {
+                            script: "echo unreachable".to_string(),
+                        },
+                        Command::Execute {
+                            script: "echo skipped".to_string(),
+                        },
+                    ],
+                    stop_on_error: true,
+                },
+            ))
+            .await;
+        match response.result {
+            CommandResult::Success { data } => {
+                let results = data.as_array().unwrap();
+                assert_eq!(results.len(), 1, "second command should be skipped");
+                assert_eq!(results[0]["error"]["code"], "MISSING_TARGET");
+            }
+            CommandResult::Error { error } => panic!("unexpected error: {error:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn per_attempt_timeout_is_enforced() {
+        let executor = executor(std::env::temp_dir());
+        let mut req = request(
+            ExecutionMode::Native,
+            Command::Execute {
+                script: "sleep 5".to_string(),
+            },
+        );
+        req.config.timeout_ms = 100;
+        let response = executor.execute(req).await;
+        match response.result {
+            CommandResult::Error { error } => assert_eq!(error.code, "TIMEOUT"),
+            CommandResult::Success { .. } => panic!("expected timeout"),
+        }
+    }
+
+    #[tokio::test]
+    async fn wasm_mode_runs_module_in_preview() {
+        let dir = tempfile::tempdir().unwrap();
+        let module_path = dir.path().join("hello.wasm");
+        let module = wat::parse_str(
+            r#"(module
+                 (import "wasi_snapshot_preview1" "fd_write"
+                   (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                 (memory (export "memory") 1)
+                 (data (i32.const 8) "preview\n")
+                 (func (export "_start")
+                   (i32.store (i32.const 0) (i32.const 8))
+                   (i32.store (i32.const 4) (i32.const 8))
+                   (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 24)))))"#,
+        )
+        .unwrap();
+        std::fs::write(&module_path, module).unwrap();
+
+        let executor = executor(dir.path().to_path_buf());
+        let response = executor
+            .execute(request(
+                ExecutionMode::WASM,
+                Command::Execute {
+                    script: module_path.to_string_lossy().into_owned(),
+                },
+            ))
+            .await;
+
+        match response.result {
+            CommandResult::Success { data } => {
+                assert_eq!(data["stdout"], "preview\n");
+                assert_eq!(data["exit_code"], 0);
+            }
+            CommandResult::Error { error } => panic!("unexpected error: {error:?}"),
+        }
+        assert_eq!(response.id, "req-1");
+    }
+
+    #[tokio::test]
+    async fn ssh_mode_without_target_reports_missing_target() {
+        let executor = executor(std::env::temp_dir());
+        let response = executor
+            .execute(request(
+                ExecutionMode::SSH,
+                Command::Execute {
+                    script: "uptime".to_string(),
+                },
+            ))
+            .await;
+        match response.result {
+            CommandResult::Error { error } => assert_eq!(error.code, "MISSING_TARGET"),
+            CommandResult::Success { .. } => panic!("expected error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn retry_reports_attempts_on_transient_success() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff_ms: 1,
+        };
+        let mut calls = 0u32;
+        let (outcome, attempts) = retry(policy, || {
+            calls += 1;
+            let fail = calls < 2;
+            async move {
+                if fail {
+                    Err("transient")
+                } else {
+                    Ok("recovered")
+                }
+            }
+        })
+        .await;
+        assert_eq!(outcome, Ok("recovered"));
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn retry_stops_at_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff_ms: 1,
+        };
+        let (outcome, attempts) = retry(policy, || async { Err::<(), _>("permanent") }).await;
+        assert_eq!(outcome, Err("permanent"));
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn retry_with_breaker_stops_hammering_once_open() {
+        use crate::ssh::BreakerConfig;
+
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            backoff_ms: 1,
+        };
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(3600),
+        });
+        let key = HostKey::new("db1.example", 22, "ops");
+        let (outcome, attempts) = retry_with_breaker::<(), _, _>(policy, &breaker, &key, || async {
+            anyhow::bail!("connection refused")
+        })
+        .await;
+        // Two failures open the breaker; the third attempt is blocked.
+        assert_eq!(attempts, 2);
+        assert!(outcome.unwrap_err().to_string().contains("circuit breaker"));
+    }
+
+    #[tokio::test]
+    async fn executor_retries_up_to_policy_and_records_attempts() {
+        let executor = executor(std::env::temp_dir());
+        let mut req = request(
+            ExecutionMode::SSH,
+            Command::Execute {
+                script: "uptime".to_string(),
+            },
+        );
+        req.config.retry_policy = RetryPolicy {
+            max_attempts: 3,
+            backoff_ms: 1,
+        };
+        let response = executor.execute(req).await;
+        assert!(matches!(response.result, CommandResult::Error { .. }));
+        assert_eq!(response.metadata.attempts, 3);
+    }
+
+    #[test]
+    fn parse_target_handles_port() {
+        let key = parse_target("ops@db1.example:2222").unwrap();
+        assert_eq!(key, HostKey::new("db1.example", 2222, "ops"));
+        assert_eq!(parse_target("ops@db1.example").unwrap().port, 22);
+        assert!(parse_target("db1.example").is_err());
+    }
+}
@@ -0,0 +1,15 @@
+//! rebe-shell: a remote execution backend.
+//!
+//! The crate is organised around a pooled SSH layer (`ssh`) that higher
+//! layers build on for bulk and interactive remote execution.
+
+pub mod audit;
+pub mod events;
+pub mod execute;
+pub mod local;
+pub mod protocol;
+pub mod pty;
+pub mod retry;
+pub mod ssh;
+pub mod stream;
+pub mod wasm;
@@ -0,0 +1,160 @@
+//! Direct local process execution, without a PTY.
+//!
+//! Structured Native-mode requests want a spawned process with its
+//! stdout, stderr, and exit code captured — not an interactive shell
+//! whose echoed output has to be parsed back apart. [`run`] spawns via
+//! [`tokio::process::Command`], buffers both streams through the
+//! bounded streaming handler, and kills the process if it outlives the
+//! timeout.
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::ssh::DEFAULT_MAX_OUTPUT_BYTES;
+use crate::stream::StreamingOutputHandler;
+
+/// Captured result of a local command.
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// `None` when the process was killed — by a signal or by the
+    /// timeout.
+    pub exit_code: Option<i32>,
+    /// The process outlived its timeout and was killed.
+    pub timed_out: bool,
+    /// Whether output was cut off at the capture cap (see
+    /// [`DEFAULT_MAX_OUTPUT_BYTES`](crate::ssh::DEFAULT_MAX_OUTPUT_BYTES)).
+    pub truncated: bool,
+}
+
+impl CommandOutput {
+    pub fn stdout_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+
+    pub fn stderr_lossy(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0) && !self.timed_out
+    }
+}
+
+/// Read `stream` to EOF into a bounded, truncating handler.
+async fn capture<R: AsyncRead + Unpin>(mut stream: R) -> Result<StreamingOutputHandler> {
+    let mut handler = StreamingOutputHandler::new_truncating(DEFAULT_MAX_OUTPUT_BYTES);
+    let mut buf = vec![0u8; 8192];
+    loop {
+        let n = stream.read(&mut buf).await.context("reading child output")?;
+        if n == 0 {
+            return Ok(handler);
+        }
+        handler.push_chunk(&buf[..n])?;
+    }
+}
+
+/// Run `cmd` with `args` locally, capturing stdout, stderr, and the
+/// exit code. The process is killed once `timeout` elapses; whatever
+/// output it produced up to that point is still returned, with
+/// [`timed_out`](CommandOutput::timed_out) set.
+pub async fn run(cmd: &str, args: &[&str], timeout: Duration) -> Result<CommandOutput> {
+    let mut child = tokio::process::Command::new(cmd)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("spawning {cmd}"))?;
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("child stdout not captured"))?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow!("child stderr not captured"))?;
+    let capture_both = async {
+        let (stdout, stderr) = tokio::join!(capture(stdout), capture(stderr));
+        Ok::<_, anyhow::Error>((stdout?, stderr?))
+    };
+
+    // Output reaches EOF when the child (and anything holding its
+    // pipes) exits, so racing the capture covers the wait too.
+    match tokio::time::timeout(timeout, capture_both).await {
+        Ok(captured) => {
+            let (stdout, stderr) = captured?;
+            let status = child.wait().await.context("waiting for child")?;
+            Ok(CommandOutput {
+                truncated: stdout.truncated() || stderr.truncated(),
+                stdout: stdout.finalize(),
+                stderr: stderr.finalize(),
+                exit_code: status.code(),
+                timed_out: false,
+            })
+        }
+        Err(_) => {
+            child.kill().await.context("killing timed-out child")?;
+            Ok(CommandOutput {
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+                exit_code: None,
+                timed_out: true,
+                truncated: false,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn captures_both_streams_and_the_exit_code() {
+        let output = run(
+            "sh",
+            &["-c", "echo out; echo err >&2; exit 3"],
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.stdout_lossy(), "out\n");
+        assert_eq!(output.stderr_lossy(), "err\n");
+        assert_eq!(output.exit_code, Some(3));
+        assert!(!output.success());
+        assert!(!output.timed_out);
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_the_child() {
+        let started = std::time::Instant::now();
+        let output = run("sleep", &["30"], Duration::from_millis(100))
+            .await
+            .unwrap();
+        assert!(output.timed_out);
+        assert_eq!(output.exit_code, None);
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "kill took {:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn spawn_failure_names_the_command() {
+        let err = run("definitely-not-a-real-binary", &[], Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("definitely-not-a-real-binary"),
+            "{err:#}"
+        );
+    }
+}
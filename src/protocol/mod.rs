@@ -0,0 +1,284 @@
+//! The structured command protocol spoken by clients.
+//!
+//! Requests and responses are typed and JSON-serialised; the goal is
+//! structured-over-textual command exchange, with plain terminal I/O
+//! reserved for the interactive PTY path.
+
+use serde::{Deserialize, Serialize};
+
+/// The protocol version this crate implements, as `major.minor`.
+///
+/// Versions with the same major are wire-compatible: additions are
+/// made with `#[serde(default)]` fields and new enum variants only.
+/// A major bump marks an incompatible change.
+pub const PROTOCOL_VERSION: &str = "1.0";
+
+/// The major component of a `major.minor` version string, or `None`
+/// when it isn't a number.
+fn major_of(version: &str) -> Option<u64> {
+    version.split('.').next()?.parse().ok()
+}
+
+/// How a command should be executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::upper_case_acronyms)]
+pub enum ExecutionMode {
+    /// Spawn on the backend host itself.
+    Native,
+    /// Run on a remote host through the connection pool.
+    SSH,
+    /// Run a sandboxed WebAssembly module in preview mode.
+    WASM,
+}
+
+/// Retry behaviour for a failed command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Initial backoff; doubles on each subsequent attempt.
+    pub backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff_ms: 100,
+        }
+    }
+}
+
+/// Execution parameters accompanying a [`Command`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecutionConfig {
+    pub mode: ExecutionMode,
+    /// Per-attempt timeout, in milliseconds.
+    pub timeout_ms: u64,
+    /// Overall budget across every retry attempt, in milliseconds.
+    /// The executor stops retrying — and clips the in-flight attempt —
+    /// once it is exhausted, bounding worst-case latency no matter how
+    /// generous the retry policy. `None` leaves only `timeout_ms`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline_ms: Option<u64>,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// Remote target for SSH mode, as `user@host[:port]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Resource limits for locally spawned children (native mode).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<ResourceLimits>,
+}
+
+/// Caps applied to a locally spawned child, so one heavy command on a
+/// shared backend can't starve everything else. Unix only: the limits
+/// bind via `setrlimit`/`setpriority` between fork and exec; on other
+/// platforms a request carrying limits is rejected rather than run
+/// silently unlimited.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    /// Nice value for the child (higher is lower priority).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+    /// CPU seconds before the kernel terminates the child
+    /// (`RLIMIT_CPU`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+    /// Address-space cap in bytes (`RLIMIT_AS`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_memory_bytes: Option<u64>,
+}
+
+/// The operation a client asks the backend to perform.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Run a script line through the configured execution mode.
+    ///
+    /// The string is shell-interpreted, so quoting, expansion, and
+    /// injection are the caller's problem; prefer
+    /// [`Exec`](Command::Exec) unless shell features are the point.
+    Execute { script: String },
+    /// Run `program` with `args` directly, with no shell in between.
+    ///
+    /// Arguments pass through verbatim — spaces, quotes, `$`, and
+    /// globs mean nothing — so there is no quoting to get wrong and
+    /// no injection surface when splicing in untrusted values.
+    Exec {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// Collect structured host facts for the requested fields.
+    SystemInfo { fields: Vec<String> },
+    /// Run several commands in order, collecting every result into the
+    /// response data.
+    Batch {
+        commands: Vec<Command>,
+        /// Stop at the first failing command instead of running the
+        /// rest.
+        stop_on_error: bool,
+    },
+}
+
+/// A single client request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandRequest {
+    /// Protocol version the client speaks, e.g. `"1.0"`.
+    pub version: String,
+    /// Client-chosen correlation id, echoed back in the response.
+    pub id: String,
+    pub command: Command,
+    pub config: ExecutionConfig,
+}
+
+impl CommandRequest {
+    /// Check the client's protocol version against
+    /// [`PROTOCOL_VERSION`].
+    ///
+    /// Requests from a different (or unparsable) major are rejected
+    /// with an `UNSUPPORTED_VERSION` error the caller can return
+    /// verbatim; minor drift within a major is accepted.
+    pub fn validate_version(&self) -> Result<(), ErrorInfo> {
+        let server_major = major_of(PROTOCOL_VERSION).expect("PROTOCOL_VERSION is well-formed");
+        match major_of(&self.version) {
+            Some(client_major) if client_major == server_major => Ok(()),
+            _ => Err(ErrorInfo {
+                code: "UNSUPPORTED_VERSION".to_string(),
+                message: format!(
+                    "client protocol version {:?} is not compatible with server version {PROTOCOL_VERSION}",
+                    self.version
+                ),
+            }),
+        }
+    }
+}
+
+/// Machine-readable error details.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    /// Stable error code, e.g. `"UNSUPPORTED_MODE"`.
+    pub code: String,
+    pub message: String,
+}
+
+/// Outcome of one executed command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum CommandResult {
+    Success { data: serde_json::Value },
+    Error { error: ErrorInfo },
+}
+
+/// Bookkeeping attached to every response.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResponseMetadata {
+    pub duration_ms: u64,
+    /// Number of attempts made, including the successful one.
+    pub attempts: u32,
+    pub cached: bool,
+}
+
+/// The backend's reply to a [`CommandRequest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub version: String,
+    /// Correlation id from the request.
+    pub id: String,
+    pub result: CommandResult,
+    pub metadata: ResponseMetadata,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_version(version: &str) -> CommandRequest {
+        CommandRequest {
+            version: version.to_string(),
+            id: "req-1".to_string(),
+            command: Command::Execute {
+                script: "true".to_string(),
+            },
+            config: ExecutionConfig {
+                mode: ExecutionMode::Native,
+                timeout_ms: 1000,
+                deadline_ms: None,
+                retry_policy: RetryPolicy::default(),
+                target: None,
+                limits: None,
+            },
+        }
+    }
+
+    #[test]
+    fn batch_variant_round_trips_with_nested_commands() {
+        let command = Command::Batch {
+            commands: vec![
+                Command::Execute {
+                    script: "apt-get update".to_string(),
+                },
+                Command::SystemInfo {
+                    fields: vec!["hostname".to_string()],
+                },
+            ],
+            stop_on_error: true,
+        };
+        let json = serde_json::to_value(&command).unwrap();
+        assert_eq!(json["type"], "batch");
+        assert_eq!(json["stop_on_error"], true);
+        assert_eq!(json["commands"][0]["type"], "execute");
+        assert_eq!(json["commands"][1]["type"], "system_info");
+
+        let back: Command = serde_json::from_value(json).unwrap();
+        match back {
+            Command::Batch { commands, .. } => assert_eq!(commands.len(), 2),
+            other => panic!("unexpected variant: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn exec_variant_round_trips_and_defaults_args_to_empty() {
+        let command = Command::Exec {
+            program: "grep".to_string(),
+            args: vec!["-F".to_string(), "two words".to_string()],
+        };
+        let json = serde_json::to_value(&command).unwrap();
+        assert_eq!(json["type"], "exec");
+        assert_eq!(json["args"][1], "two words");
+
+        let back: Command = serde_json::from_value(json).unwrap();
+        match back {
+            Command::Exec { program, args } => {
+                assert_eq!(program, "grep");
+                assert_eq!(args, ["-F", "two words"]);
+            }
+            other => panic!("unexpected variant: {other:?}"),
+        }
+
+        // `args` may be omitted entirely for argument-less programs.
+        let bare: Command =
+            serde_json::from_value(serde_json::json!({ "type": "exec", "program": "true" }))
+                .unwrap();
+        match bare {
+            Command::Exec { args, .. } => assert!(args.is_empty()),
+            other => panic!("unexpected variant: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn same_major_is_accepted_across_minors() {
+        assert!(request_with_version(PROTOCOL_VERSION).validate_version().is_ok());
+        assert!(request_with_version("1.7").validate_version().is_ok());
+    }
+
+    #[test]
+    fn different_or_malformed_major_is_rejected() {
+        for version in ["2.0", "0.9", "latest", ""] {
+            let err = request_with_version(version).validate_version().unwrap_err();
+            assert_eq!(err.code, "UNSUPPORTED_VERSION", "version {version:?}");
+            assert!(err.message.contains(PROTOCOL_VERSION));
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Typed errors for PTY session operations.
+//!
+//! As with [`SshError`](crate::ssh::SshError), public APIs stay on
+//! `anyhow::Result`, but the failure carries a [`PtyError`] in its
+//! chain so callers can branch on the kind — and, via
+//! [`user_message`](PtyError::user_message), show people plain English
+//! instead of a raw context chain.
+
+use thiserror::Error;
+
+/// What went wrong with a PTY session, as a matchable value.
+#[derive(Debug, Error)]
+pub enum PtyError {
+    #[error("no session {id}")]
+    SessionNotFound { id: String },
+    #[error("writing to the terminal failed")]
+    WriteFailed,
+    #[error("write to session {id} timed out")]
+    WriteTimeout { id: String },
+    #[error("reading terminal output failed")]
+    ReadFailed,
+    #[error("spawning the shell failed")]
+    SpawnFailed,
+    #[error("resizing the terminal failed")]
+    ResizeFailed,
+}
+
+impl PtyError {
+    /// Convenience for the commonest failure: an operation against an
+    /// id that isn't (or is no longer) a live session.
+    pub(crate) fn session_not_found(id: &str) -> anyhow::Error {
+        anyhow::Error::new(PtyError::SessionNotFound { id: id.to_string() })
+    }
+
+    /// The typed error underlying `err`, if the failure originated in
+    /// the PTY layer.
+    pub fn classify(err: &anyhow::Error) -> Option<&PtyError> {
+        // anyhow's downcast reaches through context layers to the
+        // typed value, wherever it sits in the chain.
+        err.downcast_ref()
+    }
+
+    /// A sentence fit for an end user, with no internals leaking out.
+    pub fn user_message(&self) -> String {
+        match self {
+            PtyError::SessionNotFound { id } => {
+                format!("Terminal session {id} no longer exists; open a new one.")
+            }
+            PtyError::WriteFailed => {
+                "Your input could not reach the terminal; the session may have ended.".into()
+            }
+            PtyError::WriteTimeout { .. } => {
+                "The terminal is not accepting input right now; the program in it may be \
+                 stopped or overwhelmed."
+                    .into()
+            }
+            PtyError::ReadFailed => {
+                "Terminal output could not be read; the session may have ended.".into()
+            }
+            PtyError::SpawnFailed => {
+                "A shell could not be started on the server; try again or contact whoever runs it."
+                    .into()
+            }
+            PtyError::ResizeFailed => {
+                "The terminal could not be resized; the session may have ended.".into()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_finds_the_typed_cause_through_context_layers() {
+        let err = anyhow::anyhow!("ENXIO").context(PtyError::WriteFailed);
+        assert!(matches!(
+            PtyError::classify(&err),
+            Some(PtyError::WriteFailed)
+        ));
+        assert!(PtyError::classify(&anyhow::anyhow!("plain")).is_none());
+    }
+
+    #[test]
+    fn user_messages_read_as_plain_english() {
+        let message = PtyError::SessionNotFound { id: "abc".into() }.user_message();
+        assert!(message.contains("abc"));
+        // No Rust/debug artefacts in anything a person will read.
+        for variant in [
+            PtyError::WriteFailed,
+            PtyError::WriteTimeout { id: "abc".into() },
+            PtyError::ReadFailed,
+            PtyError::SpawnFailed,
+            PtyError::ResizeFailed,
+        ] {
+            let message = variant.user_message();
+            assert!(message.ends_with('.'), "{message}");
+            assert!(!message.contains("anyhow"), "{message}");
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Shared retry loop with jittered exponential backoff.
+//!
+//! The SSH connect path, the executor's per-request retries, and the
+//! backend's `/api/execute` all need the same shape of loop; keeping
+//! one here avoids three slightly different copies drifting apart.
+
+use std::time::{Duration, Instant};
+
+/// How a retried operation backs off between attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffPolicy {
+    /// Attempts in total, including the first; clamped to at least 1.
+    pub max_attempts: u32,
+    /// Sleep before the first retry; doubles per subsequent retry.
+    pub initial_backoff: Duration,
+    /// Ceiling on any single sleep.
+    pub max_backoff: Duration,
+    /// Total wall-clock budget: once an upcoming sleep would cross it,
+    /// the loop gives up with the last error. `None` is unbounded.
+    pub deadline: Option<Duration>,
+    /// Fraction (0.0..=1.0) each sleep is randomly stretched or
+    /// shrunk by, so synchronized callers don't retry in lockstep.
+    pub jitter: f64,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            deadline: None,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// The sleep before retry `attempt` (1-based), before jitter:
+    /// the initial backoff doubled per prior retry, capped at
+    /// [`max_backoff`](Self::max_backoff).
+    fn base_backoff(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(u32::BITS - 1);
+        self.initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+/// A [`RetryPolicy`](crate::protocol::RetryPolicy) from the protocol
+/// maps onto the loop without jitter or deadline, preserving its
+/// documented plain-doubling behaviour.
+impl From<crate::protocol::RetryPolicy> for BackoffPolicy {
+    fn from(policy: crate::protocol::RetryPolicy) -> Self {
+        Self {
+            max_attempts: policy.max_attempts,
+            initial_backoff: Duration::from_millis(policy.backoff_ms),
+            max_backoff: Duration::MAX,
+            deadline: None,
+            jitter: 0.0,
+        }
+    }
+}
+
+/// Stretch or shrink `base` by up to `±jitter`.
+fn jittered(base: Duration, jitter: f64) -> Duration {
+    if jitter <= 0.0 {
+        return base;
+    }
+    let jitter = jitter.min(1.0);
+    // A cheap uniform sample; retry pacing doesn't warrant an RNG
+    // dependency.
+    let sample = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| f64::from(d.subsec_nanos()) / 1e9)
+        .unwrap_or(0.5);
+    base.mul_f64(1.0 - jitter + 2.0 * jitter * sample)
+}
+
+/// Run `op` until it succeeds, `classify` deems its error permanent,
+/// attempts run out, or the next sleep would cross the deadline.
+/// Returns the final outcome and the number of attempts made.
+pub async fn with_backoff<T, E, C, F, Fut>(
+    policy: BackoffPolicy,
+    mut classify: C,
+    mut op: F,
+) -> (Result<T, E>, u32)
+where
+    C: FnMut(&E) -> bool,
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+{
+    let started = Instant::now();
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match op().await {
+            Ok(value) => return (Ok(value), attempts),
+            Err(e) => {
+                if attempts >= max_attempts || !classify(&e) {
+                    return (Err(e), attempts);
+                }
+                let sleep = jittered(policy.base_backoff(attempts), policy.jitter);
+                if let Some(deadline) = policy.deadline {
+                    if started.elapsed() + sleep >= deadline {
+                        return (Err(e), attempts);
+                    }
+                }
+                tokio::time::sleep(sleep).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_per_retry_and_caps() {
+        let policy = BackoffPolicy {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            ..BackoffPolicy::default()
+        };
+        let mut previous = Duration::ZERO;
+        for attempt in 1..64 {
+            let base = policy.base_backoff(attempt);
+            let expected = Duration::from_millis(10)
+                .saturating_mul(1 << (attempt - 1).min(31))
+                .min(Duration::from_secs(1));
+            assert_eq!(base, expected, "attempt {attempt}");
+            assert!(base >= previous, "backoff shrank at attempt {attempt}");
+            assert!(base <= policy.max_backoff);
+            previous = base;
+
+            // Jitter stays inside its advertised band.
+            for _ in 0..50 {
+                let j = jittered(base, 0.3);
+                assert!(j >= base.mul_f64(0.69) && j <= base.mul_f64(1.31), "{j:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn deadline_caps_total_retry_time() {
+        let policy = BackoffPolicy {
+            max_attempts: 1_000,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(20),
+            deadline: Some(Duration::from_millis(100)),
+            jitter: 0.0,
+        };
+        let started = Instant::now();
+        let (outcome, attempts) =
+            with_backoff(policy, |_| true, || async { Err::<(), _>("down") }).await;
+        assert_eq!(outcome, Err("down"));
+        assert!(attempts < 1_000, "deadline never fired ({attempts} attempts)");
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "ran past the deadline: {:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_stop_the_loop_immediately() {
+        let policy = BackoffPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(1),
+            ..BackoffPolicy::default()
+        };
+        let (outcome, attempts) =
+            with_backoff(policy, |e: &&str| *e != "permanent", || async {
+                Err::<(), _>("permanent")
+            })
+            .await;
+        assert_eq!(outcome, Err("permanent"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn transient_errors_retry_until_success() {
+        let policy = BackoffPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(1),
+            ..BackoffPolicy::default()
+        };
+        let mut calls = 0u32;
+        let (outcome, attempts) = with_backoff(policy, |_| true, || {
+            calls += 1;
+            let fail = calls < 3;
+            async move {
+                if fail {
+                    Err("transient")
+                } else {
+                    Ok("recovered")
+                }
+            }
+        })
+        .await;
+        assert_eq!(outcome, Ok("recovered"));
+        assert_eq!(attempts, 3);
+    }
+}
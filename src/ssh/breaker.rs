@@ -0,0 +1,362 @@
+//! Per-host circuit breaking for remote execution.
+//!
+//! Repeated failures against one host open its breaker; while open, no
+//! commands are attempted. After a cooldown the breaker goes half-open
+//! and lets a single probe through to decide whether to close again.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::HostKey;
+use crate::events::{Event, EventBus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    /// Consecutive failures before the breaker opens.
+    pub failure_threshold: u32,
+    /// How long an open breaker blocks before going half-open.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+struct HostBreaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl Default for HostBreaker {
+    fn default() -> Self {
+        Self {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+        }
+    }
+}
+
+/// Tracks breaker state for every host seen so far.
+#[derive(Default)]
+pub struct CircuitBreaker {
+    hosts: Mutex<HashMap<HostKey, HostBreaker>>,
+    config: BreakerConfig,
+    /// Host-pattern overrides, consulted in order; first match wins.
+    overrides: Vec<(String, BreakerConfig)>,
+    events: Option<Arc<EventBus>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            hosts: Mutex::new(HashMap::new()),
+            config,
+            overrides: Vec::new(),
+            events: None,
+        }
+    }
+
+    /// Publish open/close transitions to `events`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Per-host-pattern config overrides: a host whose name matches a
+    /// pattern (`*` and `?` glob against [`HostKey::host`]) uses that
+    /// pattern's config instead of the default, so flaky hosts can get
+    /// a higher threshold and critical ones can trip fast. Earlier
+    /// patterns win.
+    pub fn with_overrides(mut self, overrides: Vec<(String, BreakerConfig)>) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// The config governing `key`: the first matching override, the
+    /// default otherwise.
+    fn config_for(&self, key: &HostKey) -> BreakerConfig {
+        self.overrides
+            .iter()
+            .find(|(pattern, _)| glob_match(pattern, &key.host))
+            .map(|(_, config)| *config)
+            .unwrap_or(self.config)
+    }
+
+    fn publish(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.publish(event);
+        }
+    }
+
+    /// Whether a command against `key` may proceed right now.
+    ///
+    /// An open breaker past its cooldown flips to half-open and admits
+    /// the caller as the probe.
+    pub fn allow(&self, key: &HostKey) -> bool {
+        let mut hosts = self.hosts.lock().expect("breaker state poisoned");
+        let breaker = hosts.entry(key.clone()).or_default();
+        match breaker.state {
+            BreakerState::Closed | BreakerState::HalfOpen => true,
+            BreakerState::Open => {
+                let cooldown = self.config_for(key).cooldown;
+                let cooled = breaker
+                    .opened_at
+                    .is_some_and(|at| at.elapsed() >= cooldown);
+                if cooled {
+                    breaker.state = BreakerState::HalfOpen;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Whether a command against `key` *would* be admitted right now,
+    /// without perturbing anything.
+    ///
+    /// Unlike [`allow`](Self::allow) this never transitions state and
+    /// never becomes the half-open probe, so dashboards and health
+    /// checks can poll it freely: an open breaker past its cooldown
+    /// reports `true` (the next real caller would get through) but
+    /// stays open until that caller arrives.
+    pub fn allows_request(&self, key: &HostKey) -> bool {
+        let hosts = self.hosts.lock().expect("breaker state poisoned");
+        let Some(breaker) = hosts.get(key) else {
+            return true;
+        };
+        match breaker.state {
+            BreakerState::Closed | BreakerState::HalfOpen => true,
+            BreakerState::Open => {
+                let cooldown = self.config_for(key).cooldown;
+                breaker.opened_at.is_some_and(|at| at.elapsed() >= cooldown)
+            }
+        }
+    }
+
+    pub fn record_success(&self, key: &HostKey) {
+        let recovered = {
+            let mut hosts = self.hosts.lock().expect("breaker state poisoned");
+            let breaker = hosts.entry(key.clone()).or_default();
+            let recovered = breaker.state != BreakerState::Closed;
+            breaker.state = BreakerState::Closed;
+            breaker.consecutive_failures = 0;
+            breaker.opened_at = None;
+            recovered
+        };
+        if recovered {
+            self.publish(Event::BreakerClosed {
+                host: key.to_string(),
+            });
+        }
+    }
+
+    pub fn record_failure(&self, key: &HostKey) {
+        let failure_threshold = self.config_for(key).failure_threshold;
+        let opened = {
+            let mut hosts = self.hosts.lock().expect("breaker state poisoned");
+            let breaker = hosts.entry(key.clone()).or_default();
+            breaker.consecutive_failures += 1;
+            // A failed half-open probe re-opens immediately.
+            let trip = breaker.state == BreakerState::HalfOpen
+                || breaker.consecutive_failures >= failure_threshold;
+            if trip {
+                let was_open = breaker.state == BreakerState::Open;
+                breaker.state = BreakerState::Open;
+                breaker.opened_at = Some(Instant::now());
+                !was_open
+            } else {
+                false
+            }
+        };
+        if opened {
+            self.publish(Event::BreakerOpened {
+                host: key.to_string(),
+            });
+        }
+    }
+
+    pub fn state(&self, key: &HostKey) -> BreakerState {
+        self.hosts
+            .lock()
+            .expect("breaker state poisoned")
+            .get(key)
+            .map(|b| b.state)
+            .unwrap_or(BreakerState::Closed)
+    }
+
+    /// Snapshot of every tracked host's state, for metrics.
+    pub fn states(&self) -> Vec<(HostKey, BreakerState)> {
+        self.hosts
+            .lock()
+            .expect("breaker state poisoned")
+            .iter()
+            .map(|(k, b)| (k.clone(), b.state))
+            .collect()
+    }
+}
+
+/// Match `text` against a glob `pattern` where `*` matches any run of
+/// characters and `?` matches exactly one. Host patterns don't need
+/// character classes, so this stays dependency-free.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    fn inner(pattern: &[u8], text: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some((b'*', rest)) => {
+                (0..=text.len()).any(|skip| inner(rest, &text[skip..]))
+            }
+            Some((b'?', rest)) => !text.is_empty() && inner(rest, &text[1..]),
+            Some((c, rest)) => text.split_first().is_some_and(|(t, text)| t == c && inner(rest, text)),
+        }
+    }
+    inner(pattern.as_bytes(), text.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> HostKey {
+        HostKey::new("db1.example", 22, "ops")
+    }
+
+    #[test]
+    fn opens_after_threshold_and_recovers_via_half_open() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 3,
+            cooldown: Duration::ZERO,
+        });
+        let key = key();
+
+        for _ in 0..2 {
+            breaker.record_failure(&key);
+        }
+        assert_eq!(breaker.state(&key), BreakerState::Closed);
+        breaker.record_failure(&key);
+        assert_eq!(breaker.state(&key), BreakerState::Open);
+
+        // Zero cooldown: the next allow() is the half-open probe.
+        assert!(breaker.allow(&key));
+        assert_eq!(breaker.state(&key), BreakerState::HalfOpen);
+        breaker.record_success(&key);
+        assert_eq!(breaker.state(&key), BreakerState::Closed);
+    }
+
+    #[test]
+    fn failed_probe_reopens() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::ZERO,
+        });
+        let key = key();
+        breaker.record_failure(&key);
+        assert!(breaker.allow(&key));
+        breaker.record_failure(&key);
+        assert_eq!(breaker.state(&key), BreakerState::Open);
+    }
+
+    #[test]
+    fn glob_patterns_match_host_names() {
+        assert!(glob_match("db*.example", "db1.example"));
+        assert!(glob_match("db*.example", "db-replica.example"));
+        assert!(glob_match("db?.example", "db1.example"));
+        assert!(glob_match("*", "anything.at.all"));
+        assert!(!glob_match("db?.example", "db12.example"));
+        assert!(!glob_match("db*.example", "web1.example"));
+        assert!(!glob_match("db1.example", "db1.example.org"));
+    }
+
+    #[test]
+    fn overrides_pick_the_first_matching_pattern() {
+        let breaker = CircuitBreaker::new(BreakerConfig::default()).with_overrides(vec![
+            (
+                "db*.example".to_string(),
+                BreakerConfig {
+                    failure_threshold: 1,
+                    cooldown: Duration::from_secs(3600),
+                },
+            ),
+            (
+                "*".to_string(),
+                BreakerConfig {
+                    failure_threshold: 2,
+                    cooldown: Duration::ZERO,
+                },
+            ),
+        ]);
+
+        // db1 matches the first pattern: trips on one failure.
+        let db = key();
+        breaker.record_failure(&db);
+        assert_eq!(breaker.state(&db), BreakerState::Open);
+
+        // web1 falls through to the catch-all, not the default of 5.
+        let web = HostKey::new("web1.example", 22, "ops");
+        breaker.record_failure(&web);
+        assert_eq!(breaker.state(&web), BreakerState::Closed);
+        breaker.record_failure(&web);
+        assert_eq!(breaker.state(&web), BreakerState::Open);
+    }
+
+    #[test]
+    fn allows_request_reports_without_transitioning() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::ZERO,
+        });
+        let key = key();
+
+        // An unseen host is trivially admitted.
+        assert!(breaker.allows_request(&key));
+
+        breaker.record_failure(&key);
+        // Cooled down: a call would go through, but polling must not
+        // flip the breaker half-open or consume the probe.
+        for _ in 0..3 {
+            assert!(breaker.allows_request(&key));
+            assert_eq!(breaker.state(&key), BreakerState::Open);
+        }
+        // The real probe is still available to the next caller.
+        assert!(breaker.allow(&key));
+        assert_eq!(breaker.state(&key), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn allows_request_respects_the_cooldown() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(3600),
+        });
+        let key = key();
+        breaker.record_failure(&key);
+        assert!(!breaker.allows_request(&key));
+        assert_eq!(breaker.state(&key), BreakerState::Open);
+    }
+
+    #[test]
+    fn open_breaker_blocks_within_cooldown() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(3600),
+        });
+        let key = key();
+        breaker.record_failure(&key);
+        assert!(!breaker.allow(&key));
+    }
+}
@@ -0,0 +1,144 @@
+//! Result types for bulk (`exec_many`) execution.
+
+use super::{CommandOutput, HostKey, SshError};
+
+/// Rough classification of a bulk failure, used to decide whether a
+/// target is worth retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureCategory {
+    /// Timeouts and connection-level errors; a retry may well succeed.
+    Transient,
+    /// Authentication rejections and the like; retrying as-is will not help.
+    Permanent,
+}
+
+/// Why one target of a bulk run failed.
+#[derive(Debug, Clone)]
+pub struct BulkFailure {
+    pub category: FailureCategory,
+    pub message: String,
+}
+
+impl BulkFailure {
+    pub(super) fn from_error(error: &anyhow::Error) -> Self {
+        let message = format!("{error:#}");
+        let category = match SshError::classify(error) {
+            Some(e) if e.is_transient() => FailureCategory::Transient,
+            Some(_) => FailureCategory::Permanent,
+            // Failures raised outside the SSH layer fall back to the
+            // message: an explicit rejection is not worth retrying,
+            // connection-level problems are.
+            None if message.contains("rejected") => FailureCategory::Permanent,
+            None => FailureCategory::Transient,
+        };
+        Self { category, message }
+    }
+}
+
+/// Outcome of one `(host, command)` pair within a bulk run.
+#[derive(Debug, Clone)]
+pub struct BulkEntry {
+    pub target: HostKey,
+    pub command: String,
+    pub outcome: Result<CommandOutput, BulkFailure>,
+}
+
+/// Per-target outcomes of an `exec_many` run, in submission order.
+#[derive(Debug, Clone, Default)]
+pub struct BulkResult {
+    pub entries: Vec<BulkEntry>,
+}
+
+impl BulkResult {
+    pub fn succeeded(&self) -> usize {
+        self.entries.iter().filter(|e| e.outcome.is_ok()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.entries.len() - self.succeeded()
+    }
+
+    /// The `(host, command)` pairs that failed, in a shape that can be
+    /// passed straight back to `exec_many` for a targeted retry.
+    pub fn failed_targets(&self) -> Vec<(HostKey, String)> {
+        self.entries
+            .iter()
+            .filter(|e| e.outcome.is_err())
+            .map(|e| (e.target.clone(), e.command.clone()))
+            .collect()
+    }
+
+    /// Like [`failed_targets`](Self::failed_targets), but restricted to
+    /// one [`FailureCategory`] — typically `Transient`, to retry only
+    /// what stands a chance of succeeding.
+    pub fn failed_targets_in(&self, category: FailureCategory) -> Vec<(HostKey, String)> {
+        self.entries
+            .iter()
+            .filter(|e| matches!(&e.outcome, Err(f) if f.category == category))
+            .map(|e| (e.target.clone(), e.command.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok_entry(host: &str, command: &str) -> BulkEntry {
+        BulkEntry {
+            target: HostKey::new(host, 22, "ops"),
+            command: command.to_string(),
+            outcome: Ok(CommandOutput::default()),
+        }
+    }
+
+    fn failed_entry(host: &str, command: &str, category: FailureCategory) -> BulkEntry {
+        BulkEntry {
+            target: HostKey::new(host, 22, "ops"),
+            command: command.to_string(),
+            outcome: Err(BulkFailure {
+                category,
+                message: "boom".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn failed_targets_returns_exactly_the_failures() {
+        let result = BulkResult {
+            entries: vec![
+                ok_entry("a.example", "uptime"),
+                failed_entry("b.example", "uptime", FailureCategory::Transient),
+                ok_entry("c.example", "uptime"),
+                failed_entry("d.example", "df -h", FailureCategory::Permanent),
+            ],
+        };
+
+        let retry = result.failed_targets();
+        assert_eq!(
+            retry,
+            vec![
+                (HostKey::new("b.example", 22, "ops"), "uptime".to_string()),
+                (HostKey::new("d.example", 22, "ops"), "df -h".to_string()),
+            ]
+        );
+        assert_eq!(result.succeeded(), 2);
+        assert_eq!(result.failed(), 2);
+    }
+
+    #[test]
+    fn failed_targets_in_filters_by_category() {
+        let result = BulkResult {
+            entries: vec![
+                failed_entry("b.example", "uptime", FailureCategory::Transient),
+                failed_entry("d.example", "df -h", FailureCategory::Permanent),
+            ],
+        };
+
+        let transient = result.failed_targets_in(FailureCategory::Transient);
+        assert_eq!(
+            transient,
+            vec![(HostKey::new("b.example", 22, "ops"), "uptime".to_string())]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! Fleet connection policy: per-host-pattern defaults for user, port,
+//! and credentials.
+//!
+//! Different corners of a fleet log in differently — one subnet as
+//! `admin` on 22, another as `svc` on 2222. Rather than spelling the
+//! full `user@host:port` plus credentials on every request, callers
+//! register the policy once and parse bare hostnames through
+//! [`HostKey::parse_with_defaults`](super::HostKey::parse_with_defaults);
+//! anything explicit in the connection string still wins.
+
+use super::{glob_match, AuthMethod};
+
+/// Connection settings a pattern imposes on matching hosts; any field
+/// left `None` defers to the next layer (the current OS user, port
+/// 22, the caller's own auth).
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionDefaults {
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    /// Password for hosts whose callers bring no auth of their own,
+    /// surfaced via [`HostDefaults::auth_for`].
+    pub password: Option<String>,
+}
+
+/// Defaults keyed by host glob patterns (`*`, `?`), consulted in
+/// insertion order; the first matching pattern wins, mirroring the
+/// breaker's override semantics.
+#[derive(Debug, Clone, Default)]
+pub struct HostDefaults {
+    entries: Vec<(String, ConnectionDefaults)>,
+}
+
+impl HostDefaults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add defaults for hosts matching `pattern`. Earlier entries win
+    /// over later ones, so list specific patterns before catch-alls.
+    pub fn with_entry(
+        mut self,
+        pattern: impl Into<String>,
+        defaults: ConnectionDefaults,
+    ) -> Self {
+        self.entries.push((pattern.into(), defaults));
+        self
+    }
+
+    /// The first entry whose pattern matches `host`.
+    pub(super) fn lookup(&self, host: &str) -> Option<&ConnectionDefaults> {
+        self.entries
+            .iter()
+            .find(|(pattern, _)| glob_match(pattern, host))
+            .map(|(_, defaults)| defaults)
+    }
+
+    /// The configured auth for `host`, for callers that did not bring
+    /// their own. `None` when no matching pattern carries credentials.
+    pub fn auth_for(&self, host: &str) -> Option<AuthMethod> {
+        self.lookup(host)
+            .and_then(|defaults| defaults.password.clone())
+            .map(AuthMethod::Password)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::HostKey;
+
+    fn fleet() -> HostDefaults {
+        HostDefaults::new()
+            .with_entry(
+                "db*.example",
+                ConnectionDefaults {
+                    user: Some("dba".to_string()),
+                    port: Some(5522),
+                    password: Some("db-secret".to_string()),
+                },
+            )
+            .with_entry(
+                "*.example",
+                ConnectionDefaults {
+                    user: Some("ops".to_string()),
+                    port: None,
+                    password: None,
+                },
+            )
+    }
+
+    #[test]
+    fn omitted_parts_come_from_the_first_matching_pattern() {
+        let key = HostKey::parse_with_defaults("db1.example", &fleet()).unwrap();
+        assert_eq!(key, HostKey::new("db1.example", 5522, "dba"));
+
+        // The catch-all supplies a user but no port.
+        let key = HostKey::parse_with_defaults("web1.example", &fleet()).unwrap();
+        assert_eq!(key, HostKey::new("web1.example", 22, "ops"));
+    }
+
+    #[test]
+    fn explicit_user_and_port_beat_the_configured_defaults() {
+        let key = HostKey::parse_with_defaults("root@db1.example:2200", &fleet()).unwrap();
+        assert_eq!(key, HostKey::new("db1.example", 2200, "root"));
+
+        // Partially explicit: the string's port, the pattern's user.
+        let key = HostKey::parse_with_defaults("db1.example:2200", &fleet()).unwrap();
+        assert_eq!(key, HostKey::new("db1.example", 2200, "dba"));
+    }
+
+    #[test]
+    fn unmatched_hosts_keep_the_usual_fallbacks() {
+        let key = HostKey::parse_with_defaults("admin@other.net", &fleet()).unwrap();
+        assert_eq!(key, HostKey::new("other.net", 22, "admin"));
+    }
+
+    #[test]
+    fn auth_for_yields_credentials_only_where_configured() {
+        match fleet().auth_for("db1.example") {
+            Some(AuthMethod::Password(password)) => assert_eq!(password, "db-secret"),
+            None => panic!("configured credentials not found"),
+        }
+        assert!(fleet().auth_for("web1.example").is_none());
+        assert!(fleet().auth_for("other.net").is_none());
+    }
+}
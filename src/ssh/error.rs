@@ -0,0 +1,89 @@
+//! Typed errors for the SSH layer.
+//!
+//! Public APIs stay on `anyhow::Result` for context-rich messages, but
+//! the failure's root cause is an [`SshError`] wherever the layer can
+//! tell what went wrong. Callers that need to branch on the kind of
+//! failure use [`SshError::classify`] instead of string-matching.
+
+use std::io;
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// Why a connection string could not be parsed into a
+/// [`HostKey`](super::HostKey); each variant carries the offending
+/// input so the message is self-diagnosing.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HostKeyParseError {
+    #[error("connection string {0:?} has no host")]
+    MissingHost(String),
+    #[error("connection string {input:?} has an invalid port {port:?}")]
+    InvalidPort { input: String, port: String },
+    #[error("connection string {0:?} omits the user and the current OS user is unknown")]
+    NoUser(String),
+}
+
+/// What went wrong in the SSH layer, as a matchable value.
+#[derive(Debug, Error)]
+pub enum SshError {
+    #[error("connect timed out after {0:?}")]
+    ConnectTimeout(Duration),
+    #[error("authentication rejected")]
+    AuthFailed,
+    #[error("server host key was not accepted")]
+    HostKeyMismatch,
+    #[error("no pooled connection freed in time")]
+    PoolExhausted,
+    #[error("command timed out after {0:?}")]
+    CommandTimeout(Duration),
+    #[error("command exited with status {code}")]
+    CommandFailed { code: u32 },
+    #[error("transport error")]
+    Transport(#[from] io::Error),
+}
+
+impl SshError {
+    /// The typed error underlying `err`, if the failure originated in
+    /// the SSH layer.
+    pub fn classify(err: &anyhow::Error) -> Option<&SshError> {
+        // anyhow's downcast reaches through context layers to the
+        // typed value, wherever it sits in the chain.
+        err.downcast_ref()
+    }
+
+    /// Whether retrying the same operation stands a chance: timeouts
+    /// and transport hiccups do, rejections and remote failures don't.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SshError::ConnectTimeout(_)
+            | SshError::CommandTimeout(_)
+            | SshError::PoolExhausted
+            | SshError::Transport(_) => true,
+            SshError::AuthFailed
+            | SshError::HostKeyMismatch
+            | SshError::CommandFailed { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_finds_the_typed_cause_through_context_layers() {
+        let err = anyhow::Error::new(SshError::AuthFailed)
+            .context("authentication with ops@db1:22 failed")
+            .context("bulk run aborted");
+        assert!(matches!(SshError::classify(&err), Some(SshError::AuthFailed)));
+        assert!(SshError::classify(&anyhow::anyhow!("plain string")).is_none());
+    }
+
+    #[test]
+    fn transience_splits_retryable_from_permanent() {
+        assert!(SshError::CommandTimeout(Duration::from_secs(30)).is_transient());
+        assert!(SshError::PoolExhausted.is_transient());
+        assert!(!SshError::AuthFailed.is_transient());
+        assert!(!SshError::CommandFailed { code: 1 }.is_transient());
+    }
+}
@@ -0,0 +1,2665 @@
+//! Pooled SSH connections and remote command execution.
+//!
+//! Connections are keyed by [`HostKey`] and reused across commands. A
+//! single [`SSHPool`] is shared by everything in the process that talks
+//! to remote hosts.
+
+mod breaker;
+mod bulk;
+mod defaults;
+mod error;
+#[cfg(test)]
+mod testing;
+mod transfer;
+
+pub use breaker::{BreakerConfig, BreakerState, CircuitBreaker};
+pub(crate) use breaker::glob_match;
+pub use bulk::{BulkEntry, BulkFailure, BulkResult, FailureCategory};
+pub use defaults::{ConnectionDefaults, HostDefaults};
+pub use error::{HostKeyParseError, SshError};
+pub use transfer::{TransferProgress, TransferSummary};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::StreamExt;
+use russh::client;
+use russh::keys::PublicKeyOrCertificate;
+use russh::ChannelMsg;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::events::{Event, EventBus};
+use crate::stream::StreamingOutputHandler;
+
+/// Timeout applied to remote commands that don't specify their own.
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default cap on captured output per command. Output past the cap is
+/// dropped (the head is kept) and the result flagged as truncated, so
+/// a runaway command cannot OOM the process.
+pub const DEFAULT_MAX_OUTPUT_BYTES: usize = 10 * 1024 * 1024;
+
+/// Quote `arg` for safe embedding in a POSIX shell command line.
+///
+/// Wraps the string in single quotes, rendering embedded single quotes
+/// as `'\''`. Inside single quotes the shell expands nothing, so
+/// spaces, `$()`, backticks, and globs all pass through literally.
+/// Anything interpolating user input into a command string sent over
+/// SSH must go through this, or a crafted hostname or argument becomes
+/// command injection on the remote side.
+pub fn shell_quote(arg: &str) -> String {
+    // Bare words stay readable in logs and echoed commands.
+    let safe_bare = !arg.is_empty()
+        && arg.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(b, b'_' | b'-' | b'.' | b'/' | b':' | b'@' | b'%' | b'+' | b'=' | b',')
+        });
+    if safe_bare {
+        return arg.to_string();
+    }
+    let mut out = String::with_capacity(arg.len() + 2);
+    out.push('\'');
+    for c in arg.chars() {
+        if c == '\'' {
+            out.push_str("'\\''");
+        } else {
+            out.push(c);
+        }
+    }
+    out.push('\'');
+    out
+}
+
+/// Identifies a remote endpoint for connection pooling.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HostKey {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+}
+
+impl HostKey {
+    pub fn new(host: impl Into<String>, port: u16, username: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            username: username.into(),
+        }
+    }
+
+    /// Parse a connection string: `host`, `host:port`, `user@host`,
+    /// `user@host:port`, or any of those behind an `ssh://` scheme.
+    /// The port defaults to 22 and the user to the current OS user.
+    /// IPv6 addresses take the usual brackets (`[::1]:2222`); a bare
+    /// colon-riddled host is read as IPv6 with the default port.
+    ///
+    /// Failures carry a [`HostKeyParseError`] root cause naming the
+    /// offending input.
+    pub fn parse(s: &str) -> Result<Self> {
+        let (host, port, username) = Self::parse_parts(s)?;
+        let username = match username {
+            Some(user) => user,
+            None => Self::current_username(s)?,
+        };
+        Ok(Self::new(host, port.unwrap_or(22), username))
+    }
+
+    /// Like [`parse`](Self::parse), but user and port omitted from the
+    /// string come from the first pattern in `defaults` matching the
+    /// host, before the usual fallbacks apply. Anything explicit in
+    /// the string always wins.
+    pub fn parse_with_defaults(s: &str, defaults: &HostDefaults) -> Result<Self> {
+        let (host, port, username) = Self::parse_parts(s)?;
+        let entry = defaults.lookup(&host);
+        let username = match username.or_else(|| entry.and_then(|d| d.user.clone())) {
+            Some(user) => user,
+            None => Self::current_username(s)?,
+        };
+        let port = port.or_else(|| entry.and_then(|d| d.port)).unwrap_or(22);
+        Ok(Self::new(host, port, username))
+    }
+
+    /// The syntactic pieces of a connection string: host plus whatever
+    /// port and user it spells out explicitly.
+    fn parse_parts(s: &str) -> Result<(String, Option<u16>, Option<String>)> {
+        let missing = || anyhow::Error::new(HostKeyParseError::MissingHost(s.to_string()));
+        let input = s.trim();
+        let rest = input.strip_prefix("ssh://").unwrap_or(input);
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        if rest.is_empty() || rest.contains('/') || rest.contains(char::is_whitespace) {
+            return Err(missing());
+        }
+
+        let (username, hostport) = match rest.split_once('@') {
+            Some((user, hostport)) if !user.is_empty() => (Some(user.to_string()), hostport),
+            Some((_, hostport)) => (None, hostport),
+            None => (None, rest),
+        };
+
+        let bad_port = |port: &str| {
+            anyhow::Error::new(HostKeyParseError::InvalidPort {
+                input: s.to_string(),
+                port: port.to_string(),
+            })
+        };
+        let (host, port) = if let Some(bracketed) = hostport.strip_prefix('[') {
+            match bracketed.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, after)) => {
+                    let port = after.strip_prefix(':').ok_or_else(|| bad_port(after))?;
+                    (host, Some(port.parse().map_err(|_| bad_port(port))?))
+                }
+                None => return Err(missing()),
+            }
+        } else if hostport.matches(':').count() > 1 {
+            // Colons but no brackets: a bare IPv6 address.
+            (hostport, None)
+        } else {
+            match hostport.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port.parse().map_err(|_| bad_port(port))?)),
+                None => (hostport, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(missing());
+        }
+        Ok((host.to_string(), port, username))
+    }
+
+    /// The user running this process, for connection strings that omit
+    /// one.
+    fn current_username(input: &str) -> Result<String> {
+        ["USER", "LOGNAME", "USERNAME"]
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()))
+            .ok_or_else(|| anyhow::Error::new(HostKeyParseError::NoUser(input.to_string())))
+    }
+}
+
+impl fmt::Display for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.username, self.host, self.port)
+    }
+}
+
+/// How to authenticate when a pooled connection has to be (re)established.
+#[derive(Clone)]
+pub enum AuthMethod {
+    Password(String),
+}
+
+/// Captured output of a completed remote command.
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_status: u32,
+    /// Whether output was cut off at the capture cap (see
+    /// [`DEFAULT_MAX_OUTPUT_BYTES`]).
+    pub truncated: bool,
+}
+
+impl CommandOutput {
+    pub fn stdout_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stdout)
+    }
+
+    pub fn stderr_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.stderr)
+    }
+
+    /// Decode stdout to UTF-8 from `encoding`, for hosts whose locale
+    /// isn't UTF-8 (Shift-JIS, Latin-1).
+    pub fn stdout_in(&self, encoding: &'static encoding_rs::Encoding) -> String {
+        let (text, _, _) = encoding.decode(&self.stdout);
+        text.into_owned()
+    }
+
+    /// Like [`stdout_in`](Self::stdout_in), for stderr.
+    pub fn stderr_in(&self, encoding: &'static encoding_rs::Encoding) -> String {
+        let (text, _, _) = encoding.decode(&self.stderr);
+        text.into_owned()
+    }
+
+    pub fn success(&self) -> bool {
+        self.exit_status == 0
+    }
+}
+
+struct ClientHandler;
+
+impl client::Handler for ClientHandler {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        &mut self,
+        _server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        // Host-key verification is delegated to the deployment (known
+        // hosts are provisioned out of band).
+        Ok(true)
+    }
+}
+
+/// How commands reach the host: an in-process russh session, or the
+/// system `ssh` client multiplexed over a persistent ControlMaster
+/// socket.
+enum Transport {
+    Direct(client::Handle<ClientHandler>),
+    /// Expanded `ControlPath` of an OS-level `ssh -M` master; every
+    /// command shells out through it.
+    ControlMaster { socket: std::path::PathBuf },
+}
+
+/// Expand the OpenSSH `ControlPath` tokens this layer can know:
+/// `%h` (host), `%p` (port), `%r` (user), and `%%`.
+fn expand_control_path(template: &std::path::Path, key: &HostKey) -> std::path::PathBuf {
+    let mut out = String::new();
+    let template = template.to_string_lossy();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => out.push_str(&key.host),
+            Some('p') => out.push_str(&key.port.to_string()),
+            Some('r') => out.push_str(&key.username),
+            Some('%') => out.push('%'),
+            // Tokens only the master can expand (`%C`, ...) pass
+            // through for it to have resolved identically.
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    std::path::PathBuf::from(out)
+}
+
+/// A single authenticated SSH session to one host.
+pub struct SSHConnection {
+    transport: Transport,
+    key: HostKey,
+    /// Cap on captured stdout/stderr per command.
+    max_output_bytes: usize,
+    /// Label of the workload currently using the connection, for
+    /// attribution in stats and logs.
+    tag: std::sync::Mutex<Option<String>>,
+}
+
+impl SSHConnection {
+    /// Open a TCP connection, run the SSH handshake and authenticate.
+    pub async fn connect(key: HostKey, auth: &AuthMethod) -> Result<Self> {
+        Self::connect_with_config(key, auth, Arc::new(client::Config::default())).await
+    }
+
+    /// Like [`connect`](Self::connect), with explicit transport
+    /// configuration (compression, algorithm preferences).
+    pub async fn connect_with_config(
+        key: HostKey,
+        auth: &AuthMethod,
+        config: Arc<client::Config>,
+    ) -> Result<Self> {
+        Self::connect_with_socket_options(key, auth, config, &SocketOptions::default()).await
+    }
+
+    /// Like [`connect_with_config`](Self::connect_with_config), with
+    /// explicit TCP-level tuning applied to the raw socket before the
+    /// handshake starts.
+    #[tracing::instrument(
+        skip_all,
+        fields(host = %key.host, port = key.port, user = %key.username)
+    )]
+    pub async fn connect_with_socket_options(
+        key: HostKey,
+        auth: &AuthMethod,
+        config: Arc<client::Config>,
+        socket_options: &SocketOptions,
+    ) -> Result<Self> {
+        let stream = tokio::time::timeout(
+            socket_options.connect_timeout,
+            tokio::net::TcpStream::connect((key.host.as_str(), key.port)),
+        )
+        .await
+        .map_err(|_| {
+            // Shape the timeout like any other transport failure so
+            // retry classification doesn't need a special case.
+            anyhow::Error::new(SshError::Transport(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "connect timed out after {:?}",
+                    socket_options.connect_timeout
+                ),
+            )))
+        })?
+        .map_err(|e| anyhow::Error::new(SshError::Transport(e)))
+        .with_context(|| format!("tcp connect to {key} failed"))?;
+        // Interactive channels are latency-bound; never Nagle-buffer
+        // keystrokes. Both options are best-effort: a socket that
+        // can't take them still carries SSH fine.
+        if let Err(e) = stream.set_nodelay(true) {
+            tracing::warn!("setting TCP_NODELAY for {key} failed: {e}");
+        }
+        if let Some(interval) = socket_options.keepalive {
+            let keepalive = socket2::TcpKeepalive::new()
+                .with_time(interval)
+                .with_interval(interval);
+            if let Err(e) = socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+                tracing::warn!("setting TCP keepalive for {key} failed: {e}");
+            }
+        }
+        let mut handle = client::connect_stream(config, stream, ClientHandler)
+            .await
+            .map_err(|e| match e {
+                // Surface transport problems as the typed error so
+                // callers can classify without string-matching.
+                russh::Error::IO(io) => anyhow::Error::new(SshError::Transport(io)),
+                other => anyhow::Error::new(other),
+            })
+            .with_context(|| format!("ssh handshake with {key} failed"))?;
+
+        match auth {
+            AuthMethod::Password(password) => {
+                let result = handle
+                    .authenticate_password(key.username.clone(), password.clone())
+                    .await
+                    .with_context(|| format!("authentication with {key} failed"))?;
+                if !matches!(result, russh::client::AuthResult::Success) {
+                    return Err(anyhow::Error::new(SshError::AuthFailed)
+                        .context(format!("authentication rejected by {key}")));
+                }
+            }
+        }
+
+        Ok(Self {
+            transport: Transport::Direct(handle),
+            key,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            tag: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// Join an OS-level `ssh -M` ControlMaster instead of opening a
+    /// transport of our own: after an `-O check` confirms the socket
+    /// answers, every command runs through the system `ssh` client
+    /// multiplexed over it. The handshake cost is the master's, so
+    /// establishing is nearly free and the underlying connection
+    /// survives restarts of this process. `control_path` may use the
+    /// OpenSSH `%h`/`%p`/`%r` tokens.
+    ///
+    /// Only plain command execution is available this way; streaming,
+    /// interactive, and SFTP operations need a direct connection.
+    pub async fn via_control_socket(
+        key: HostKey,
+        control_path: &std::path::Path,
+    ) -> Result<Self> {
+        let socket = expand_control_path(control_path, &key);
+        let socket_str = socket
+            .to_str()
+            .ok_or_else(|| anyhow!("control path {} is not valid UTF-8", socket.display()))?;
+        let target = format!("{}@{}", key.username, key.host);
+        let port = key.port.to_string();
+        let check = crate::local::run(
+            "ssh",
+            &["-S", socket_str, "-O", "check", "-p", &port, &target],
+            Duration::from_secs(10),
+        )
+        .await
+        .with_context(|| format!("checking control socket {} failed", socket.display()))?;
+        if !check.success() {
+            bail!(
+                "no live control master for {key} at {}: {}",
+                socket.display(),
+                check.stderr_lossy().trim()
+            );
+        }
+        Ok(Self {
+            transport: Transport::ControlMaster { socket },
+            key,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            tag: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// The russh handle behind this connection, for operations the
+    /// control-master transport cannot serve.
+    fn direct(&self) -> Result<&client::Handle<ClientHandler>> {
+        match &self.transport {
+            Transport::Direct(handle) => Ok(handle),
+            Transport::ControlMaster { socket } => Err(anyhow!(
+                "{} is reached through control socket {}; \
+                 this operation needs a direct connection",
+                self.key,
+                socket.display()
+            )),
+        }
+    }
+
+    /// Label (or clear the label of) the workload using this
+    /// connection; surfaced via [`tag`](Self::tag) and
+    /// [`SSHPool::stats`].
+    pub fn set_tag(&self, tag: Option<&str>) {
+        *self.tag.lock().expect("tag poisoned") = tag.map(str::to_owned);
+    }
+
+    pub fn tag(&self) -> Option<String> {
+        self.tag.lock().expect("tag poisoned").clone()
+    }
+
+    /// Override the per-command output capture cap.
+    pub fn with_max_output_bytes(mut self, bytes: usize) -> Self {
+        self.max_output_bytes = bytes;
+        self
+    }
+
+    pub fn host_key(&self) -> &HostKey {
+        &self.key
+    }
+
+    /// Whether the underlying session is still usable. A control
+    /// socket's liveness belongs to the external master, so it is
+    /// checked per command rather than here.
+    pub fn is_alive(&self) -> bool {
+        match &self.transport {
+            Transport::Direct(handle) => !handle.is_closed(),
+            Transport::ControlMaster { .. } => true,
+        }
+    }
+
+    /// Send a protocol-level disconnect so the remote sshd logs a
+    /// clean close. Best-effort: a transport that's already gone has
+    /// nothing left to say goodbye to. A no-op over a control socket,
+    /// whose transport belongs to the external master.
+    pub async fn disconnect(&self) {
+        if let Transport::Direct(handle) = &self.transport {
+            let _ = handle
+                .disconnect(russh::Disconnect::ByApplication, "shutting down", "en")
+                .await;
+        }
+    }
+
+    /// Run `command` on the remote host and collect its full output.
+    pub async fn exec(&self, command: &str) -> Result<CommandOutput> {
+        self.exec_cancellable(command, &CancellationToken::new())
+            .await
+    }
+
+    /// Like [`exec`](Self::exec), but aborts when `cancel` fires:
+    /// the channel is closed (freeing the pooled connection for the
+    /// next command) and an error is returned. Abandoned clients can
+    /// thus cancel a long-running command instead of tying up the
+    /// connection until the timeout.
+    #[tracing::instrument(
+        skip_all,
+        fields(host = %self.key.host, port = self.key.port, user = %self.key.username, command)
+    )]
+    pub async fn exec_cancellable(
+        &self,
+        command: &str,
+        cancel: &CancellationToken,
+    ) -> Result<CommandOutput> {
+        self.exec_with_timeout(command, DEFAULT_COMMAND_TIMEOUT, cancel)
+            .await
+    }
+
+    /// Like [`exec_cancellable`](Self::exec_cancellable), with an
+    /// explicit per-command timeout for operations that legitimately
+    /// outlive [`DEFAULT_COMMAND_TIMEOUT`].
+    pub async fn exec_with_timeout(
+        &self,
+        command: &str,
+        timeout: Duration,
+        cancel: &CancellationToken,
+    ) -> Result<CommandOutput> {
+        tokio::time::timeout(timeout, self.exec_inner(command, cancel))
+            .await
+            .map_err(|_| {
+                anyhow::Error::new(SshError::CommandTimeout(timeout))
+                    .context(format!("command timed out after {timeout:?} on {}", self.key))
+            })?
+    }
+
+    /// Run `command` through the system `ssh` client over the control
+    /// socket. The caller's timeout wraps this (killing the child on
+    /// expiry via the dropped future), so the local run itself gets no
+    /// deadline of its own.
+    async fn exec_via_control(
+        &self,
+        socket: &std::path::Path,
+        command: &str,
+        cancel: &CancellationToken,
+    ) -> Result<CommandOutput> {
+        let socket_str = socket
+            .to_str()
+            .ok_or_else(|| anyhow!("control path {} is not valid UTF-8", socket.display()))?;
+        let port = self.key.port.to_string();
+        let args = [
+            "-S",
+            socket_str,
+            "-o",
+            "BatchMode=yes",
+            "-p",
+            &port,
+            "-l",
+            &self.key.username,
+            &self.key.host,
+            "--",
+            command,
+        ];
+        let output = tokio::select! {
+            result = crate::local::run("ssh", &args, Duration::MAX) => result
+                .with_context(|| format!("running ssh over control socket {socket_str}"))?,
+            _ = cancel.cancelled() => {
+                bail!("command on {} cancelled", self.key)
+            }
+        };
+        Ok(CommandOutput {
+            stdout: output.stdout,
+            stderr: output.stderr,
+            // 255 is the ssh client's own failure code; a killed child
+            // reports the same way.
+            exit_status: output.exit_code.map(|c| c as u32).unwrap_or(255),
+            truncated: output.truncated,
+        })
+    }
+
+    async fn exec_inner(&self, command: &str, cancel: &CancellationToken) -> Result<CommandOutput> {
+        if let Transport::ControlMaster { socket } = &self.transport {
+            return self.exec_via_control(socket, command, cancel).await;
+        }
+        let mut channel = self
+            .direct()?
+            .channel_open_session()
+            .await
+            .with_context(|| format!("opening channel to {} failed", self.key))?;
+        channel.exec(true, command).await?;
+
+        let mut stdout = StreamingOutputHandler::new_truncating(self.max_output_bytes);
+        let mut stderr = StreamingOutputHandler::new_truncating(self.max_output_bytes);
+        let mut exit_status = 0;
+        let mut saw_exit = false;
+        loop {
+            let msg = tokio::select! {
+                msg = channel.wait() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = cancel.cancelled() => {
+                    channel.close().await?;
+                    bail!("command cancelled on {}", self.key);
+                }
+            };
+            match msg {
+                // Truncating handlers never error.
+                ChannelMsg::Data { data } => {
+                    let _ = stdout.push_chunk(&data);
+                }
+                ChannelMsg::ExtendedData { data, ext: 1 } => {
+                    let _ = stderr.push_chunk(&data);
+                }
+                ChannelMsg::ExitStatus { exit_status: status } => {
+                    exit_status = status;
+                    saw_exit = true;
+                }
+                _ => {}
+            }
+        }
+        // A channel that ends without an exit status on a transport
+        // that is now closed didn't finish — the session died under
+        // the command. Surfacing that as a transport error (rather
+        // than a fabricated exit 0) lets the pool tell connection
+        // death from command failure.
+        if !saw_exit && !self.is_alive() {
+            return Err(anyhow::Error::new(SshError::Transport(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "session closed before the command finished",
+            ))))
+            .with_context(|| format!("connection to {} died mid-command", self.key));
+        }
+        Ok(CommandOutput {
+            truncated: stdout.truncated() || stderr.truncated(),
+            stdout: stdout.finalize(),
+            stderr: stderr.finalize(),
+            exit_status,
+        })
+    }
+}
+
+/// One event of a streamed command's lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    Stdout(Vec<u8>),
+    Stderr(Vec<u8>),
+    /// Terminal event: the command finished with this status.
+    Exit(u32),
+}
+
+impl SSHConnection {
+    /// Run `command`, delivering output chunks as they arrive instead
+    /// of buffering until completion.
+    ///
+    /// The receiver yields [`StreamEvent`]s and closes after
+    /// [`StreamEvent::Exit`]. Dropping the receiver cancels the
+    /// stream: the channel is closed so the connection is freed
+    /// rather than draining output nobody reads.
+    #[tracing::instrument(
+        skip_all,
+        fields(host = %self.key.host, port = self.key.port, user = %self.key.username, command)
+    )]
+    pub async fn exec_stream(
+        &self,
+        command: &str,
+    ) -> Result<tokio::sync::mpsc::UnboundedReceiver<StreamEvent>> {
+        let mut channel = self
+            .direct()?
+            .channel_open_session()
+            .await
+            .with_context(|| format!("opening channel to {} failed", self.key))?;
+        channel.exec(true, command).await?;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut exit_status = 0;
+            while let Some(msg) = channel.wait().await {
+                let event = match msg {
+                    ChannelMsg::Data { data } => StreamEvent::Stdout(data.to_vec()),
+                    ChannelMsg::ExtendedData { data, ext: 1 } => StreamEvent::Stderr(data.to_vec()),
+                    ChannelMsg::ExitStatus { exit_status: status } => {
+                        exit_status = status;
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if tx.send(event).is_err() {
+                    let _ = channel.close().await;
+                    return;
+                }
+            }
+            let _ = tx.send(StreamEvent::Exit(exit_status));
+        });
+        Ok(rx)
+    }
+}
+
+/// A remote command running with a PTY and an open stdin.
+///
+/// Dropping the handle closes the channel, terminating the command.
+pub struct InteractiveCommand {
+    stdin: Option<tokio::sync::mpsc::UnboundedSender<Vec<u8>>>,
+    events: tokio::sync::mpsc::UnboundedReceiver<StreamEvent>,
+    /// Output already read past the previous sentinel, carried over
+    /// for the next [`exec_delimited`](Self::exec_delimited) call.
+    pending: Vec<u8>,
+}
+
+impl InteractiveCommand {
+    /// Feed bytes to the remote program's stdin.
+    pub fn write_stdin(&self, data: &[u8]) -> Result<()> {
+        self.stdin
+            .as_ref()
+            .ok_or_else(|| anyhow!("stdin already closed"))?
+            .send(data.to_vec())
+            .map_err(|_| anyhow!("command already finished"))
+    }
+
+    /// Signal EOF on stdin, letting programs that read until EOF
+    /// finish.
+    pub fn close_stdin(&mut self) {
+        self.stdin = None;
+    }
+
+    /// The next output event; `None` after [`StreamEvent::Exit`].
+    pub async fn recv(&mut self) -> Option<StreamEvent> {
+        self.events.recv().await
+    }
+
+    /// Run one command inside the interactive session and return its
+    /// output and exit code, delimited by a unique sentinel — several
+    /// commands can share one channel without reconnecting between
+    /// them.
+    ///
+    /// The command is sent as `cmd; echo __REBE_DONE_<nonce>_$?__`;
+    /// output is collected until the shell's expansion of that marker
+    /// arrives, and the exit code is parsed out of it. The echoed
+    /// input line (PTYs echo) still contains the literal `$?`, which
+    /// is how it is told apart from the real marker. The session must
+    /// be a shell reading command lines from stdin; with a PTY the
+    /// echoed input is part of the returned output.
+    pub async fn exec_delimited(
+        &mut self,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<CommandOutput> {
+        let nonce = uuid::Uuid::new_v4().simple().to_string();
+        let prefix = format!("__REBE_DONE_{nonce}_");
+        self.write_stdin(format!("{command}; echo {prefix}$?__\n").as_bytes())?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut buf = std::mem::take(&mut self.pending);
+        loop {
+            if let Some((output_end, exit_status, resume)) = find_sentinel(&buf, prefix.as_bytes())
+            {
+                self.pending = buf.split_off(resume);
+                buf.truncate(output_end);
+                return Ok(CommandOutput {
+                    truncated: false,
+                    stdout: buf,
+                    stderr: Vec::new(),
+                    exit_status,
+                });
+            }
+            let event = tokio::time::timeout_at(deadline, self.recv())
+                .await
+                .map_err(|_| anyhow::Error::new(SshError::CommandTimeout(timeout)))
+                .context("command did not reach its sentinel marker in time")?;
+            match event {
+                // A PTY merges stderr into stdout anyway; collect both
+                // so non-PTY sessions behave the same.
+                Some(StreamEvent::Stdout(data)) | Some(StreamEvent::Stderr(data)) => {
+                    buf.extend_from_slice(&data)
+                }
+                Some(StreamEvent::Exit(_)) | None => {
+                    bail!("channel closed before the sentinel marker arrived")
+                }
+            }
+        }
+    }
+}
+
+/// Locate a completed sentinel `<prefix><digits>__` in `buf`.
+///
+/// Returns the offset where command output ends (the start of the
+/// marker's line), the parsed exit code, and the offset after the
+/// marker line where subsequent output resumes. Occurrences of the
+/// prefix not followed by digits — the PTY echo of the literal
+/// `<prefix>$?__` — are skipped.
+fn find_sentinel(buf: &[u8], prefix: &[u8]) -> Option<(usize, u32, usize)> {
+    let mut from = 0;
+    while let Some(pos) = buf[from..]
+        .windows(prefix.len())
+        .position(|w| w == prefix)
+        .map(|p| p + from)
+    {
+        let digits_start = pos + prefix.len();
+        let digits_end = buf[digits_start..]
+            .iter()
+            .position(|b| !b.is_ascii_digit())
+            .map_or(buf.len(), |p| p + digits_start);
+        if digits_end > digits_start && buf[digits_end..].starts_with(b"__") {
+            let exit_status = std::str::from_utf8(&buf[digits_start..digits_end])
+                .ok()?
+                .parse()
+                .ok()?;
+            let line_start = buf[..pos]
+                .iter()
+                .rposition(|&b| b == b'\n')
+                .map_or(0, |p| p + 1);
+            let mut resume = digits_end + 2;
+            while buf[resume..].starts_with(b"\r") || buf[resume..].starts_with(b"\n") {
+                resume += 1;
+            }
+            return Some((line_start, exit_status, resume));
+        }
+        from = digits_start;
+    }
+    None
+}
+
+impl SSHConnection {
+    /// Run `command` with a PTY and an open stdin, for remote programs
+    /// that prompt (`sudo`, interactive installers).
+    ///
+    /// The PTY makes the remote side emit its prompts; input written
+    /// through [`InteractiveCommand::write_stdin`] reaches the program
+    /// as terminal input. With a PTY, stderr arrives merged into the
+    /// stdout stream.
+    #[tracing::instrument(
+        skip_all,
+        fields(host = %self.key.host, port = self.key.port, user = %self.key.username, command)
+    )]
+    pub async fn exec_interactive(&self, command: &str) -> Result<InteractiveCommand> {
+        let mut channel = self
+            .direct()?
+            .channel_open_session()
+            .await
+            .with_context(|| format!("opening channel to {} failed", self.key))?;
+        channel
+            .request_pty(false, "xterm-256color", 80, 24, 0, 0, &[])
+            .await?;
+        channel.exec(true, command).await?;
+
+        let (stdin_tx, mut stdin_rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+        let (event_tx, event_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut exit_status = 0;
+            let mut stdin_open = true;
+            loop {
+                tokio::select! {
+                    msg = channel.wait() => {
+                        let event = match msg {
+                            Some(ChannelMsg::Data { data }) => StreamEvent::Stdout(data.to_vec()),
+                            Some(ChannelMsg::ExtendedData { data, ext: 1 }) => {
+                                StreamEvent::Stderr(data.to_vec())
+                            }
+                            Some(ChannelMsg::ExitStatus { exit_status: status }) => {
+                                exit_status = status;
+                                continue;
+                            }
+                            Some(_) => continue,
+                            None => break,
+                        };
+                        if event_tx.send(event).is_err() {
+                            let _ = channel.close().await;
+                            return;
+                        }
+                    }
+                    data = stdin_rx.recv(), if stdin_open => match data {
+                        Some(data) => {
+                            if channel.data(&data[..]).await.is_err() {
+                                break;
+                            }
+                        }
+                        None => {
+                            stdin_open = false;
+                            let _ = channel.eof().await;
+                        }
+                    },
+                }
+            }
+            let _ = event_tx.send(StreamEvent::Exit(exit_status));
+        });
+
+        Ok(InteractiveCommand {
+            stdin: Some(stdin_tx),
+            events: event_rx,
+            pending: Vec::new(),
+        })
+    }
+}
+
+/// Decision returned by a line callback after each output line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineAction {
+    /// Keep reading output.
+    Continue,
+    /// Close the channel, terminating the command early.
+    Stop,
+}
+
+impl SSHConnection {
+    /// Run `command`, invoking `on_line` for every complete output line
+    /// as it arrives.
+    ///
+    /// Returning [`LineAction::Stop`] terminates the command early and
+    /// returns whatever output was received up to that point — useful
+    /// for wait-for-pattern flows ("proceed once a `ready` line shows
+    /// up") without parsing the whole stream after the fact.
+    #[tracing::instrument(
+        skip_all,
+        fields(host = %self.key.host, port = self.key.port, user = %self.key.username, command)
+    )]
+    pub async fn exec_with_line_callback(
+        &self,
+        command: &str,
+        timeout: Duration,
+        on_line: impl FnMut(&str) -> LineAction,
+    ) -> Result<CommandOutput> {
+        tokio::time::timeout(timeout, self.exec_with_line_callback_inner(command, on_line))
+            .await
+            .map_err(|_| anyhow!("command timed out after {timeout:?} on {}", self.key))?
+    }
+
+    async fn exec_with_line_callback_inner(
+        &self,
+        command: &str,
+        mut on_line: impl FnMut(&str) -> LineAction,
+    ) -> Result<CommandOutput> {
+        let mut channel = self
+            .direct()?
+            .channel_open_session()
+            .await
+            .with_context(|| format!("opening channel to {} failed", self.key))?;
+        channel.exec(true, command).await?;
+
+        let mut output = CommandOutput::default();
+        // Start of the first line not yet handed to the callback.
+        let mut line_start = 0;
+        'msgs: while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => {
+                    output.stdout.extend_from_slice(&data);
+                    while let Some(nl) = output.stdout[line_start..]
+                        .iter()
+                        .position(|&b| b == b'\n')
+                    {
+                        let line_end = line_start + nl;
+                        let line = String::from_utf8_lossy(&output.stdout[line_start..line_end]);
+                        let action = on_line(line.trim_end_matches('\r'));
+                        line_start = line_end + 1;
+                        if action == LineAction::Stop {
+                            channel.close().await?;
+                            break 'msgs;
+                        }
+                    }
+                }
+                ChannelMsg::ExtendedData { data, ext: 1 } => {
+                    output.stderr.extend_from_slice(&data)
+                }
+                ChannelMsg::ExitStatus { exit_status } => output.exit_status = exit_status,
+                _ => {}
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// One host's entry in [`SSHPool::stats`].
+#[derive(Debug, Clone)]
+pub struct PoolHostStats {
+    pub host: HostKey,
+    pub connections: usize,
+    pub in_use: usize,
+    pub alive: bool,
+    /// Workload labels of the host's tagged connections, in slot order.
+    pub tags: Vec<String>,
+}
+
+/// Allowed SSH algorithms, in preference order, for hosts that only
+/// speak a restricted set (legacy gear, FIPS builds). Any field left
+/// `None` keeps russh's defaults. Unknown algorithm names fail the
+/// connect with an error naming them.
+#[derive(Debug, Clone, Default)]
+pub struct AlgorithmPrefs {
+    pub kex: Option<Vec<String>>,
+    pub host_key: Option<Vec<String>>,
+    pub cipher: Option<Vec<String>>,
+    pub mac: Option<Vec<String>>,
+}
+
+/// TCP-level tuning applied to the raw socket before the SSH
+/// handshake.
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    /// How long the TCP connect may take before the attempt fails.
+    /// Without an explicit bound a dead address can block for the OS
+    /// default of a couple of minutes.
+    pub connect_timeout: Duration,
+    /// Idle time before TCP keepalive probes start, also used as the
+    /// probe interval, so the kernel notices a silently dropped link
+    /// (dead peer, expired NAT state) within a few probes instead of
+    /// leaving a pooled connection to hang the next command. `None`
+    /// disables keepalive.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            keepalive: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Limits applied when checking connections out of the pool.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Ceiling on concurrent connections per host.
+    pub max_connections_per_host: usize,
+    /// How long a checkout may wait for a busy slot to free up before
+    /// failing; `None` fails immediately on exhaustion.
+    pub acquire_timeout: Option<Duration>,
+    /// Extra connection attempts after a retryable transport failure
+    /// (timeout, reset); authentication rejections never retry.
+    pub connect_retries: u32,
+    /// Negotiate SSH-level compression, for constrained links. Off by
+    /// default: on a fast LAN it only costs CPU.
+    pub compression: bool,
+    /// Cap on captured stdout/stderr per command; see
+    /// [`DEFAULT_MAX_OUTPUT_BYTES`].
+    pub max_output_bytes: usize,
+    /// Restrict the algorithms offered during the handshake; `None`
+    /// negotiates from russh's defaults.
+    pub algorithms: Option<AlgorithmPrefs>,
+    /// `ControlPath` of an OS-level `ssh -M` master (OpenSSH
+    /// `%h`/`%p`/`%r` tokens allowed). When set, the pool joins the
+    /// master via [`SSHConnection::via_control_socket`] instead of
+    /// opening transports of its own, so the underlying connections
+    /// survive restarts of this process.
+    pub control_path: Option<std::path::PathBuf>,
+    /// TCP tuning for the sockets under new connections; see
+    /// [`SocketOptions`].
+    pub socket_options: SocketOptions,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_host: 8,
+            acquire_timeout: Some(Duration::from_secs(10)),
+            connect_retries: 2,
+            compression: false,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            algorithms: None,
+            control_path: None,
+            socket_options: SocketOptions::default(),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// A validating builder starting from the defaults.
+    pub fn builder() -> PoolConfigBuilder {
+        PoolConfigBuilder::default()
+    }
+}
+
+/// Builds a [`PoolConfig`], validating the combination at
+/// [`build`](Self::build) time so a malformed config (zero connections
+/// per host, a misspelled cipher name) fails up front rather than on
+/// the first checkout.
+#[derive(Debug, Default)]
+pub struct PoolConfigBuilder {
+    config: PoolConfig,
+}
+
+impl PoolConfigBuilder {
+    pub fn max_connections_per_host(mut self, max: usize) -> Self {
+        self.config.max_connections_per_host = max;
+        self
+    }
+
+    /// How long a checkout may wait for a busy slot; `None` fails
+    /// immediately on exhaustion.
+    pub fn acquire_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.acquire_timeout = timeout;
+        self
+    }
+
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.config.connect_retries = retries;
+        self
+    }
+
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.config.compression = enabled;
+        self
+    }
+
+    pub fn max_output_bytes(mut self, bytes: usize) -> Self {
+        self.config.max_output_bytes = bytes;
+        self
+    }
+
+    pub fn algorithms(mut self, prefs: AlgorithmPrefs) -> Self {
+        self.config.algorithms = Some(prefs);
+        self
+    }
+
+    /// Join an OS-level ControlMaster at this `ControlPath` instead of
+    /// opening transports directly; see [`PoolConfig::control_path`].
+    pub fn control_path(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.config.control_path = Some(path.into());
+        self
+    }
+
+    /// TCP tuning for new connections' sockets; see [`SocketOptions`].
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.config.socket_options = options;
+        self
+    }
+
+    /// Validate and produce the config.
+    pub fn build(self) -> Result<PoolConfig> {
+        let config = self.config;
+        if config.max_connections_per_host == 0 {
+            bail!("max_connections_per_host must be at least 1");
+        }
+        if config.acquire_timeout == Some(Duration::ZERO) {
+            bail!("acquire_timeout must be positive (use None to fail immediately)");
+        }
+        if config.max_output_bytes == 0 {
+            bail!("max_output_bytes must be at least 1");
+        }
+        // Surface algorithm typos here instead of on the first
+        // handshake.
+        if let Some(prefs) = &config.algorithms {
+            if let Some(kex) = &prefs.kex {
+                parse_algorithm_names::<russh::kex::Name>(kex, "kex")?;
+            }
+            if let Some(host_key) = &prefs.host_key {
+                for name in host_key {
+                    name.parse::<russh::keys::Algorithm>()
+                        .map_err(|_| anyhow!("unknown host key algorithm {name:?}"))?;
+                }
+            }
+            if let Some(cipher) = &prefs.cipher {
+                parse_algorithm_names::<russh::cipher::Name>(cipher, "cipher")?;
+            }
+            if let Some(mac) = &prefs.mac {
+                parse_algorithm_names::<russh::mac::Name>(mac, "mac")?;
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Initial pause between connection attempts; doubles per retry.
+const CONNECT_BACKOFF: Duration = Duration::from_millis(200);
+
+/// How long [`SSHPool::shutdown`] waits for checked-out connections
+/// to come back before disconnecting them out from under their users.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parse algorithm names into russh's typed form, naming the offender
+/// and its `kind` (`kex`, `cipher`, `mac`) on failure.
+fn parse_algorithm_names<T: for<'a> TryFrom<&'a str>>(names: &[String], kind: &str) -> Result<Vec<T>> {
+    names
+        .iter()
+        .map(|name| {
+            T::try_from(name).map_err(|_| anyhow!("unknown {kind} algorithm {name:?}"))
+        })
+        .collect()
+}
+
+/// Whether a failed connect is worth retrying: transport-level errors
+/// (a momentarily loaded or restarting host) are, authentication and
+/// protocol rejections are not.
+fn is_retryable_connect_error(err: &anyhow::Error) -> bool {
+    for cause in err.chain() {
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        if let Some(russh) = cause.downcast_ref::<russh::Error>() {
+            return matches!(russh, russh::Error::IO(_) | russh::Error::Disconnect);
+        }
+    }
+    false
+}
+
+/// Whether a failed exec means the session under it died, as opposed
+/// to the command failing: the connection now reports itself closed,
+/// the failure is a typed transport error, or russh saw the link go.
+/// Only these justify a transparent retry — a genuine command failure
+/// must reach the caller untouched.
+fn is_session_death(conn: &SSHConnection, err: &anyhow::Error) -> bool {
+    if !conn.is_alive() {
+        return true;
+    }
+    if matches!(SshError::classify(err), Some(SshError::Transport(_))) {
+        return true;
+    }
+    err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<russh::Error>(),
+            Some(russh::Error::IO(_) | russh::Error::Disconnect | russh::Error::SendError)
+        )
+    })
+}
+
+/// Handshake latencies a host's metrics keep for percentile summaries
+/// before discarding the oldest.
+const HANDSHAKE_SAMPLES: usize = 256;
+
+/// Per-host connection timing and reuse counters, accumulated since
+/// pool creation.
+///
+/// This is the data behind the pooling pitch: the reuse ratio shows
+/// how often a checkout skipped the handshake, and the latency fields
+/// point at the one host dragging a batch down.
+#[derive(Debug, Clone, Default)]
+pub struct ConnMetrics {
+    /// Checkouts that performed a fresh handshake.
+    pub created: u64,
+    /// Checkouts satisfied by an idle pooled connection.
+    pub reused: u64,
+    /// Cumulative time spent establishing connections, including
+    /// retry backoff.
+    pub total_handshake: Duration,
+    /// Latency of the most recent handshake.
+    pub last_handshake: Option<Duration>,
+    /// Recent handshake latencies, oldest first, capped at
+    /// [`HANDSHAKE_SAMPLES`].
+    pub handshake_samples: Vec<Duration>,
+}
+
+impl ConnMetrics {
+    /// Fraction of checkouts served without a handshake.
+    pub fn reuse_ratio(&self) -> f64 {
+        let total = self.created + self.reused;
+        if total == 0 {
+            return 0.0;
+        }
+        self.reused as f64 / total as f64
+    }
+
+    fn record_handshake(&mut self, latency: Duration) {
+        self.created += 1;
+        self.total_handshake += latency;
+        self.last_handshake = Some(latency);
+        if self.handshake_samples.len() == HANDSHAKE_SAMPLES {
+            self.handshake_samples.remove(0);
+        }
+        self.handshake_samples.push(latency);
+    }
+}
+
+/// One pooled connection to a host, with its checked-out flag.
+struct PooledSlot {
+    conn: Arc<SSHConnection>,
+    in_use: Arc<AtomicBool>,
+}
+
+/// A host's slots plus the semaphore capping them.
+struct HostEntry {
+    slots: Vec<PooledSlot>,
+    semaphore: Arc<tokio::sync::Semaphore>,
+}
+
+/// A connection checked out of the pool by one caller.
+///
+/// The guard is bound to the specific slot it was acquired from, so
+/// concurrent checkouts against one host each get their own session
+/// and their commands cannot interleave.
+///
+/// Call [`release`](Self::release) when done so the connection is
+/// marked free at a deterministic point; plain `Drop` also returns it,
+/// but only as a best-effort fallback that callers shouldn't sequence
+/// against (e.g. before asserting on [`SSHPool::stats`]).
+pub struct PooledConnection {
+    conn: Arc<SSHConnection>,
+    in_use: Arc<AtomicBool>,
+    /// Held for the lifetime of the checkout; dropping it admits the
+    /// next waiter.
+    _permit: tokio::sync::OwnedSemaphorePermit,
+    released: bool,
+}
+
+impl PooledConnection {
+    /// Return the connection to the pool. After this resolves, the
+    /// slot is free for the next checkout.
+    pub async fn release(mut self) {
+        self.mark_released();
+    }
+
+    fn mark_released(&mut self) {
+        if !self.released {
+            self.in_use.store(false, Ordering::SeqCst);
+            self.released = true;
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = SSHConnection;
+
+    fn deref(&self) -> &SSHConnection {
+        &self.conn
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        self.mark_released();
+    }
+}
+
+/// A pool of authenticated connections, keyed by [`HostKey`].
+///
+/// Credentials are supplied per call and only used when a connection
+/// has to be (re)established; an existing pooled connection is reused
+/// as-is.
+pub struct SSHPool {
+    config: PoolConfig,
+    connections: Mutex<HashMap<HostKey, HostEntry>>,
+    /// Outlives the slots it measures: counters persist across reaps.
+    metrics: std::sync::Mutex<HashMap<HostKey, ConnMetrics>>,
+    events: Option<Arc<EventBus>>,
+    audit: Option<Arc<crate::audit::AuditLog>>,
+}
+
+impl Default for SSHPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SSHPool {
+    pub fn new() -> Self {
+        Self::with_config(PoolConfig::default())
+    }
+
+    pub fn with_config(config: PoolConfig) -> Self {
+        Self {
+            config,
+            connections: Mutex::new(HashMap::new()),
+            metrics: std::sync::Mutex::new(HashMap::new()),
+            events: None,
+            audit: None,
+        }
+    }
+
+    /// Publish connection lifecycle events to `events`.
+    pub fn with_events(mut self, events: Arc<EventBus>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Record every exec through this pool in `audit`; see
+    /// [`crate::audit`].
+    pub fn with_audit(mut self, audit: Arc<crate::audit::AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Emit one audit record for a completed exec against `key`.
+    fn audit_exec(
+        &self,
+        key: &HostKey,
+        command: &str,
+        started: std::time::Instant,
+        result: &Result<CommandOutput>,
+    ) {
+        let Some(log) = &self.audit else { return };
+        let mut record = crate::audit::AuditRecord::now(
+            key.username.clone(),
+            format!("{}:{}", key.host, key.port),
+            command,
+        );
+        record.duration_ms = started.elapsed().as_millis() as u64;
+        record.exit_code = result.as_ref().ok().map(|o| o.exit_status);
+        log.emit(record);
+    }
+
+    fn publish(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.publish(event);
+        }
+    }
+
+    /// Check out a free connection for `key`, establishing a new one
+    /// when every pooled slot is busy and the host is under its
+    /// connection cap. At the cap, waits up to
+    /// [`PoolConfig::acquire_timeout`] for a slot to free.
+    pub async fn checkout(&self, key: &HostKey, auth: &AuthMethod) -> Result<PooledConnection> {
+        self.checkout_tagged(key, auth, None).await
+    }
+
+    /// Like [`checkout`](Self::checkout), labelling the connection with
+    /// the workload acquiring it (`discovery`, `provisioning`, ...) so
+    /// stats and log spans can be sliced by job. Attribution only;
+    /// pooling semantics are unchanged.
+    #[tracing::instrument(
+        name = "acquire",
+        skip_all,
+        fields(host = %key.host, port = key.port, user = %key.username, tag)
+    )]
+    pub async fn checkout_tagged(
+        &self,
+        key: &HostKey,
+        auth: &AuthMethod,
+        tag: Option<&str>,
+    ) -> Result<PooledConnection> {
+        if let Some(tag) = tag {
+            tracing::Span::current().record("tag", tag);
+        }
+        let semaphore = {
+            let mut connections = self.connections.lock().await;
+            let entry = connections.entry(key.clone()).or_insert_with(|| HostEntry {
+                slots: Vec::new(),
+                semaphore: Arc::new(tokio::sync::Semaphore::new(
+                    self.config.max_connections_per_host,
+                )),
+            });
+            entry.semaphore.clone()
+        };
+
+        let permit = match self.config.acquire_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, semaphore.acquire_owned())
+                .await
+                .map_err(|_| {
+                    anyhow::Error::new(SshError::PoolExhausted).context(format!(
+                        "pool exhausted for {key}: no connection freed within {timeout:?}"
+                    ))
+                })?
+                .expect("pool semaphore closed"),
+            None => semaphore.try_acquire_owned().map_err(|_| {
+                anyhow::Error::new(SshError::PoolExhausted)
+                    .context(format!("pool exhausted for {key}"))
+            })?,
+        };
+
+        let mut connections = self.connections.lock().await;
+        let entry = connections.get_mut(key).expect("host entry vanished");
+        // Reap idle slots whose transport has died; reusing one would
+        // fail the next command for no reason.
+        let before = entry.slots.len();
+        entry
+            .slots
+            .retain(|s| s.in_use.load(Ordering::SeqCst) || s.conn.is_alive());
+        for _ in entry.slots.len()..before {
+            self.publish(Event::ConnectionReaped {
+                host: key.to_string(),
+            });
+        }
+        if let Some(slot) = entry
+            .slots
+            .iter()
+            .find(|s| !s.in_use.load(Ordering::SeqCst))
+        {
+            slot.in_use.store(true, Ordering::SeqCst);
+            slot.conn.set_tag(tag);
+            self.metrics
+                .lock()
+                .expect("pool metrics poisoned")
+                .entry(key.clone())
+                .or_default()
+                .reused += 1;
+            self.publish(Event::ConnectionReused {
+                host: key.to_string(),
+            });
+            return Ok(PooledConnection {
+                conn: slot.conn.clone(),
+                in_use: slot.in_use.clone(),
+                _permit: permit,
+                released: false,
+            });
+        }
+        let started = std::time::Instant::now();
+        let conn = self.establish(key, auth).await?;
+        self.metrics
+            .lock()
+            .expect("pool metrics poisoned")
+            .entry(key.clone())
+            .or_default()
+            .record_handshake(started.elapsed());
+        conn.set_tag(tag);
+        let in_use = Arc::new(AtomicBool::new(true));
+        entry.slots.push(PooledSlot {
+            conn: conn.clone(),
+            in_use: in_use.clone(),
+        });
+        self.publish(Event::ConnectionCreated {
+            host: key.to_string(),
+        });
+        Ok(PooledConnection {
+            conn,
+            in_use,
+            _permit: permit,
+            released: false,
+        })
+    }
+
+    /// Run a single command on one host through the pool.
+    ///
+    /// A pooled connection can die between health checks (sshd
+    /// restart, dropped NAT entry); when the failure is session death
+    /// rather than the command itself, the corpse is evicted and the
+    /// command retried once on a fresh connection. One transparent
+    /// retry only — repeating further would mask real failures.
+    pub async fn exec(
+        &self,
+        key: &HostKey,
+        auth: &AuthMethod,
+        command: &str,
+    ) -> Result<CommandOutput> {
+        let started = std::time::Instant::now();
+        let conn = self.checkout(key, auth).await?;
+        let result = match conn.exec(command).await {
+            Err(e) if is_session_death(&conn, &e) => {
+                tracing::warn!(
+                    "connection to {key} died mid-exec; retrying once on a fresh connection"
+                );
+                self.evict(key, &conn.conn).await;
+                // Release (and its permit) before reconnecting, or a
+                // single-slot host would deadlock against itself.
+                conn.release().await;
+                match self.checkout(key, auth).await {
+                    Ok(fresh) => {
+                        let retried = fresh.exec(command).await;
+                        fresh.release().await;
+                        retried
+                    }
+                    Err(reconnect) => Err(reconnect.context(format!(
+                        "reconnect to {key} after mid-exec connection death failed"
+                    ))),
+                }
+            }
+            result => {
+                conn.release().await;
+                result
+            }
+        };
+        self.audit_exec(key, command, started, &result);
+        result
+    }
+
+    /// Cancellable variant of [`exec`](Self::exec); see
+    /// [`SSHConnection::exec_cancellable`].
+    pub async fn exec_cancellable(
+        &self,
+        key: &HostKey,
+        auth: &AuthMethod,
+        command: &str,
+        cancel: &CancellationToken,
+    ) -> Result<CommandOutput> {
+        let started = std::time::Instant::now();
+        let conn = self.checkout(key, auth).await?;
+        let result = conn.exec_cancellable(command, cancel).await;
+        conn.release().await;
+        self.audit_exec(key, command, started, &result);
+        result
+    }
+
+    /// The transport configuration new connections are established
+    /// with, derived from [`PoolConfig`]. Fails when
+    /// [`PoolConfig::algorithms`] names an algorithm russh doesn't
+    /// know.
+    fn client_config(&self) -> Result<Arc<client::Config>> {
+        let mut config = client::Config::default();
+        if self.config.compression {
+            config.preferred.compression = std::borrow::Cow::Borrowed(&[
+                russh::compression::ZLIB_LEGACY,
+                russh::compression::ZLIB,
+                russh::compression::NONE,
+            ]);
+        }
+        if let Some(prefs) = &self.config.algorithms {
+            if let Some(kex) = &prefs.kex {
+                config.preferred.kex = parse_algorithm_names::<russh::kex::Name>(kex, "kex")?.into();
+            }
+            if let Some(host_key) = &prefs.host_key {
+                config.preferred.key = host_key
+                    .iter()
+                    .map(|name| {
+                        name.parse::<russh::keys::Algorithm>().map_err(|_| {
+                            anyhow!("unknown host key algorithm {name:?}")
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .into();
+            }
+            if let Some(cipher) = &prefs.cipher {
+                config.preferred.cipher =
+                    parse_algorithm_names::<russh::cipher::Name>(cipher, "cipher")?.into();
+            }
+            if let Some(mac) = &prefs.mac {
+                config.preferred.mac =
+                    parse_algorithm_names::<russh::mac::Name>(mac, "mac")?.into();
+            }
+        }
+        Ok(Arc::new(config))
+    }
+
+    /// Connect to `key`, retrying transient transport failures up to
+    /// [`PoolConfig::connect_retries`] times with doubling backoff.
+    async fn establish(&self, key: &HostKey, auth: &AuthMethod) -> Result<Arc<SSHConnection>> {
+        // Joining an external master needs no transport, auth, or
+        // connect retries of our own; the check either answers or the
+        // master isn't there.
+        if let Some(template) = &self.config.control_path {
+            let conn = SSHConnection::via_control_socket(key.clone(), template).await?;
+            return Ok(Arc::new(
+                conn.with_max_output_bytes(self.config.max_output_bytes),
+            ));
+        }
+        if self.config.compression {
+            tracing::debug!("requesting ssh compression for {key}");
+        }
+        let client_config = self.client_config()?;
+        let mut backoff = CONNECT_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match SSHConnection::connect_with_socket_options(
+                key.clone(),
+                auth,
+                client_config.clone(),
+                &self.config.socket_options,
+            )
+            .await
+            {
+                Ok(conn) => {
+                    return Ok(Arc::new(
+                        conn.with_max_output_bytes(self.config.max_output_bytes),
+                    ))
+                }
+                Err(e) if attempt <= self.config.connect_retries
+                    && is_retryable_connect_error(&e) =>
+                {
+                    tracing::warn!(
+                        "connect to {key} failed on attempt {attempt}: {e:#}; \
+                         retrying in {backoff:?}"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Variant of [`exec`](Self::exec) with an explicit per-command
+    /// timeout; see [`SSHConnection::exec_with_timeout`].
+    pub async fn exec_with_timeout(
+        &self,
+        key: &HostKey,
+        auth: &AuthMethod,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<CommandOutput> {
+        let started = std::time::Instant::now();
+        let conn = self.checkout(key, auth).await?;
+        let result = conn
+            .exec_with_timeout(command, timeout, &CancellationToken::new())
+            .await;
+        conn.release().await;
+        self.audit_exec(key, command, started, &result);
+        result
+    }
+
+    /// Streaming variant of [`exec`](Self::exec); see
+    /// [`SSHConnection::exec_stream`].
+    pub async fn exec_stream(
+        &self,
+        key: &HostKey,
+        auth: &AuthMethod,
+        command: &str,
+    ) -> Result<tokio::sync::mpsc::UnboundedReceiver<StreamEvent>> {
+        let conn = self.checkout(key, auth).await?;
+        let started = std::time::Instant::now();
+        let result = conn.exec_stream(command).await;
+        conn.release().await;
+        let mut inner = result?;
+        // The exit code only exists once the stream finishes, so the
+        // audit record is written by a forwarding task that sees it.
+        let audit = self.audit.clone();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let key = key.clone();
+        let command = command.to_string();
+        tokio::spawn(async move {
+            let mut exit_code = None;
+            while let Some(event) = inner.recv().await {
+                if let StreamEvent::Exit(code) = &event {
+                    exit_code = Some(*code);
+                }
+                if tx.send(event).is_err() {
+                    break;
+                }
+            }
+            if let Some(log) = audit {
+                let mut record = crate::audit::AuditRecord::now(
+                    key.username.clone(),
+                    format!("{}:{}", key.host, key.port),
+                    command,
+                );
+                record.duration_ms = started.elapsed().as_millis() as u64;
+                record.exit_code = exit_code;
+                log.emit(record);
+            }
+        });
+        Ok(rx)
+    }
+
+    /// Pre-establish one pooled connection per host, with at most
+    /// `concurrency` handshakes in flight, so the first real command
+    /// against each host doesn't pay the connect cost.
+    ///
+    /// Each connection is checked out and immediately released, leaving
+    /// it idle in the pool. Failures are reported per host and don't
+    /// abort the remaining warmups.
+    pub async fn warmup(
+        &self,
+        keys: Vec<HostKey>,
+        auth: &AuthMethod,
+        concurrency: usize,
+    ) -> Vec<(HostKey, Result<()>)> {
+        futures::stream::iter(keys.into_iter().map(|key| async move {
+            let outcome = match self.checkout(&key, auth).await {
+                Ok(conn) => {
+                    conn.release().await;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+            (key, outcome)
+        }))
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await
+    }
+
+    /// Per-host snapshot of the pool, for metrics.
+    pub async fn stats(&self) -> Vec<PoolHostStats> {
+        self.connections
+            .lock()
+            .await
+            .iter()
+            .map(|(key, entry)| PoolHostStats {
+                host: key.clone(),
+                connections: entry.slots.len(),
+                in_use: entry
+                    .slots
+                    .iter()
+                    .filter(|s| s.in_use.load(Ordering::SeqCst))
+                    .count(),
+                alive: entry.slots.iter().all(|s| s.conn.is_alive()),
+                tags: entry.slots.iter().filter_map(|s| s.conn.tag()).collect(),
+            })
+            .collect()
+    }
+
+    /// Per-host timing and reuse metrics. Unlike
+    /// [`stats`](Self::stats), which snapshots the live slots, these
+    /// counters accumulate across connection churn.
+    pub fn detailed_stats(&self) -> HashMap<HostKey, ConnMetrics> {
+        self.metrics.lock().expect("pool metrics poisoned").clone()
+    }
+
+    /// Drop every pooled connection. Used on shutdown.
+    pub async fn clear(&self) {
+        self.connections.lock().await.clear();
+    }
+
+    /// Remove the slot holding `conn` from `key`'s entry, so the next
+    /// checkout can't be handed a connection known to be dead.
+    async fn evict(&self, key: &HostKey, conn: &Arc<SSHConnection>) {
+        let removed = {
+            let mut connections = self.connections.lock().await;
+            match connections.get_mut(key) {
+                Some(entry) => {
+                    let before = entry.slots.len();
+                    entry.slots.retain(|s| !Arc::ptr_eq(&s.conn, conn));
+                    before != entry.slots.len()
+                }
+                None => false,
+            }
+        };
+        if removed {
+            self.publish(Event::ConnectionReaped {
+                host: key.to_string(),
+            });
+        }
+    }
+
+    /// Disconnect every pooled connection and empty the pool, waiting
+    /// up to [`SHUTDOWN_DRAIN_TIMEOUT`] for checked-out ones to be
+    /// released first. Unlike [`clear`](Self::clear), which silently
+    /// drops the transports, each connection sends a protocol-level
+    /// disconnect, so remote sshds log a clean close instead of
+    /// keeping a half-open session until their own timeout fires.
+    pub async fn shutdown(&self) {
+        let entries: Vec<(HostKey, HostEntry)> = {
+            let mut connections = self.connections.lock().await;
+            connections.drain().collect()
+        };
+        let deadline = std::time::Instant::now() + SHUTDOWN_DRAIN_TIMEOUT;
+        for (key, entry) in entries {
+            for slot in entry.slots {
+                while slot.in_use.load(Ordering::SeqCst) && std::time::Instant::now() < deadline {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                if slot.in_use.load(Ordering::SeqCst) {
+                    tracing::warn!(
+                        "connection to {key} still checked out at shutdown; \
+                         disconnecting anyway"
+                    );
+                }
+                slot.conn.disconnect().await;
+                self.publish(Event::ConnectionReaped {
+                    host: key.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Run a batch of `(host, command)` pairs concurrently, collecting
+    /// per-target outcomes into a [`BulkResult`].
+    pub async fn exec_many(&self, targets: Vec<(HostKey, String)>, auth: &AuthMethod) -> BulkResult {
+        let futures = targets.into_iter().map(|(key, command)| async move {
+            let outcome = self
+                .exec(&key, auth, &command)
+                .await
+                .map_err(|e| BulkFailure::from_error(&e));
+            BulkEntry {
+                target: key,
+                command,
+                outcome,
+            }
+        });
+        BulkResult {
+            entries: futures::future::join_all(futures).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{Scripted, TestSshServer};
+    use super::*;
+
+    async fn connect(server: &TestSshServer) -> SSHConnection {
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        SSHConnection::connect(key, &AuthMethod::Password("secret".into()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn line_callback_stops_on_target_line() {
+        let server = TestSshServer::spawn(|_| {
+            Scripted::lines(&["starting", "warming up", "ready", "should not be seen"])
+        })
+        .await;
+        let conn = connect(&server).await;
+
+        let mut seen = Vec::new();
+        let output = conn
+            .exec_with_line_callback("service start", Duration::from_secs(5), |line| {
+                seen.push(line.to_string());
+                if line == "ready" {
+                    LineAction::Stop
+                } else {
+                    LineAction::Continue
+                }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(seen, vec!["starting", "warming up", "ready"]);
+        assert!(output.stdout_lossy().contains("ready"));
+        assert!(!seen.contains(&"should not be seen".to_string()));
+    }
+
+    #[test]
+    fn control_path_tokens_expand_like_openssh() {
+        let key = HostKey::new("db1.example", 2222, "ops");
+        assert_eq!(
+            expand_control_path(std::path::Path::new("/run/cm/%r@%h:%p.sock"), &key),
+            std::path::PathBuf::from("/run/cm/ops@db1.example:2222.sock")
+        );
+        // `%%` is literal; tokens only the master knows pass through.
+        assert_eq!(
+            expand_control_path(std::path::Path::new("/run/%%cm/%C"), &key),
+            std::path::PathBuf::from("/run/%cm/%C")
+        );
+    }
+
+    fn control_master_connection(socket: &str) -> SSHConnection {
+        SSHConnection {
+            transport: Transport::ControlMaster {
+                socket: socket.into(),
+            },
+            key: HostKey::new("db1.example", 22, "ops"),
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            tag: std::sync::Mutex::new(None),
+        }
+    }
+
+    #[tokio::test]
+    async fn control_master_refuses_operations_needing_a_direct_connection() {
+        let conn = control_master_connection("/run/cm/sock");
+        for err in [
+            conn.exec_stream("uptime").await.err(),
+            conn.exec_interactive("bash").await.err(),
+            conn.sftp().await.err(),
+        ] {
+            let err = err.expect("operation should be refused over a control socket");
+            assert!(err.to_string().contains("control socket"), "{err:#}");
+        }
+        // The socket's liveness belongs to the external master.
+        assert!(conn.is_alive());
+    }
+
+    #[tokio::test]
+    async fn joining_a_dead_control_socket_fails_up_front() {
+        let key = HostKey::new("db1.example", 22, "ops");
+        let err = match SSHConnection::via_control_socket(
+            key,
+            std::path::Path::new("/nonexistent/%r@%h.sock"),
+        )
+        .await
+        {
+            Ok(_) => panic!("a dead socket should not produce a connection"),
+            Err(e) => e,
+        };
+        assert!(
+            err.to_string().contains("ops@db1.example.sock"),
+            "error should name the expanded socket: {err:#}"
+        );
+    }
+
+    #[test]
+    fn host_key_parse_accepts_the_common_forms() {
+        let key = HostKey::parse("ops@db1.example:2222").unwrap();
+        assert_eq!(key, HostKey::new("db1.example", 2222, "ops"));
+
+        assert_eq!(
+            HostKey::parse("ssh://ops@db1.example:2222/").unwrap(),
+            HostKey::new("db1.example", 2222, "ops")
+        );
+        assert_eq!(
+            HostKey::parse("ops@db1.example").unwrap().port,
+            22,
+            "port should default"
+        );
+        assert_eq!(
+            HostKey::parse("admin@[::1]:2200").unwrap(),
+            HostKey::new("::1", 2200, "admin")
+        );
+        assert_eq!(
+            HostKey::parse("admin@fe80::1").unwrap(),
+            HostKey::new("fe80::1", 22, "admin")
+        );
+
+        // Omitted user falls back to whoever runs the process; in an
+        // environment with no user variables at all, that's a typed
+        // error rather than a guess.
+        let me = ["USER", "LOGNAME", "USERNAME"]
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()));
+        match me {
+            Some(me) => assert_eq!(HostKey::parse("db1.example").unwrap().username, me),
+            None => assert!(matches!(
+                HostKey::parse("db1.example")
+                    .unwrap_err()
+                    .downcast_ref::<HostKeyParseError>(),
+                Some(HostKeyParseError::NoUser(_))
+            )),
+        }
+    }
+
+    #[test]
+    fn host_key_parse_errors_name_the_offending_input() {
+        let err = HostKey::parse("ops@db1:notaport").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<HostKeyParseError>(),
+            Some(&HostKeyParseError::InvalidPort {
+                input: "ops@db1:notaport".to_string(),
+                port: "notaport".to_string(),
+            })
+        );
+
+        for bad in ["", "ssh://", "ops@", "a b", "ssh://host/path"] {
+            let err = HostKey::parse(bad).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<HostKeyParseError>(),
+                    Some(HostKeyParseError::MissingHost(input)) if input == bad
+                ),
+                "{bad:?}: {err}"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn directory_transfers_round_trip_over_sftp() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let server = TestSshServer::spawn(|_| Scripted::interactive()).await;
+        let conn = connect(&server).await;
+
+        // A tree with the awkward cases: a mode worth preserving, a
+        // nested file larger than one copy chunk, an empty directory,
+        // and a relative symlink.
+        let src = tempfile::tempdir().unwrap();
+        std::fs::write(src.path().join("config.toml"), b"alpha = 1\n").unwrap();
+        std::fs::set_permissions(
+            src.path().join("config.toml"),
+            std::fs::Permissions::from_mode(0o741),
+        )
+        .unwrap();
+        std::fs::create_dir(src.path().join("sub")).unwrap();
+        std::fs::write(src.path().join("sub/blob.bin"), vec![7u8; 100_000]).unwrap();
+        std::fs::create_dir(src.path().join("empty")).unwrap();
+        std::os::unix::fs::symlink("config.toml", src.path().join("link")).unwrap();
+
+        let remote = tempfile::tempdir().unwrap();
+        let remote_root = remote.path().join("pushed");
+        let remote_root = remote_root.to_str().unwrap();
+        let mut reported = Vec::new();
+        let summary = conn
+            .upload_dir_with_progress(src.path(), remote_root, |p| {
+                reported.push((p.path.clone(), p.bytes_copied));
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            TransferSummary { files: 2, dirs: 3, symlinks: 1, bytes: 100_010 }
+        );
+
+        let pushed = std::path::Path::new(remote_root);
+        assert_eq!(std::fs::read(pushed.join("config.toml")).unwrap(), b"alpha = 1\n");
+        assert_eq!(
+            std::fs::metadata(pushed.join("config.toml")).unwrap().permissions().mode() & 0o777,
+            0o741
+        );
+        assert!(pushed.join("empty").is_dir());
+        assert_eq!(
+            std::fs::read_link(pushed.join("link")).unwrap(),
+            std::path::PathBuf::from("config.toml")
+        );
+        // The big file reported incremental progress up to its size.
+        let blob: Vec<_> = reported
+            .iter()
+            .filter(|(p, _)| p == std::path::Path::new("sub/blob.bin"))
+            .collect();
+        assert!(blob.len() > 1, "no chunked progress: {reported:?}");
+        assert_eq!(blob.last().unwrap().1, 100_000);
+
+        let dest = tempfile::tempdir().unwrap();
+        let pulled = dest.path().join("pulled");
+        let summary = conn.download_dir(remote_root, &pulled).await.unwrap();
+        assert_eq!(
+            summary,
+            TransferSummary { files: 2, dirs: 3, symlinks: 1, bytes: 100_010 }
+        );
+        assert_eq!(
+            std::fs::read(pulled.join("sub/blob.bin")).unwrap(),
+            vec![7u8; 100_000]
+        );
+        assert_eq!(
+            std::fs::metadata(pulled.join("config.toml")).unwrap().permissions().mode() & 0o777,
+            0o741
+        );
+        assert!(pulled.join("empty").is_dir());
+        assert_eq!(
+            std::fs::read_link(pulled.join("link")).unwrap(),
+            std::path::PathBuf::from("config.toml")
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_checkouts_use_distinct_connections() {
+        let server = TestSshServer::spawn(|cmd| {
+            if cmd == "cmd-a" {
+                Scripted::lines(&["alpha-1", "alpha-2"])
+            } else {
+                Scripted::lines(&["beta-1", "beta-2"])
+            }
+        })
+        .await;
+        let pool = SSHPool::new();
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let a = pool.checkout(&key, &auth).await.unwrap();
+        let b = pool.checkout(&key, &auth).await.unwrap();
+        assert!(
+            !Arc::ptr_eq(&a.conn, &b.conn),
+            "second checkout reused the busy connection"
+        );
+
+        let (out_a, out_b) = tokio::join!(a.exec("cmd-a"), b.exec("cmd-b"));
+        let out_a = out_a.unwrap();
+        let out_b = out_b.unwrap();
+        assert!(out_a.stdout_lossy().contains("alpha-1"));
+        assert!(!out_a.stdout_lossy().contains("beta"));
+        assert!(out_b.stdout_lossy().contains("beta-2"));
+        assert!(!out_b.stdout_lossy().contains("alpha"));
+
+        a.release().await;
+        b.release().await;
+        let stats = pool.stats().await;
+        assert_eq!(stats[0].connections, 2);
+        assert_eq!(stats[0].in_use, 0);
+    }
+
+    #[tokio::test]
+    async fn shutdown_disconnects_and_empties_the_pool() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["ok"])).await;
+        let pool = SSHPool::new();
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let conn = pool.checkout(&key, &auth).await.unwrap();
+        conn.exec("cmd").await.unwrap();
+        conn.release().await;
+        assert_eq!(pool.stats().await[0].connections, 1);
+
+        pool.shutdown().await;
+        assert!(
+            pool.stats().await.is_empty(),
+            "pool still tracks hosts after shutdown"
+        );
+
+        // Shutdown left nothing behind; the next checkout starts a
+        // fresh connection rather than finding a closed one.
+        let conn = pool.checkout(&key, &auth).await.unwrap();
+        assert!(conn.is_alive());
+        conn.release().await;
+    }
+
+    #[tokio::test]
+    async fn a_connection_dying_mid_exec_is_retried_on_a_fresh_one() {
+        // The first exec gets its connection killed out from under it;
+        // every later one is answered normally.
+        let killed = Arc::new(AtomicBool::new(false));
+        let script_killed = killed.clone();
+        let server = TestSshServer::spawn(move |_| {
+            if script_killed.swap(true, Ordering::SeqCst) {
+                Scripted::lines(&["recovered"])
+            } else {
+                Scripted::kills_connection()
+            }
+        })
+        .await;
+        let pool = SSHPool::new();
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let output = pool
+            .exec(&key, &auth, "uptime")
+            .await
+            .expect("the transparent retry should have recovered");
+        assert_eq!(output.stdout_lossy().trim(), "recovered");
+        // The corpse was evicted; only the fresh connection remains.
+        let stats = pool.stats().await;
+        assert_eq!(stats[0].connections, 1);
+        assert!(stats[0].alive);
+    }
+
+    #[tokio::test]
+    async fn release_marks_the_connection_free_deterministically() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["ok"])).await;
+        let pool = SSHPool::new();
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let conn = pool.checkout(&key, &auth).await.unwrap();
+        let second = pool.checkout(&key, &auth).await.unwrap();
+        assert_eq!(pool.stats().await[0].in_use, 2);
+
+        conn.release().await;
+        assert_eq!(pool.stats().await[0].in_use, 1);
+        second.release().await;
+        assert_eq!(pool.stats().await[0].in_use, 0);
+    }
+
+    #[tokio::test]
+    async fn compression_enabled_pool_still_executes_commands() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["compressed-ok"])).await;
+        let pool = SSHPool::with_config(PoolConfig {
+            compression: true,
+            ..PoolConfig::default()
+        });
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        // The server may negotiate compression down to "none"; either
+        // way the session must come up and run commands.
+        let output = pool.exec(&key, &auth, "echo hi").await.unwrap();
+        assert!(output.stdout_lossy().contains("compressed-ok"));
+    }
+
+    #[tokio::test]
+    async fn output_past_the_cap_is_truncated_not_buffered() {
+        let server = TestSshServer::spawn(|_| Scripted {
+            // 16 KiB against a 1 KiB cap.
+            stdout: vec![vec![b'a'; 4096]; 4],
+            exit_status: 0,
+            chunk_delay: Duration::ZERO,
+            close_after_script: true,
+            drop_connection: false,
+        })
+        .await;
+        let pool = SSHPool::with_config(PoolConfig {
+            max_output_bytes: 1024,
+            ..PoolConfig::default()
+        });
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let output = pool.exec(&key, &auth, "yes").await.unwrap();
+        assert!(output.truncated);
+        assert_eq!(output.stdout.len(), 1024);
+        assert_eq!(output.exit_status, 0);
+    }
+
+    #[tokio::test]
+    async fn warmup_leaves_idle_connections_and_reports_per_host_failures() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["warm"])).await;
+        // An unbound port for the host that must fail to warm.
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_port = dead.local_addr().unwrap().port();
+        drop(dead);
+
+        let pool = SSHPool::with_config(PoolConfig {
+            connect_retries: 0,
+            ..PoolConfig::default()
+        });
+        let auth = AuthMethod::Password("secret".into());
+        let alice = HostKey::new("127.0.0.1", server.addr.port(), "alice");
+        let bob = HostKey::new("127.0.0.1", server.addr.port(), "bob");
+        let dead_key = HostKey::new("127.0.0.1", dead_port, "alice");
+
+        let outcomes = pool
+            .warmup(vec![alice.clone(), bob, dead_key.clone()], &auth, 2)
+            .await;
+        assert_eq!(outcomes.len(), 3);
+        for (key, outcome) in &outcomes {
+            assert_eq!(outcome.is_err(), *key == dead_key, "unexpected outcome for {key}");
+        }
+
+        // Both reachable hosts hold one idle connection; the next
+        // checkout reuses it instead of handshaking again.
+        let stats = pool.stats().await;
+        let warmed: Vec<_> = stats.iter().filter(|s| s.connections == 1).collect();
+        assert_eq!(warmed.len(), 2);
+        assert!(warmed.iter().all(|s| s.in_use == 0));
+        let output = pool.exec(&alice, &auth, "echo hi").await.unwrap();
+        assert!(output.stdout_lossy().contains("warm"));
+        assert_eq!(
+            pool.stats()
+                .await
+                .iter()
+                .find(|s| s.host == alice)
+                .unwrap()
+                .connections,
+            1
+        );
+    }
+
+    #[test]
+    fn shell_quote_neutralizes_metacharacters() {
+        assert_eq!(shell_quote("plain-word_1.txt"), "plain-word_1.txt");
+        assert_eq!(shell_quote("user@host:2222"), "user@host:2222");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("$(reboot)"), "'$(reboot)'");
+        assert_eq!(shell_quote("`id`"), "'`id`'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[tokio::test]
+    async fn shell_quote_round_trips_through_a_real_shell() {
+        let hostile = r#"pay"load 'quoted' $(touch /tmp/pwned) `id` \ * ; rm -rf"#;
+        let script = format!("printf '%s' {}", shell_quote(hostile));
+        let output = crate::local::run("sh", &["-c", &script], Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(output.stdout_lossy(), hostile);
+        assert!(output.success());
+    }
+
+    #[test]
+    fn pool_config_builder_validates_before_use() {
+        let config = PoolConfig::builder()
+            .max_connections_per_host(2)
+            .acquire_timeout(None)
+            .connect_retries(0)
+            .compression(true)
+            .max_output_bytes(1024)
+            .build()
+            .unwrap();
+        assert_eq!(config.max_connections_per_host, 2);
+        assert_eq!(config.acquire_timeout, None);
+        assert!(config.compression);
+
+        let err = PoolConfig::builder()
+            .max_connections_per_host(0)
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("max_connections_per_host"), "{err:#}");
+
+        let err = PoolConfig::builder()
+            .acquire_timeout(Some(Duration::ZERO))
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("acquire_timeout"), "{err:#}");
+
+        // A misspelled algorithm fails at build, not on first connect.
+        let err = PoolConfig::builder()
+            .algorithms(AlgorithmPrefs {
+                cipher: Some(vec!["rot13".to_string()]),
+                ..AlgorithmPrefs::default()
+            })
+            .build()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown cipher algorithm \"rot13\""),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn algorithm_prefs_restrict_the_handshake() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["ok"])).await;
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        // Both sides are russh, so restricting to one modern kex and
+        // cipher still negotiates.
+        let pool = SSHPool::with_config(PoolConfig {
+            algorithms: Some(AlgorithmPrefs {
+                kex: Some(vec!["curve25519-sha256".to_string()]),
+                cipher: Some(vec!["chacha20-poly1305@openssh.com".to_string()]),
+                ..AlgorithmPrefs::default()
+            }),
+            ..PoolConfig::default()
+        });
+        let output = pool.exec(&key, &auth, "echo hi").await.unwrap();
+        assert!(output.stdout_lossy().contains("ok"));
+
+        // A name russh doesn't know fails loudly instead of silently
+        // negotiating the defaults.
+        let pool = SSHPool::with_config(PoolConfig {
+            algorithms: Some(AlgorithmPrefs {
+                kex: Some(vec!["made-up-kex".to_string()]),
+                ..AlgorithmPrefs::default()
+            }),
+            ..PoolConfig::default()
+        });
+        let err = match pool.checkout(&key, &auth).await {
+            Ok(_) => panic!("checkout succeeded with an unknown kex algorithm"),
+            Err(e) => e,
+        };
+        assert!(
+            err.to_string().contains("unknown kex algorithm \"made-up-kex\""),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn detailed_stats_count_handshakes_and_reuses() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["ok"])).await;
+        let pool = SSHPool::new();
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        pool.checkout(&key, &auth).await.unwrap().release().await;
+        pool.checkout(&key, &auth).await.unwrap().release().await;
+
+        let metrics = &pool.detailed_stats()[&key];
+        assert_eq!(metrics.created, 1);
+        assert_eq!(metrics.reused, 1);
+        assert_eq!(metrics.handshake_samples.len(), 1);
+        assert_eq!(Some(metrics.total_handshake), metrics.last_handshake);
+        assert!((metrics.reuse_ratio() - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn checkout_tags_label_connections_in_stats() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["ok"])).await;
+        let pool = SSHPool::new();
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let conn = pool
+            .checkout_tagged(&key, &auth, Some("discovery"))
+            .await
+            .unwrap();
+        assert_eq!(pool.stats().await[0].tags, vec!["discovery".to_string()]);
+        conn.release().await;
+
+        // An untagged checkout of the same slot clears the stale label.
+        let conn = pool.checkout(&key, &auth).await.unwrap();
+        assert!(pool.stats().await[0].tags.is_empty());
+        conn.release().await;
+    }
+
+    #[tokio::test]
+    async fn tcp_connect_failures_carry_the_typed_transport_error() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let key = HostKey::new("127.0.0.1", port, "test");
+        let auth = AuthMethod::Password("secret".into());
+        let err = match SSHConnection::connect_with_socket_options(
+            key,
+            &auth,
+            Arc::new(client::Config::default()),
+            &SocketOptions::default(),
+        )
+        .await
+        {
+            Ok(_) => panic!("connect to a closed port succeeded"),
+            Err(e) => e,
+        };
+        // The refusal happens before the handshake; it must still be
+        // classified as retryable and name the failing stage.
+        assert!(is_retryable_connect_error(&err), "{err:#}");
+        assert!(format!("{err:#}").contains("tcp connect"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn transient_connect_failures_are_retried() {
+        // Bind then drop a listener: connecting to the freed port is
+        // refused, which counts as a retryable transport failure.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let pool = SSHPool::with_config(PoolConfig {
+            connect_retries: 2,
+            ..PoolConfig::default()
+        });
+        let key = HostKey::new("127.0.0.1", port, "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let started = std::time::Instant::now();
+        assert!(pool.checkout(&key, &auth).await.is_err());
+        // Two retries with 200ms + 400ms backoff must have elapsed.
+        assert!(
+            started.elapsed() >= Duration::from_millis(500),
+            "gave up after {:?} without retrying",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_rejection_fails_without_retrying() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["ok"])).await;
+        let pool = SSHPool::with_config(PoolConfig {
+            connect_retries: 5,
+            ..PoolConfig::default()
+        });
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("wrong".into());
+
+        let started = std::time::Instant::now();
+        let err = match pool.checkout(&key, &auth).await {
+            Ok(_) => panic!("checkout succeeded with a rejected password"),
+            Err(e) => e,
+        };
+        assert!(err.to_string().contains("auth"), "{err:#}");
+        assert!(
+            matches!(SshError::classify(&err), Some(SshError::AuthFailed)),
+            "auth rejection should classify as AuthFailed: {err:#}"
+        );
+        assert!(
+            started.elapsed() < CONNECT_BACKOFF,
+            "auth rejection was retried: {:?}",
+            started.elapsed()
+        );
+    }
+
+    #[tokio::test]
+    async fn checkout_waits_for_a_freed_slot_at_the_cap() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["ok"])).await;
+        let pool = SSHPool::with_config(PoolConfig {
+            max_connections_per_host: 1,
+            acquire_timeout: Some(Duration::from_secs(2)),
+            ..PoolConfig::default()
+        });
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let first = pool.checkout(&key, &auth).await.unwrap();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            first.release().await;
+        });
+
+        // Blocks until the spawned release frees the single slot.
+        let second = pool.checkout(&key, &auth).await.unwrap();
+        let stats = pool.stats().await;
+        assert_eq!(stats[0].connections, 1);
+        assert_eq!(stats[0].in_use, 1);
+        second.release().await;
+    }
+
+    #[tokio::test]
+    async fn checkout_times_out_when_no_slot_frees() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["ok"])).await;
+        let pool = SSHPool::with_config(PoolConfig {
+            max_connections_per_host: 1,
+            acquire_timeout: Some(Duration::from_millis(100)),
+            ..PoolConfig::default()
+        });
+        let key = HostKey::new("127.0.0.1", server.addr.port(), "test");
+        let auth = AuthMethod::Password("secret".into());
+
+        let held = pool.checkout(&key, &auth).await.unwrap();
+        let err = match pool.checkout(&key, &auth).await {
+            Ok(_) => panic!("checkout succeeded past the connection cap"),
+            Err(e) => e,
+        };
+        assert!(err.to_string().contains("pool exhausted"), "{err:#}");
+        held.release().await;
+    }
+
+    #[tokio::test]
+    async fn cancellation_frees_an_in_flight_command() {
+        let server = TestSshServer::spawn(|_| {
+            let lines: Vec<String> = (0..200).map(|i| format!("line {i}")).collect();
+            let refs: Vec<&str> = lines.iter().map(String::as_str).collect();
+            Scripted::lines(&refs)
+        })
+        .await;
+        let conn = connect(&server).await;
+
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            canceller.cancel();
+        });
+
+        let err = conn
+            .exec_cancellable("tail -f /var/log/syslog", &cancel)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn interactive_exec_feeds_stdin_and_streams_output() {
+        let server = TestSshServer::spawn(|_| Scripted::interactive()).await;
+        let conn = connect(&server).await;
+
+        let mut cmd = conn.exec_interactive("cat").await.unwrap();
+        cmd.write_stdin(b"hello interactive\n").unwrap();
+
+        let mut seen = Vec::new();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), cmd.recv())
+                .await
+                .expect("no echo before timeout");
+            match event {
+                Some(StreamEvent::Stdout(chunk)) => {
+                    seen.extend_from_slice(&chunk);
+                    if String::from_utf8_lossy(&seen).contains("hello interactive") {
+                        break;
+                    }
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        assert!(
+            String::from_utf8_lossy(&seen).contains("hello interactive"),
+            "echo not seen: {seen:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn exec_delimited_separates_commands_on_one_channel() {
+        let server = TestSshServer::spawn(|cmd| match cmd {
+            "sh" => Scripted::interactive(),
+            "first" => Scripted::lines(&["alpha", "beta"]),
+            _ => Scripted {
+                exit_status: 7,
+                ..Scripted::lines(&[])
+            },
+        })
+        .await;
+        let conn = connect(&server).await;
+        let mut shell = conn.exec_interactive("sh").await.unwrap();
+
+        let one = shell
+            .exec_delimited("first", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(one.exit_status, 0);
+        let text = one.stdout_lossy();
+        assert!(text.contains("alpha\nbeta"), "output: {text:?}");
+        // The echoed input still holds the literal `$?` marker; it
+        // must be part of the output, not mistaken for the sentinel.
+        assert!(text.contains("$?__"), "echo missing: {text:?}");
+
+        // A second command on the same channel sees only its own
+        // output and exit code.
+        let two = shell
+            .exec_delimited("second", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(two.exit_status, 7);
+        assert!(!two.stdout_lossy().contains("alpha"), "{:?}", two.stdout_lossy());
+    }
+
+    #[test]
+    fn sentinel_matching_skips_the_echoed_literal() {
+        let prefix = b"__REBE_DONE_abc_";
+        let buf = b"out\r\ncmd; echo __REBE_DONE_abc_$?__\r\nmore\n__REBE_DONE_abc_3__\r\ntail";
+        let (end, exit, resume) = find_sentinel(buf, prefix).expect("marker not found");
+        assert_eq!(exit, 3);
+        assert!(buf[..end].ends_with(b"more\n"), "{:?}", &buf[..end]);
+        assert_eq!(&buf[resume..], b"tail");
+
+        // Incomplete marker (digits still in flight) isn't matched.
+        assert!(find_sentinel(b"__REBE_DONE_abc_12", prefix).is_none());
+    }
+
+    #[tokio::test]
+    async fn exec_stream_delivers_chunks_incrementally() {
+        let server = TestSshServer::spawn(|_| Scripted::lines(&["one", "two", "three"])).await;
+        let conn = connect(&server).await;
+
+        let mut events = conn.exec_stream("cat file").await.unwrap();
+        let mut stdout_chunks = 0;
+        let mut exit = None;
+        while let Some(event) = events.recv().await {
+            match event {
+                StreamEvent::Stdout(_) => stdout_chunks += 1,
+                StreamEvent::Stderr(_) => {}
+                StreamEvent::Exit(status) => exit = Some(status),
+            }
+        }
+        // Each scripted line is written (and delayed) separately, so
+        // they must arrive as distinct chunks.
+        assert!(stdout_chunks >= 2, "got {stdout_chunks} chunks");
+        assert_eq!(exit, Some(0));
+    }
+}
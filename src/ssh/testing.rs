@@ -0,0 +1,484 @@
+//! In-process scripted SSH server used by the unit tests.
+//!
+//! Binds an ephemeral port, accepts any password, and answers exec
+//! requests from a caller-supplied script mapping command lines to
+//! output chunks and an exit status.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use russh::keys::ssh_key::Algorithm;
+use russh::keys::PrivateKey;
+use russh::server::{self, Auth, Msg, Server as _, Session};
+use russh::{Channel, ChannelId};
+
+/// The server's reply to one exec request.
+#[derive(Clone)]
+pub(crate) struct Scripted {
+    /// Data chunks written to the channel in order.
+    pub stdout: Vec<Vec<u8>>,
+    pub exit_status: u32,
+    /// Pause before each chunk, to exercise streaming behaviour.
+    pub chunk_delay: Duration,
+    /// Close the channel once the script is written; interactive
+    /// sessions leave it open and echo stdin instead.
+    pub close_after_script: bool,
+    /// Tear down the whole connection instead of answering, for
+    /// exercising mid-exec session death.
+    pub drop_connection: bool,
+}
+
+impl Scripted {
+    pub fn lines(lines: &[&str]) -> Self {
+        Self {
+            stdout: lines.iter().map(|l| format!("{l}\n").into_bytes()).collect(),
+            exit_status: 0,
+            chunk_delay: Duration::from_millis(5),
+            close_after_script: true,
+            drop_connection: false,
+        }
+    }
+
+    /// A reply that kills the connection out from under the client,
+    /// the way a crashed sshd or dropped NAT entry would.
+    pub fn kills_connection() -> Self {
+        Self {
+            drop_connection: true,
+            ..Self::lines(&[])
+        }
+    }
+
+    /// An open-ended session: no scripted output, stdin echoed back,
+    /// channel stays open until the client closes it.
+    pub fn interactive() -> Self {
+        Self {
+            stdout: Vec::new(),
+            exit_status: 0,
+            chunk_delay: Duration::ZERO,
+            close_after_script: false,
+            drop_connection: false,
+        }
+    }
+}
+
+type Script = Arc<dyn Fn(&str) -> Scripted + Send + Sync>;
+
+pub(crate) struct TestSshServer {
+    pub addr: SocketAddr,
+}
+
+impl TestSshServer {
+    /// Start a server answering exec requests with `script(command)`.
+    pub async fn spawn(script: impl Fn(&str) -> Scripted + Send + Sync + 'static) -> Self {
+        let config = Arc::new(server::Config {
+            auth_rejection_time: Duration::from_millis(1),
+            keys: vec![PrivateKey::random(&mut rand::rng(), Algorithm::Ed25519).unwrap()],
+            ..Default::default()
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut server = ScriptedServer {
+            script: Arc::new(script),
+            channels: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        tokio::spawn(async move {
+            while let Ok((socket, peer)) = listener.accept().await {
+                let handler = server.new_client(Some(peer));
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let _ = server::run_stream(config, socket, handler).await;
+                });
+            }
+        });
+
+        Self { addr }
+    }
+}
+
+#[derive(Clone)]
+struct ScriptedServer {
+    script: Script,
+    /// Session channels by id, parked until a subsystem request names
+    /// what runs on them (the SFTP tests take them back out here).
+    channels: Arc<Mutex<HashMap<ChannelId, Channel<Msg>>>>,
+}
+
+impl server::Server for ScriptedServer {
+    type Handler = Self;
+
+    fn new_client(&mut self, _peer: Option<SocketAddr>) -> Self {
+        self.clone()
+    }
+}
+
+impl server::Handler for ScriptedServer {
+    type Error = russh::Error;
+
+    async fn auth_password(&mut self, _user: &str, password: &str) -> Result<Auth, Self::Error> {
+        // Any password except "wrong" is accepted, so tests can
+        // exercise both auth outcomes.
+        if password == "wrong" {
+            return Ok(Auth::reject());
+        }
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        reply: server::ChannelOpenHandle,
+        _session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.channels
+            .lock()
+            .expect("channels poisoned")
+            .insert(channel.id(), channel);
+        reply.accept().await;
+        Ok(())
+    }
+
+    /// Serve the SFTP subsystem against the real filesystem, so the
+    /// transfer tests exercise full client/server round-trips over
+    /// temp directories.
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let channel = self
+            .channels
+            .lock()
+            .expect("channels poisoned")
+            .remove(&channel_id);
+        match (name, channel) {
+            ("sftp", Some(channel)) => {
+                session.channel_success(channel_id)?;
+                tokio::spawn(async move {
+                    russh_sftp::server::run(channel.into_stream(), SftpFsHandler::default()).await;
+                });
+            }
+            _ => session.channel_failure(channel_id)?,
+        }
+        Ok(())
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let command = String::from_utf8_lossy(data).into_owned();
+        let scripted = (self.script)(&command);
+        if scripted.drop_connection {
+            // Erroring out of the handler aborts the whole session,
+            // which the client sees as the transport vanishing.
+            return Err(russh::Error::Disconnect);
+        }
+        let handle = session.handle();
+
+        tokio::spawn(async move {
+            let _ = handle.channel_success(channel).await;
+            for chunk in scripted.stdout {
+                if !scripted.chunk_delay.is_zero() {
+                    tokio::time::sleep(scripted.chunk_delay).await;
+                }
+                if handle.data(channel, chunk).await.is_err() {
+                    // Client went away (e.g. stopped the command early).
+                    return;
+                }
+            }
+            if scripted.close_after_script {
+                let _ = handle.exit_status_request(channel, scripted.exit_status).await;
+                let _ = handle.eof(channel).await;
+                let _ = handle.close(channel).await;
+            }
+        });
+        Ok(())
+    }
+
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        _term: &str,
+        _col_width: u32,
+        _row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(russh::Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_success(channel)?;
+        Ok(())
+    }
+
+    /// Echo stdin back, so interactive tests can observe round-trips.
+    ///
+    /// Sentinel-delimited lines (`cmd; echo <marker>$?__`) are
+    /// additionally answered like a shell would: the script's output
+    /// for `cmd`, then the marker with the exit status expanded.
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.data(channel, data.to_vec())?;
+        let text = String::from_utf8_lossy(data).into_owned();
+        for line in text.lines() {
+            if let Some((command, marker)) = line
+                .strip_suffix("$?__")
+                .and_then(|rest| rest.rsplit_once("; echo "))
+            {
+                let scripted = (self.script)(command);
+                for chunk in scripted.stdout {
+                    session.data(channel, chunk)?;
+                }
+                session.data(
+                    channel,
+                    format!("{marker}{}__\n", scripted.exit_status).into_bytes(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Minimal SFTP server over the real filesystem: paths are used as the
+/// client sent them, so tests point transfers at temp directories.
+/// Only the operations the transfer code relies on are implemented.
+#[derive(Default)]
+struct SftpFsHandler {
+    handles: HashMap<String, FsHandle>,
+    next_handle: u64,
+}
+
+enum FsHandle {
+    File(std::fs::File),
+    /// A directory listing, served whole on the first readdir.
+    Dir {
+        entries: Vec<sftp::File>,
+        done: bool,
+    },
+}
+
+/// Protocol types, aliased to keep the handler readable.
+mod sftp {
+    pub(super) use russh_sftp::protocol::{
+        Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode,
+    };
+    pub(super) use russh_sftp::server::StatusReply;
+}
+
+/// Map an IO failure to the closest SFTP status.
+fn io_reply(err: &std::io::Error) -> sftp::StatusReply {
+    let code = match err.kind() {
+        std::io::ErrorKind::NotFound => sftp::StatusCode::NoSuchFile,
+        std::io::ErrorKind::PermissionDenied => sftp::StatusCode::PermissionDenied,
+        _ => sftp::StatusCode::Failure,
+    };
+    sftp::StatusReply::new(code).with_message(err.to_string())
+}
+
+fn ok_status(id: u32) -> sftp::Status {
+    sftp::Status {
+        id,
+        status_code: sftp::StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+/// Attributes of `path` itself (symlinks not followed), typed so the
+/// client's `file_type` is faithful.
+fn lstat_attrs(path: &std::path::Path) -> Result<sftp::FileAttributes, sftp::StatusReply> {
+    let meta = std::fs::symlink_metadata(path).map_err(|e| io_reply(&e))?;
+    Ok(sftp::FileAttributes::from(&meta))
+}
+
+impl SftpFsHandler {
+    fn store(&mut self, handle: FsHandle) -> String {
+        self.next_handle += 1;
+        let name = self.next_handle.to_string();
+        self.handles.insert(name.clone(), handle);
+        name
+    }
+
+    fn file(&mut self, handle: &str) -> Result<&mut std::fs::File, sftp::StatusReply> {
+        match self.handles.get_mut(handle) {
+            Some(FsHandle::File(file)) => Ok(file),
+            _ => Err(sftp::StatusCode::NoSuchFile.into()),
+        }
+    }
+}
+
+impl russh_sftp::server::Handler for SftpFsHandler {
+    type Error = sftp::StatusReply;
+
+    fn unimplemented(&self) -> Self::Error {
+        sftp::StatusCode::OpUnsupported.into()
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: sftp::OpenFlags,
+        _attrs: sftp::FileAttributes,
+    ) -> Result<sftp::Handle, Self::Error> {
+        let options: std::fs::OpenOptions = pflags.into();
+        let file = options.open(&filename).map_err(|e| io_reply(&e))?;
+        Ok(sftp::Handle {
+            id,
+            handle: self.store(FsHandle::File(file)),
+        })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<sftp::Status, Self::Error> {
+        self.handles.remove(&handle);
+        Ok(ok_status(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<sftp::Data, Self::Error> {
+        use std::io::{Read as _, Seek as _};
+        let file = self.file(&handle)?;
+        file.seek(std::io::SeekFrom::Start(offset))
+            .map_err(|e| io_reply(&e))?;
+        let mut data = vec![0u8; len as usize];
+        let n = file.read(&mut data).map_err(|e| io_reply(&e))?;
+        if n == 0 && len > 0 {
+            return Err(sftp::StatusCode::Eof.into());
+        }
+        data.truncate(n);
+        Ok(sftp::Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<sftp::Status, Self::Error> {
+        use std::io::{Seek as _, Write as _};
+        let file = self.file(&handle)?;
+        file.seek(std::io::SeekFrom::Start(offset))
+            .map_err(|e| io_reply(&e))?;
+        file.write_all(&data).map_err(|e| io_reply(&e))?;
+        Ok(ok_status(id))
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<sftp::Attrs, Self::Error> {
+        let meta = self.file(&handle)?.metadata().map_err(|e| io_reply(&e))?;
+        Ok(sftp::Attrs {
+            id,
+            attrs: sftp::FileAttributes::from(&meta),
+        })
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<sftp::Attrs, Self::Error> {
+        Ok(sftp::Attrs {
+            id,
+            attrs: lstat_attrs(std::path::Path::new(&path))?,
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<sftp::Attrs, Self::Error> {
+        let meta = std::fs::metadata(&path).map_err(|e| io_reply(&e))?;
+        Ok(sftp::Attrs {
+            id,
+            attrs: sftp::FileAttributes::from(&meta),
+        })
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: sftp::FileAttributes,
+    ) -> Result<sftp::Status, Self::Error> {
+        #[cfg(unix)]
+        if let Some(mode) = attrs.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o7777))
+                .map_err(|e| io_reply(&e))?;
+        }
+        Ok(ok_status(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<sftp::Handle, Self::Error> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&path).map_err(|e| io_reply(&e))? {
+            let entry = entry.map_err(|e| io_reply(&e))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            entries.push(sftp::File::new(name, lstat_attrs(&entry.path())?));
+        }
+        Ok(sftp::Handle {
+            id,
+            handle: self.store(FsHandle::Dir { entries, done: false }),
+        })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<sftp::Name, Self::Error> {
+        match self.handles.get_mut(&handle) {
+            Some(FsHandle::Dir { entries, done }) if !*done => {
+                *done = true;
+                Ok(sftp::Name {
+                    id,
+                    files: std::mem::take(entries),
+                })
+            }
+            Some(FsHandle::Dir { .. }) => Err(sftp::StatusCode::Eof.into()),
+            _ => Err(sftp::StatusCode::NoSuchFile.into()),
+        }
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: sftp::FileAttributes,
+    ) -> Result<sftp::Status, Self::Error> {
+        std::fs::create_dir(&path).map_err(|e| io_reply(&e))?;
+        Ok(ok_status(id))
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<sftp::Name, Self::Error> {
+        Ok(sftp::Name {
+            id,
+            files: vec![sftp::File::dummy(path)],
+        })
+    }
+
+    async fn symlink(
+        &mut self,
+        id: u32,
+        linkpath: String,
+        targetpath: String,
+    ) -> Result<sftp::Status, Self::Error> {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&targetpath, &linkpath).map_err(|e| io_reply(&e))?;
+        #[cfg(not(unix))]
+        let _ = (linkpath, targetpath);
+        Ok(ok_status(id))
+    }
+
+    async fn readlink(&mut self, id: u32, path: String) -> Result<sftp::Name, Self::Error> {
+        let target = std::fs::read_link(&path).map_err(|e| io_reply(&e))?;
+        Ok(sftp::Name {
+            id,
+            files: vec![sftp::File::dummy(target.to_string_lossy().into_owned())],
+        })
+    }
+}
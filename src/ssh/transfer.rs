@@ -0,0 +1,439 @@
+//! Recursive directory transfer over SFTP.
+//!
+//! Walks a local tree and mirrors it onto the remote host (or the
+//! reverse), preserving file modes, recreating symlinks, and creating
+//! empty directories. Used by provisioning workflows that would
+//! otherwise shell out to `scp -r`.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use russh_sftp::client::SftpSession;
+use russh_sftp::protocol::{FileAttributes, FileType, OpenFlags};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::SSHConnection;
+
+/// Copy unit for file contents; also the granularity of progress
+/// callbacks.
+const COPY_CHUNK: usize = 32 * 1024;
+
+/// One progress report during a recursive transfer. Reported once per
+/// chunk while a file's contents copy, and once per directory or
+/// symlink when it is created.
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+    /// Path of the entry, relative to the root being transferred.
+    pub path: PathBuf,
+    /// Bytes of this file copied so far; zero for non-files.
+    pub bytes_copied: u64,
+    /// Total size of this file; zero for non-files.
+    pub file_size: u64,
+}
+
+/// Totals for one completed recursive transfer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransferSummary {
+    pub files: u64,
+    /// Directories created, the transferred root included.
+    pub dirs: u64,
+    pub symlinks: u64,
+    /// File content bytes copied.
+    pub bytes: u64,
+}
+
+/// The permission bits of a local entry, for mirroring to the remote
+/// side; `None` where the platform has no POSIX modes.
+fn local_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Attributes carrying only permission bits, for setstat.
+fn mode_attrs(mode: u32) -> FileAttributes {
+    FileAttributes {
+        permissions: Some(mode & 0o7777),
+        ..FileAttributes::default()
+    }
+}
+
+/// Create `path` remotely, accepting a directory that already exists
+/// so transfers into a prepared target (or retries) succeed.
+async fn ensure_remote_dir(sftp: &SftpSession, path: &str) -> Result<()> {
+    match sftp.create_dir(path).await {
+        Ok(()) => Ok(()),
+        Err(create_err) => match sftp.metadata(path).await {
+            Ok(meta) if meta.is_dir() => Ok(()),
+            _ => Err(anyhow::Error::new(create_err)
+                .context(format!("creating remote directory {path}"))),
+        },
+    }
+}
+
+/// The file name of a local entry as a `String`, which SFTP paths
+/// require.
+fn utf8_name(entry: &tokio::fs::DirEntry) -> Result<String> {
+    entry.file_name().into_string().map_err(|name| {
+        anyhow!(
+            "file name {:?} in {} is not valid UTF-8",
+            name,
+            entry.path().parent().unwrap_or(Path::new("")).display()
+        )
+    })
+}
+
+impl SSHConnection {
+    /// Open an SFTP session on a fresh channel of this connection.
+    /// Channels multiplex, so commands keep running while it is open.
+    pub async fn sftp(&self) -> Result<SftpSession> {
+        let channel = self
+            .direct()?
+            .channel_open_session()
+            .await
+            .with_context(|| format!("opening sftp channel to {} failed", self.key))?;
+        channel
+            .request_subsystem(true, "sftp")
+            .await
+            .with_context(|| format!("{} refused the sftp subsystem", self.key))?;
+        SftpSession::new(channel.into_stream())
+            .await
+            .with_context(|| format!("sftp handshake with {} failed", self.key))
+    }
+
+    /// Recursively upload the directory `local` to `remote` on the
+    /// host, preserving file modes and recreating symlinks and empty
+    /// directories. `remote` is created if missing and merged into if
+    /// it already exists.
+    pub async fn upload_dir(&self, local: &Path, remote: &str) -> Result<TransferSummary> {
+        self.upload_dir_with_progress(local, remote, |_| {}).await
+    }
+
+    /// Like [`upload_dir`](Self::upload_dir), reporting progress per
+    /// copied chunk and created entry.
+    #[tracing::instrument(skip_all, fields(host = %self.key.host, local = %local.display(), remote))]
+    pub async fn upload_dir_with_progress(
+        &self,
+        local: &Path,
+        remote: &str,
+        mut progress: impl FnMut(&TransferProgress),
+    ) -> Result<TransferSummary> {
+        let root_meta = tokio::fs::symlink_metadata(local)
+            .await
+            .with_context(|| format!("reading {} failed", local.display()))?;
+        if !root_meta.is_dir() {
+            bail!("{} is not a directory", local.display());
+        }
+
+        let sftp = self.sftp().await?;
+        let mut summary = TransferSummary::default();
+        // Directory modes are applied bottom-up after the walk: a
+        // read-only directory set too early would refuse its own
+        // contents.
+        let mut dir_modes: Vec<(String, u32)> = Vec::new();
+
+        ensure_remote_dir(&sftp, remote).await?;
+        summary.dirs += 1;
+        if let Some(mode) = local_mode(&root_meta) {
+            dir_modes.push((remote.to_string(), mode));
+        }
+
+        let mut pending = VecDeque::new();
+        pending.push_back((local.to_path_buf(), remote.to_string()));
+        while let Some((dir, remote_dir)) = pending.pop_front() {
+            let mut entries = Vec::new();
+            let mut reader = tokio::fs::read_dir(&dir)
+                .await
+                .with_context(|| format!("listing {} failed", dir.display()))?;
+            while let Some(entry) = reader.next_entry().await? {
+                entries.push(entry);
+            }
+            entries.sort_by_key(|e| e.file_name());
+
+            for entry in entries {
+                let name = utf8_name(&entry)?;
+                let remote_path = format!("{remote_dir}/{name}");
+                let rel = entry
+                    .path()
+                    .strip_prefix(local)
+                    .expect("entry escaped the transfer root")
+                    .to_path_buf();
+                let meta = entry
+                    .metadata()
+                    .await
+                    .with_context(|| format!("reading {} failed", entry.path().display()))?;
+
+                if meta.file_type().is_symlink() {
+                    let target = tokio::fs::read_link(entry.path())
+                        .await
+                        .with_context(|| format!("reading link {} failed", entry.path().display()))?;
+                    let target = target
+                        .to_str()
+                        .ok_or_else(|| anyhow!("link target of {rel:?} is not valid UTF-8"))?;
+                    sftp.symlink(&remote_path, target)
+                        .await
+                        .with_context(|| format!("creating remote symlink {remote_path}"))?;
+                    summary.symlinks += 1;
+                    progress(&TransferProgress { path: rel, bytes_copied: 0, file_size: 0 });
+                } else if meta.is_dir() {
+                    ensure_remote_dir(&sftp, &remote_path).await?;
+                    summary.dirs += 1;
+                    if let Some(mode) = local_mode(&meta) {
+                        dir_modes.push((remote_path.clone(), mode));
+                    }
+                    progress(&TransferProgress { path: rel, bytes_copied: 0, file_size: 0 });
+                    pending.push_back((entry.path(), remote_path));
+                } else {
+                    let copied = upload_file(&sftp, &entry.path(), &remote_path, &rel, &mut progress)
+                        .await?;
+                    if let Some(mode) = local_mode(&meta) {
+                        sftp.set_metadata(&remote_path, mode_attrs(mode))
+                            .await
+                            .with_context(|| format!("setting mode on {remote_path}"))?;
+                    }
+                    summary.files += 1;
+                    summary.bytes += copied;
+                }
+            }
+        }
+
+        for (path, mode) in dir_modes.into_iter().rev() {
+            sftp.set_metadata(&path, mode_attrs(mode))
+                .await
+                .with_context(|| format!("setting mode on {path}"))?;
+        }
+        let _ = sftp.close().await;
+        Ok(summary)
+    }
+
+    /// Recursively download the directory `remote` into `local`,
+    /// preserving file modes and recreating symlinks and empty
+    /// directories. `local` is created if missing.
+    pub async fn download_dir(&self, remote: &str, local: &Path) -> Result<TransferSummary> {
+        self.download_dir_with_progress(remote, local, |_| {}).await
+    }
+
+    /// Like [`download_dir`](Self::download_dir), reporting progress
+    /// per copied chunk and created entry.
+    #[tracing::instrument(skip_all, fields(host = %self.key.host, remote, local = %local.display()))]
+    pub async fn download_dir_with_progress(
+        &self,
+        remote: &str,
+        local: &Path,
+        mut progress: impl FnMut(&TransferProgress),
+    ) -> Result<TransferSummary> {
+        let sftp = self.sftp().await?;
+        let root_meta = sftp
+            .metadata(remote)
+            .await
+            .with_context(|| format!("reading remote {remote} failed"))?;
+        if !root_meta.is_dir() {
+            bail!("remote {remote} is not a directory");
+        }
+
+        let mut summary = TransferSummary::default();
+        let mut dir_modes: Vec<(PathBuf, u32)> = Vec::new();
+        tokio::fs::create_dir_all(local)
+            .await
+            .with_context(|| format!("creating {} failed", local.display()))?;
+        summary.dirs += 1;
+        if let Some(mode) = root_meta.permissions {
+            dir_modes.push((local.to_path_buf(), mode));
+        }
+
+        let mut pending = VecDeque::new();
+        pending.push_back((remote.to_string(), local.to_path_buf()));
+        while let Some((remote_dir, dir)) = pending.pop_front() {
+            for entry in sftp
+                .read_dir(&remote_dir)
+                .await
+                .with_context(|| format!("listing remote {remote_dir} failed"))?
+            {
+                let name = entry.file_name();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let remote_path = format!("{remote_dir}/{name}");
+                let local_path = dir.join(&name);
+                let rel = PathBuf::from(
+                    remote_path
+                        .strip_prefix(remote)
+                        .unwrap_or(&remote_path)
+                        .trim_start_matches('/'),
+                );
+
+                match entry.file_type() {
+                    FileType::Symlink => {
+                        let target = sftp
+                            .read_link(&remote_path)
+                            .await
+                            .with_context(|| format!("reading remote link {remote_path}"))?;
+                        make_symlink(&target, &local_path)?;
+                        summary.symlinks += 1;
+                        progress(&TransferProgress { path: rel, bytes_copied: 0, file_size: 0 });
+                    }
+                    FileType::Dir => {
+                        tokio::fs::create_dir_all(&local_path)
+                            .await
+                            .with_context(|| format!("creating {} failed", local_path.display()))?;
+                        summary.dirs += 1;
+                        if let Some(mode) = entry.metadata().permissions {
+                            dir_modes.push((local_path.clone(), mode));
+                        }
+                        progress(&TransferProgress { path: rel, bytes_copied: 0, file_size: 0 });
+                        pending.push_back((remote_path, local_path));
+                    }
+                    _ => {
+                        let copied =
+                            download_file(&sftp, &remote_path, &local_path, &rel, &mut progress)
+                                .await?;
+                        if let Some(mode) = entry.metadata().permissions {
+                            set_local_mode(&local_path, mode)?;
+                        }
+                        summary.files += 1;
+                        summary.bytes += copied;
+                    }
+                }
+            }
+        }
+
+        for (path, mode) in dir_modes.into_iter().rev() {
+            set_local_mode(&path, mode)?;
+        }
+        let _ = sftp.close().await;
+        Ok(summary)
+    }
+}
+
+/// Copy one local file to the remote path, reporting per-chunk
+/// progress; returns the bytes copied.
+async fn upload_file(
+    sftp: &SftpSession,
+    local: &Path,
+    remote: &str,
+    rel: &Path,
+    progress: &mut impl FnMut(&TransferProgress),
+) -> Result<u64> {
+    let mut src = tokio::fs::File::open(local)
+        .await
+        .with_context(|| format!("opening {} failed", local.display()))?;
+    let file_size = src.metadata().await.map(|m| m.len()).unwrap_or(0);
+    let mut dst = sftp
+        .open_with_flags(
+            remote,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        )
+        .await
+        .with_context(|| format!("creating remote file {remote}"))?;
+
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let mut copied = 0u64;
+    loop {
+        let n = src
+            .read(&mut buf)
+            .await
+            .with_context(|| format!("reading {} failed", local.display()))?;
+        if n == 0 {
+            break;
+        }
+        dst.write_all(&buf[..n])
+            .await
+            .with_context(|| format!("writing remote file {remote}"))?;
+        copied += n as u64;
+        progress(&TransferProgress {
+            path: rel.to_path_buf(),
+            bytes_copied: copied,
+            file_size,
+        });
+    }
+    dst.shutdown()
+        .await
+        .with_context(|| format!("closing remote file {remote}"))?;
+    Ok(copied)
+}
+
+/// Copy one remote file to the local path, reporting per-chunk
+/// progress; returns the bytes copied.
+async fn download_file(
+    sftp: &SftpSession,
+    remote: &str,
+    local: &Path,
+    rel: &Path,
+    progress: &mut impl FnMut(&TransferProgress),
+) -> Result<u64> {
+    let mut src = sftp
+        .open(remote)
+        .await
+        .with_context(|| format!("opening remote file {remote}"))?;
+    let file_size = src.metadata().await.ok().and_then(|m| m.size).unwrap_or(0);
+    let mut dst = tokio::fs::File::create(local)
+        .await
+        .with_context(|| format!("creating {} failed", local.display()))?;
+
+    let mut buf = vec![0u8; COPY_CHUNK];
+    let mut copied = 0u64;
+    loop {
+        let n = src
+            .read(&mut buf)
+            .await
+            .with_context(|| format!("reading remote file {remote}"))?;
+        if n == 0 {
+            break;
+        }
+        dst.write_all(&buf[..n])
+            .await
+            .with_context(|| format!("writing {} failed", local.display()))?;
+        copied += n as u64;
+        progress(&TransferProgress {
+            path: rel.to_path_buf(),
+            bytes_copied: copied,
+            file_size,
+        });
+    }
+    dst.flush()
+        .await
+        .with_context(|| format!("writing {} failed", local.display()))?;
+    Ok(copied)
+}
+
+/// Recreate a symlink locally; platforms without symlinks skip it with
+/// a warning rather than failing the whole transfer.
+fn make_symlink(target: &str, link: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+            .with_context(|| format!("creating symlink {} failed", link.display()))
+    }
+    #[cfg(not(unix))]
+    {
+        tracing::warn!(
+            "skipping symlink {} -> {target}: unsupported on this platform",
+            link.display()
+        );
+        Ok(())
+    }
+}
+
+/// Apply remote permission bits to a local entry, where the platform
+/// has POSIX modes.
+fn set_local_mode(path: &Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))
+            .with_context(|| format!("setting mode on {} failed", path.display()))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+        Ok(())
+    }
+}
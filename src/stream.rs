@@ -0,0 +1,558 @@
+//! Bounded buffering of streamed command output.
+//!
+//! Remote commands can produce arbitrarily large (or infinite) output;
+//! [`StreamingOutputHandler`] accumulates it chunk by chunk while
+//! enforcing a size bound, so a runaway command cannot OOM the backend.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// A process-wide cap on bytes buffered across handlers.
+///
+/// Each handler bounds itself via `max_size`, but thousands of
+/// concurrent sessions would still add up without bound. Handlers
+/// constructed against a shared budget (see
+/// [`StreamingOutputHandler::with_budget`]) charge it on every push
+/// and release on drain or drop, giving one knob for total memory
+/// devoted to output buffering.
+#[derive(Debug)]
+pub struct StreamBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl StreamBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Bytes currently charged across all sharing handlers.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Reserve `bytes`, failing without side effect when the budget
+    /// would be exceeded.
+    fn try_charge(&self, bytes: usize) -> bool {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let next = match used.checked_add(bytes) {
+                Some(next) if next <= self.limit => next,
+                _ => return false,
+            };
+            match self
+                .used
+                .compare_exchange_weak(used, next, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// What `push_chunk` does once `max_size` would be exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OverflowMode {
+    /// Refuse the chunk and return an error (the default).
+    Error,
+    /// Evict the oldest chunks until the new one fits.
+    Ring,
+    /// Keep the first `max_size` bytes and silently drop the rest,
+    /// remembering that truncation happened.
+    Truncate,
+}
+
+/// Accumulates streamed output chunks under a total size bound.
+#[derive(Debug)]
+pub struct StreamingOutputHandler {
+    chunks: VecDeque<Vec<u8>>,
+    buffered: usize,
+    max_size: usize,
+    mode: OverflowMode,
+    high_water_mark: Option<usize>,
+    /// Set once a truncating handler has dropped bytes.
+    truncated: bool,
+    /// Shared cap this handler charges in addition to `max_size`.
+    budget: Option<Arc<StreamBudget>>,
+}
+
+impl StreamingOutputHandler {
+    /// A handler that errors when more than `max_size` bytes are pushed.
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            chunks: VecDeque::new(),
+            buffered: 0,
+            max_size,
+            mode: OverflowMode::Error,
+            high_water_mark: None,
+            truncated: false,
+            budget: None,
+        }
+    }
+
+    /// A handler that keeps only the most recent `max_size` bytes,
+    /// evicting the oldest chunks on overflow instead of erroring.
+    ///
+    /// Suited to scrollback-style "last N bytes" views of streams with
+    /// no natural end.
+    pub fn new_ring(max_size: usize) -> Self {
+        let mut handler = Self::new(max_size);
+        handler.mode = OverflowMode::Ring;
+        handler
+    }
+
+    /// A handler that keeps the first `max_size` bytes and silently
+    /// drops the rest, flagging the loss via
+    /// [`truncated`](Self::truncated).
+    ///
+    /// Suited to capturing command output where the head is what
+    /// matters and a runaway `yes` must not grow the buffer.
+    pub fn new_truncating(max_size: usize) -> Self {
+        let mut handler = Self::new(max_size);
+        handler.mode = OverflowMode::Truncate;
+        handler
+    }
+
+    /// Charge buffered bytes against `budget` as well as `max_size`:
+    /// [`push_chunk`](Self::push_chunk) fails once the shared budget is
+    /// exhausted, whatever the handler's own overflow mode. Bytes are
+    /// released back on drain, finalize, or drop.
+    pub fn with_budget(mut self, budget: Arc<StreamBudget>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Grow the buffer by `bytes`, charging the shared budget first
+    /// when one is attached.
+    fn charge(&mut self, bytes: usize) -> Result<()> {
+        if let Some(budget) = &self.budget {
+            if !budget.try_charge(bytes) {
+                bail!(
+                    "global stream budget of {} bytes exhausted",
+                    budget.limit()
+                );
+            }
+        }
+        self.buffered += bytes;
+        Ok(())
+    }
+
+    /// Shrink the buffer by `bytes`, releasing them to the budget.
+    fn credit(&mut self, bytes: usize) {
+        if let Some(budget) = &self.budget {
+            budget.release(bytes);
+        }
+        self.buffered -= bytes;
+    }
+
+    /// Total bytes currently buffered.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Whether a truncating handler has dropped any bytes.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Append a chunk, applying the handler's overflow policy.
+    pub fn push_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        if self.buffered + chunk.len() > self.max_size {
+            match self.mode {
+                OverflowMode::Error => bail!(
+                    "output exceeds maximum size of {} bytes",
+                    self.max_size
+                ),
+                OverflowMode::Truncate => {
+                    self.truncated = true;
+                    let room = self.max_size - self.buffered;
+                    if room > 0 {
+                        self.charge(room)?;
+                        self.chunks.push_back(chunk[..room].to_vec());
+                    }
+                    return Ok(());
+                }
+                OverflowMode::Ring => {
+                    while self.buffered + chunk.len() > self.max_size {
+                        match self.chunks.pop_front() {
+                            Some(evicted) => self.credit(evicted.len()),
+                            // A single chunk larger than max_size: keep
+                            // its tail, consistent with "most recent
+                            // bytes win".
+                            None => {
+                                let tail = &chunk[chunk.len() - self.max_size..];
+                                self.charge(tail.len())?;
+                                self.chunks.push_back(tail.to_vec());
+                                return Ok(());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.charge(chunk.len())?;
+        self.chunks.push_back(chunk.to_vec());
+        Ok(())
+    }
+
+    /// The buffered bytes, concatenated, without consuming the handler.
+    ///
+    /// Used for scrollback snapshots, where the buffer keeps filling
+    /// after the read.
+    pub fn contents(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.buffered);
+        for chunk in &self.chunks {
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    /// Buffered-byte count at which [`push_chunk_to`](Self::push_chunk_to)
+    /// drains to its sink before appending.
+    pub fn with_high_water_mark(mut self, bytes: usize) -> Self {
+        self.high_water_mark = Some(bytes);
+        self
+    }
+
+    /// Write all buffered chunks to `writer` and drop them.
+    ///
+    /// Chunks pushed afterwards accumulate again, so a relay loop can
+    /// alternate `push_chunk` / `drain_to` to forward output of any
+    /// total size with bounded memory.
+    pub async fn drain_to<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<()> {
+        while let Some(chunk) = self.chunks.pop_front() {
+            self.credit(chunk.len());
+            writer.write_all(&chunk).await?;
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Append a chunk, draining to `writer` first whenever the
+    /// high-water mark (see [`with_high_water_mark`](Self::with_high_water_mark))
+    /// would be crossed.
+    pub async fn push_chunk_to<W: AsyncWrite + Unpin>(
+        &mut self,
+        chunk: &[u8],
+        writer: &mut W,
+    ) -> Result<()> {
+        if let Some(mark) = self.high_water_mark {
+            if self.buffered + chunk.len() > mark {
+                self.drain_to(writer).await?;
+            }
+        }
+        self.push_chunk(chunk)
+    }
+
+    /// Consume the handler and return the buffered bytes, releasing
+    /// them to the shared budget if one is attached.
+    pub fn finalize(mut self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.buffered);
+        for chunk in std::mem::take(&mut self.chunks) {
+            out.extend_from_slice(&chunk);
+        }
+        out
+    }
+
+    /// Like [`finalize`](Self::finalize), but validating the output as
+    /// UTF-8.
+    pub fn finalize_string(self) -> Result<String> {
+        String::from_utf8(self.finalize()).map_err(|e| anyhow::anyhow!("output is not UTF-8: {e}"))
+    }
+
+    /// Consume the handler and decode the buffered bytes from
+    /// `encoding` to UTF-8, with unmappable sequences replaced by
+    /// U+FFFD.
+    ///
+    /// For hosts whose locale isn't UTF-8 (Shift-JIS, Latin-1):
+    /// forcing [`finalize_string`](Self::finalize_string) on their
+    /// output mangles every non-ASCII byte.
+    pub fn finalize_with_encoding(self, encoding: &'static encoding_rs::Encoding) -> String {
+        let bytes = self.finalize();
+        let (text, _, _) = encoding.decode(&bytes);
+        text.into_owned()
+    }
+
+    /// Like [`finalize_string`](Self::finalize_string), but replacing
+    /// invalid UTF-8 with U+FFFD instead of failing.
+    ///
+    /// Terminal capture regularly contains the odd binary or Latin-1
+    /// byte (`hexdump`, a misconfigured locale); losing the entire
+    /// capture over one stray byte is worse than a replacement char.
+    pub fn finalize_string_lossy(self) -> String {
+        String::from_utf8(self.finalize())
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+    }
+}
+
+impl Drop for StreamingOutputHandler {
+    /// A dropped handler gives its buffered bytes back to the shared
+    /// budget, so closed sessions free global headroom immediately.
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(self.buffered);
+        }
+    }
+}
+
+/// Remove ANSI escape sequences (CSI/SGR, OSC, two-byte escapes) from
+/// raw terminal output, leaving the printable bytes.
+pub fn strip_ansi(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != 0x1b {
+            out.push(input[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        match input.get(i) {
+            // CSI: parameter/intermediate bytes, then one final byte.
+            Some(b'[') => {
+                i += 1;
+                while i < input.len() && (0x20..=0x3f).contains(&input[i]) {
+                    i += 1;
+                }
+                if i < input.len() {
+                    i += 1;
+                }
+            }
+            // OSC: runs until BEL or ST (ESC \).
+            Some(b']') => {
+                i += 1;
+                while i < input.len() {
+                    if input[i] == 0x07 {
+                        i += 1;
+                        break;
+                    }
+                    if input[i] == 0x1b && input.get(i + 1) == Some(&b'\\') {
+                        i += 2;
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            // Two-byte escape (ESC c, ESC 7, ...).
+            Some(_) => i += 1,
+            None => {}
+        }
+    }
+    out
+}
+
+impl StreamingOutputHandler {
+    /// Consume the handler and return a plain-text rendering: escape
+    /// sequences stripped and carriage-return overwrites resolved, so
+    /// progress bars collapse to their final state.
+    ///
+    /// Intended for storing searchable logs of sessions rather than a
+    /// faithful terminal replay.
+    pub fn finalize_plain(self) -> Result<String> {
+        let stripped = strip_ansi(&self.finalize());
+        let text = String::from_utf8(stripped)
+            .map_err(|e| anyhow::anyhow!("output is not UTF-8: {e}"))?;
+        // CRLF is a line terminator, not an overwrite.
+        let text = text.replace("\r\n", "\n");
+        let mut out = String::new();
+        for (idx, line) in text.split('\n').enumerate() {
+            if idx > 0 {
+                out.push('\n');
+            }
+            // Within a line, only what follows the last '\r' survives.
+            out.push_str(line.rsplit('\r').next().unwrap_or(line));
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_mode_errors_on_overflow() {
+        let mut handler = StreamingOutputHandler::new(8);
+        handler.push_chunk(b"12345").unwrap();
+        assert!(handler.push_chunk(b"6789").is_err());
+    }
+
+    #[test]
+    fn truncating_mode_keeps_the_head_and_flags_the_loss() {
+        let mut handler = StreamingOutputHandler::new_truncating(8);
+        handler.push_chunk(b"12345").unwrap();
+        assert!(!handler.truncated());
+        // Partially fits: the overflowing tail is dropped.
+        handler.push_chunk(b"6789").unwrap();
+        assert!(handler.truncated());
+        // Entirely over budget: dropped outright.
+        handler.push_chunk(b"more").unwrap();
+        assert_eq!(handler.buffered(), 8);
+        assert_eq!(handler.finalize(), b"12345678");
+    }
+
+    #[test]
+    fn ring_mode_keeps_most_recent_bytes() {
+        let mut handler = StreamingOutputHandler::new_ring(8);
+        handler.push_chunk(b"aaaa").unwrap();
+        handler.push_chunk(b"bbbb").unwrap();
+        // Overflows: the oldest chunk is evicted.
+        handler.push_chunk(b"cccc").unwrap();
+        assert_eq!(handler.buffered(), 8);
+        assert_eq!(handler.finalize(), b"bbbbcccc");
+    }
+
+    #[tokio::test]
+    async fn drain_to_writes_and_clears_buffered_chunks() {
+        let mut handler = StreamingOutputHandler::new(64);
+        handler.push_chunk(b"hello ").unwrap();
+        handler.push_chunk(b"world").unwrap();
+
+        let mut sink = Vec::new();
+        handler.drain_to(&mut sink).await.unwrap();
+        assert_eq!(sink, b"hello world");
+        assert_eq!(handler.buffered(), 0);
+
+        handler.push_chunk(b"!").unwrap();
+        assert_eq!(handler.finalize(), b"!");
+    }
+
+    #[tokio::test]
+    async fn push_chunk_to_flushes_at_high_water_mark() {
+        let mut handler = StreamingOutputHandler::new(1024).with_high_water_mark(8);
+        let mut sink = Vec::new();
+        handler.push_chunk_to(b"aaaa", &mut sink).await.unwrap();
+        assert!(sink.is_empty());
+        // Crosses the 8-byte mark: the first chunk is flushed out.
+        handler.push_chunk_to(b"bbbbbb", &mut sink).await.unwrap();
+        assert_eq!(sink, b"aaaa");
+        assert_eq!(handler.finalize(), b"bbbbbb");
+    }
+
+    #[test]
+    fn finalize_string_lossy_keeps_output_around_invalid_bytes() {
+        let mut handler = StreamingOutputHandler::new(64);
+        handler.push_chunk(b"before \xff after").unwrap();
+        assert_eq!(handler.finalize_string_lossy(), "before \u{fffd} after");
+
+        let mut strict = StreamingOutputHandler::new(64);
+        strict.push_chunk(b"before \xff after").unwrap();
+        assert!(strict.finalize_string().is_err());
+    }
+
+    #[test]
+    fn finalize_with_encoding_decodes_legacy_charsets() {
+        let (bytes, _, _) = encoding_rs::SHIFT_JIS.encode("こんにちは");
+        let mut handler = StreamingOutputHandler::new(64);
+        handler.push_chunk(&bytes).unwrap();
+        assert_eq!(
+            handler.finalize_with_encoding(encoding_rs::SHIFT_JIS),
+            "こんにちは"
+        );
+
+        let mut latin = StreamingOutputHandler::new(64);
+        latin.push_chunk(b"caf\xe9").unwrap();
+        assert_eq!(
+            latin.finalize_with_encoding(encoding_rs::WINDOWS_1252),
+            "café"
+        );
+    }
+
+    #[test]
+    fn strip_ansi_removes_color_and_cursor_sequences() {
+        let colored = b"\x1b[1;32mok\x1b[0m plain \x1b[2J\x1b[10;20Hmoved";
+        assert_eq!(strip_ansi(colored), b"ok plain moved");
+
+        let osc_title = b"\x1b]0;window title\x07text";
+        assert_eq!(strip_ansi(osc_title), b"text");
+    }
+
+    #[test]
+    fn finalize_plain_resolves_carriage_return_overwrite() {
+        let mut handler = StreamingOutputHandler::new(256);
+        handler
+            .push_chunk(b"progress 10%\rprogress 50%\rprogress 100%\n\x1b[31mdone\x1b[0m\r\n")
+            .unwrap();
+        assert_eq!(handler.finalize_plain().unwrap(), "progress 100%\ndone\n");
+    }
+
+    #[test]
+    fn shared_budget_caps_handlers_collectively_and_frees_on_drop() {
+        let budget = Arc::new(StreamBudget::new(16));
+        let mut a = StreamingOutputHandler::new(64).with_budget(budget.clone());
+        let mut b = StreamingOutputHandler::new(64).with_budget(budget.clone());
+
+        a.push_chunk(b"aaaaaaaa").unwrap();
+        b.push_chunk(b"bbbbbbbb").unwrap();
+        assert_eq!(budget.used(), 16);
+
+        // Each handler has local headroom, but the budget is spent.
+        let err = b.push_chunk(b"x").unwrap_err();
+        assert!(err.to_string().contains("stream budget"), "{err:#}");
+
+        // Dropping one handler frees its share for the other.
+        drop(a);
+        assert_eq!(budget.used(), 8);
+        b.push_chunk(b"cccc").unwrap();
+        assert_eq!(b.finalize(), b"bbbbbbbbcccc");
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn concurrent_handlers_never_overshoot_the_budget() {
+        let budget = Arc::new(StreamBudget::new(1000));
+        let accepted: Vec<usize> = std::thread::scope(|scope| {
+            (0..8)
+                .map(|_| {
+                    let budget = budget.clone();
+                    scope.spawn(move || {
+                        let mut handler =
+                            StreamingOutputHandler::new(usize::MAX).with_budget(budget);
+                        let mut accepted = 0;
+                        for _ in 0..50 {
+                            if handler.push_chunk(&[0u8; 10]).is_ok() {
+                                accepted += 10;
+                            }
+                        }
+                        // Keep the charge alive until every thread has
+                        // finished pushing.
+                        std::mem::forget(handler);
+                        accepted
+                    })
+                })
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .collect()
+        });
+        let total: usize = accepted.iter().sum();
+        assert_eq!(total, budget.used());
+        assert!(total <= 1000, "budget overshot: {total}");
+        // 8 threads wanted 4000 bytes; contention must not have left
+        // the budget half-empty either.
+        assert_eq!(total, 1000);
+    }
+
+    #[test]
+    fn ring_mode_truncates_oversized_chunk_to_tail() {
+        let mut handler = StreamingOutputHandler::new_ring(4);
+        handler.push_chunk(b"0123456789").unwrap();
+        assert_eq!(handler.finalize(), b"6789");
+    }
+}
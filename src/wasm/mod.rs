@@ -0,0 +1,635 @@
+//! Sandboxed WebAssembly execution for command previews.
+//!
+//! Isolation over integration: modules run under WASI with capped CPU
+//! (fuel) and a copy-on-write overlay of the filesystem, so a preview
+//! can show what a command *would* do without letting it touch
+//! anything. The overlay is diffed afterwards into
+//! [`FilesystemChange`]s, which [`WasmRuntime::apply_changes`] replays
+//! for real once a user approves them.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use wasmtime::{Config, Engine, Linker, Module, ResourceLimiter, Store, Trap};
+use wasmtime_wasi::p1::{self, WasiP1Ctx};
+use wasmtime_wasi::p2::pipe::MemoryOutputPipe;
+use wasmtime_wasi::{FsPerms, WasiCtxBuilder};
+
+/// Bridge a [`wasmtime::Error`] (which is not a `std::error::Error`)
+/// into the crate's `anyhow` error chain.
+fn wasm_err(e: wasmtime::Error) -> anyhow::Error {
+    anyhow::anyhow!("{e:?}")
+}
+
+/// Fuel granted to a preview run; roughly bounds CPU time.
+const PREVIEW_FUEL: u64 = 500_000_000;
+
+/// Default memory cap per preview run.
+const PREVIEW_MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+/// Resource bounds applied to one sandboxed run.
+#[derive(Debug, Clone, Copy)]
+pub struct WasmLimits {
+    /// Fuel units the module may burn; roughly proportional to
+    /// executed instructions.
+    pub fuel: u64,
+    pub max_memory_bytes: usize,
+}
+
+impl Default for WasmLimits {
+    fn default() -> Self {
+        Self {
+            fuel: PREVIEW_FUEL,
+            max_memory_bytes: PREVIEW_MAX_MEMORY,
+        }
+    }
+}
+
+/// A module hit one of its [`WasmLimits`].
+#[derive(Debug, thiserror::Error)]
+pub enum WasmError {
+    #[error("module exhausted its fuel allowance of {0}")]
+    FuelExhausted(u64),
+    #[error("module exceeded the memory limit of {0} bytes")]
+    MemoryLimit(usize),
+}
+
+/// Upper bound on captured stdout/stderr per run.
+const MAX_CAPTURE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Host functions a plugin may import. Deliberately excludes anything
+/// touching sockets or paths: plugins extend command handling, they do
+/// not get network or filesystem access.
+const ALLOWED_PLUGIN_IMPORTS: &[(&str, &str)] = &[
+    ("wasi_snapshot_preview1", "fd_write"),
+    ("wasi_snapshot_preview1", "proc_exit"),
+    ("wasi_snapshot_preview1", "args_get"),
+    ("wasi_snapshot_preview1", "args_sizes_get"),
+    ("wasi_snapshot_preview1", "environ_get"),
+    ("wasi_snapshot_preview1", "environ_sizes_get"),
+    ("wasi_snapshot_preview1", "clock_time_get"),
+    ("wasi_snapshot_preview1", "random_get"),
+];
+
+/// A filesystem operation a previewed module attempted.
+///
+/// `path` is the real host path the change would land on — the
+/// previewed directory plus the path the module used inside the
+/// sandbox — so a recorded change can be applied verbatim.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum FilesystemChange {
+    Write { path: String, contents: Vec<u8> },
+    Delete { path: String },
+    Mkdir { path: String },
+}
+
+/// What a preview run produced and would have changed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub filesystem_changes: Vec<FilesystemChange>,
+}
+
+/// A validated, registered plugin module.
+pub struct Plugin {
+    pub name: String,
+    /// Names of the functions the module exports.
+    pub exports: Vec<String>,
+    #[allow(dead_code)]
+    module: Module,
+}
+
+/// Executes WebAssembly modules in a preview sandbox.
+pub struct WasmRuntime {
+    engine: Engine,
+    plugins: Mutex<HashMap<String, Plugin>>,
+}
+
+impl WasmRuntime {
+    pub fn new() -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Ok(Self {
+            engine: Engine::new(&config)
+                .map_err(wasm_err)
+                .context("creating wasm engine")?,
+            plugins: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Compile `module_bytes`, verify it only imports allowlisted host
+    /// functions, and register its exports under `name`.
+    ///
+    /// Rejection enumerates every disallowed import so plugin authors
+    /// see the full list in one round.
+    pub fn load_plugin(&self, name: &str, module_bytes: &[u8]) -> Result<()> {
+        let module = Module::from_binary(&self.engine, module_bytes)
+            .map_err(wasm_err)
+            .with_context(|| format!("compiling plugin {name}"))?;
+
+        let disallowed: Vec<String> = module
+            .imports()
+            .filter(|import| {
+                !ALLOWED_PLUGIN_IMPORTS
+                    .iter()
+                    .any(|(m, n)| *m == import.module() && *n == import.name())
+            })
+            .map(|import| format!("{}::{}", import.module(), import.name()))
+            .collect();
+        if !disallowed.is_empty() {
+            bail!(
+                "plugin {name} imports disallowed host functions: {}",
+                disallowed.join(", ")
+            );
+        }
+
+        let exports: Vec<String> = module
+            .exports()
+            .filter(|e| e.ty().func().is_some())
+            .map(|e| e.name().to_string())
+            .collect();
+        let plugin = Plugin {
+            name: name.to_string(),
+            exports,
+            module,
+        };
+        self.plugins
+            .lock()
+            .expect("plugin registry poisoned")
+            .insert(name.to_string(), plugin);
+        Ok(())
+    }
+
+    /// Exported function names of a registered plugin, if any.
+    pub fn plugin_exports(&self, name: &str) -> Option<Vec<String>> {
+        self.plugins
+            .lock()
+            .expect("plugin registry poisoned")
+            .get(name)
+            .map(|p| p.exports.clone())
+    }
+
+    /// Run `module_bytes` as a WASI command with a writable copy of
+    /// `preview_dir` mounted at `/`, capturing output.
+    ///
+    /// The module cannot modify the host: its writes land in a
+    /// throwaway overlay, which is diffed against `preview_dir`
+    /// afterwards into the result's `filesystem_changes`. `limits` cap
+    /// fuel (CPU) and memory; hitting a limit surfaces as a typed
+    /// [`WasmError`] in the error chain.
+    pub async fn execute_preview(
+        &self,
+        module_bytes: &[u8],
+        args: &[String],
+        preview_dir: &Path,
+        limits: WasmLimits,
+    ) -> Result<PreviewResult> {
+        let module = Module::from_binary(&self.engine, module_bytes)
+            .map_err(wasm_err)
+            .context("compiling wasm module")?;
+
+        let overlay = OverlayDir::copy_of(preview_dir)?;
+
+        let stdout = MemoryOutputPipe::new(MAX_CAPTURE_BYTES);
+        let stderr = MemoryOutputPipe::new(MAX_CAPTURE_BYTES);
+        let mut builder = WasiCtxBuilder::new();
+        builder
+            .stdout(stdout.clone())
+            .stderr(stderr.clone())
+            .args(args);
+        builder
+            .preopened_dir(&overlay.path, "/", FsPerms::ReadWrite)
+            .map_err(wasm_err)
+            .with_context(|| format!("preopening {}", overlay.path.display()))?;
+        let wasi = builder.build_p1();
+
+        let mut store = Store::new(
+            &self.engine,
+            PreviewState {
+                wasi,
+                limiter: MemoryLimiter {
+                    max_bytes: limits.max_memory_bytes,
+                    exceeded: false,
+                },
+            },
+        );
+        store.limiter(|state| &mut state.limiter);
+        store.set_fuel(limits.fuel).map_err(wasm_err)?;
+
+        let mut linker: Linker<PreviewState> = Linker::new(&self.engine);
+        p1::add_to_linker_async(&mut linker, |state| &mut state.wasi).map_err(wasm_err)?;
+
+        let instance = match linker.instantiate_async(&mut store, &module).await {
+            Ok(instance) => instance,
+            Err(e) => {
+                return Err(Self::limit_error(&store, limits, e)
+                    .context("instantiating wasm module"))
+            }
+        };
+        let start = instance
+            .get_typed_func::<(), ()>(&mut store, "_start")
+            .map_err(wasm_err)
+            .context("module has no _start export")?;
+
+        let exit_code = match start.call_async(&mut store, ()).await {
+            Ok(()) => 0,
+            Err(trap) => match trap.downcast_ref::<wasmtime_wasi::I32Exit>() {
+                Some(exit) => exit.0,
+                None => {
+                    return Err(Self::limit_error(&store, limits, trap)
+                        .context("wasm module trapped"))
+                }
+            },
+        };
+
+        let mut filesystem_changes = Vec::new();
+        diff_dir(preview_dir, &overlay.path, preview_dir, &mut filesystem_changes)
+            .context("diffing preview overlay")?;
+
+        Ok(PreviewResult {
+            stdout: String::from_utf8_lossy(&stdout.contents()).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr.contents()).into_owned(),
+            exit_code,
+            filesystem_changes,
+        })
+    }
+
+    /// Perform a preview's recorded changes on the real filesystem,
+    /// after user approval — the second half of the preview-then-apply
+    /// loop. Changes are applied in recorded order, so a new
+    /// directory's `Mkdir` always precedes the writes into it.
+    pub fn apply_changes(&self, preview: &PreviewResult) -> Result<()> {
+        for change in &preview.filesystem_changes {
+            match change {
+                FilesystemChange::Mkdir { path } => std::fs::create_dir_all(path)
+                    .with_context(|| format!("creating directory {path}"))?,
+                FilesystemChange::Write { path, contents } => std::fs::write(path, contents)
+                    .with_context(|| format!("writing {path}"))?,
+                FilesystemChange::Delete { path } => {
+                    let meta = std::fs::symlink_metadata(path)
+                        .with_context(|| format!("deleting {path}"))?;
+                    if meta.is_dir() {
+                        std::fs::remove_dir_all(path)
+                    } else {
+                        std::fs::remove_file(path)
+                    }
+                    .with_context(|| format!("deleting {path}"))?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Translate a trap into a typed [`WasmError`] when it was caused
+    /// by one of our limits, falling back to the raw engine error.
+    fn limit_error(store: &Store<PreviewState>, limits: WasmLimits, e: wasmtime::Error) -> anyhow::Error {
+        if store.data().limiter.exceeded {
+            return WasmError::MemoryLimit(limits.max_memory_bytes).into();
+        }
+        if e.downcast_ref::<Trap>() == Some(&Trap::OutOfFuel) {
+            return WasmError::FuelExhausted(limits.fuel).into();
+        }
+        wasm_err(e)
+    }
+}
+
+/// The copy-on-write overlay one preview run mutates: a temp-dir copy
+/// of the previewed directory, removed when the run ends — success,
+/// trap, or limit.
+struct OverlayDir {
+    path: PathBuf,
+}
+
+impl OverlayDir {
+    fn copy_of(src: &Path) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("rebe-preview-{}", uuid::Uuid::new_v4()));
+        let overlay = Self { path };
+        copy_tree(src, &overlay.path)
+            .with_context(|| format!("copying {} into the preview overlay", src.display()))?;
+        Ok(overlay)
+    }
+}
+
+impl Drop for OverlayDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Recursively copy the directory `src` to `dst`.
+fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dst.join(entry.file_name());
+        // Guards against previewing the directory the overlay itself
+        // lives in, which would otherwise copy forever.
+        if entry.path() == dst {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            copy_tree(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Record how `overlay` differs from `base` as changes against
+/// `real` — the host directory the preview ran against. Creations come
+/// parent-first so applying in order works; a deleted directory is one
+/// `Delete` (apply removes it recursively).
+fn diff_dir(
+    base: &Path,
+    overlay: &Path,
+    real: &Path,
+    changes: &mut Vec<FilesystemChange>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(overlay)
+        .with_context(|| format!("reading overlay directory {}", overlay.display()))?
+    {
+        let entry = entry?;
+        let base_path = base.join(entry.file_name());
+        let real_path = real.join(entry.file_name());
+        let path = real_path.to_string_lossy().into_owned();
+        let base_meta = std::fs::metadata(&base_path).ok();
+        if entry.file_type()?.is_dir() {
+            match &base_meta {
+                Some(m) if m.is_dir() => {}
+                // A file the module replaced with a directory.
+                Some(_) => {
+                    changes.push(FilesystemChange::Delete { path: path.clone() });
+                    changes.push(FilesystemChange::Mkdir { path });
+                }
+                None => changes.push(FilesystemChange::Mkdir { path }),
+            }
+            diff_dir(&base_path, &entry.path(), &real_path, changes)?;
+        } else {
+            let contents = std::fs::read(entry.path())
+                .with_context(|| format!("reading overlay file {}", entry.path().display()))?;
+            match &base_meta {
+                // A directory the module replaced with a file.
+                Some(m) if m.is_dir() => {
+                    changes.push(FilesystemChange::Delete { path: path.clone() });
+                    changes.push(FilesystemChange::Write { path, contents });
+                }
+                Some(_) if std::fs::read(&base_path).is_ok_and(|b| b == contents) => {}
+                _ => changes.push(FilesystemChange::Write { path, contents }),
+            }
+        }
+    }
+    if base.is_dir() {
+        for entry in std::fs::read_dir(base)? {
+            let entry = entry?;
+            if !overlay.join(entry.file_name()).exists() {
+                changes.push(FilesystemChange::Delete {
+                    path: real.join(entry.file_name()).to_string_lossy().into_owned(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Store state for one preview run: the WASI context plus the memory
+/// limiter, which records whether it ever had to deny a request.
+struct PreviewState {
+    wasi: WasiP1Ctx,
+    limiter: MemoryLimiter,
+}
+
+struct MemoryLimiter {
+    max_bytes: usize,
+    exceeded: bool,
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        if desired > self.max_bytes {
+            self.exceeded = true;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        _desired: usize,
+        _maximum: Option<usize>,
+    ) -> wasmtime::Result<bool> {
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A minimal WASI module printing one line to stdout.
+    const HELLO_WAT: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 8) "hello from wasm\n")
+          (func (export "_start")
+            (i32.store (i32.const 0) (i32.const 8))
+            (i32.store (i32.const 4) (i32.const 16))
+            (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 24)))))
+    "#;
+
+    #[test]
+    fn load_plugin_registers_exports() {
+        let runtime = WasmRuntime::new().unwrap();
+        let module = wat::parse_str(r#"(module (func (export "run")))"#).unwrap();
+        runtime.load_plugin("greeter", &module).unwrap();
+        assert_eq!(
+            runtime.plugin_exports("greeter").unwrap(),
+            vec!["run".to_string()]
+        );
+    }
+
+    #[test]
+    fn load_plugin_rejects_disallowed_imports() {
+        let runtime = WasmRuntime::new().unwrap();
+        let module = wat::parse_str(
+            r#"(module
+                 (import "wasi_snapshot_preview1" "sock_open"
+                   (func (param i32 i32) (result i32)))
+                 (func (export "run")))"#,
+        )
+        .unwrap();
+        let err = runtime.load_plugin("netty", &module).unwrap_err();
+        assert!(err.to_string().contains("sock_open"), "{err}");
+        assert!(runtime.plugin_exports("netty").is_none());
+    }
+
+    #[tokio::test]
+    async fn fuel_exhaustion_is_a_typed_error() {
+        let runtime = WasmRuntime::new().unwrap();
+        let module =
+            wat::parse_str(r#"(module (func (export "_start") (loop br 0)))"#).unwrap();
+        let limits = WasmLimits {
+            fuel: 10_000,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let err = runtime
+            .execute_preview(&module, &[], dir.path(), limits)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(WasmError::FuelExhausted(10_000))),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_limit_is_a_typed_error() {
+        let runtime = WasmRuntime::new().unwrap();
+        // 32 pages = 2 MiB of linear memory, over the 1 MiB cap below.
+        let module = wat::parse_str(r#"(module (memory 32) (func (export "_start")))"#).unwrap();
+        let limits = WasmLimits {
+            max_memory_bytes: 1024 * 1024,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let err = runtime
+            .execute_preview(&module, &[], dir.path(), limits)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(WasmError::MemoryLimit(_))),
+            "{err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn execute_preview_captures_stdout() {
+        let runtime = WasmRuntime::new().unwrap();
+        let module = wat::parse_str(HELLO_WAT).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let result = runtime
+            .execute_preview(&module, &[], dir.path(), WasmLimits::default())
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, "hello from wasm\n");
+        assert_eq!(result.exit_code, 0);
+        assert!(result.filesystem_changes.is_empty());
+    }
+
+    /// A WASI module creating `made.txt` in the mounted directory and
+    /// writing one line into it.
+    const WRITER_WAT: &str = r#"
+        (module
+          (import "wasi_snapshot_preview1" "path_open"
+            (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+          (import "wasi_snapshot_preview1" "fd_write"
+            (func $fd_write (param i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "made.txt")
+          (data (i32.const 16) "hello overlay\n")
+          (func (export "_start")
+            ;; iovec { ptr = 16, len = 14 } at 40
+            (i32.store (i32.const 40) (i32.const 16))
+            (i32.store (i32.const 44) (i32.const 14))
+            ;; open "made.txt" (O_CREAT, rights fd_write) under the
+            ;; preopened root; the new fd lands at 48
+            (drop (call $path_open
+              (i32.const 3)
+              (i32.const 0)
+              (i32.const 0) (i32.const 8)
+              (i32.const 1)
+              (i64.const 0x40) (i64.const 0)
+              (i32.const 0)
+              (i32.const 48)))
+            (drop (call $fd_write
+              (i32.load (i32.const 48))
+              (i32.const 40) (i32.const 1) (i32.const 52)))))
+    "#;
+
+    #[tokio::test]
+    async fn preview_captures_writes_without_touching_the_host() {
+        let runtime = WasmRuntime::new().unwrap();
+        let module = wat::parse_str(WRITER_WAT).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let result = runtime
+            .execute_preview(&module, &[], dir.path(), WasmLimits::default())
+            .await
+            .unwrap();
+
+        // The write was recorded, not performed.
+        assert!(!dir.path().join("made.txt").exists());
+        assert_eq!(
+            result.filesystem_changes,
+            vec![FilesystemChange::Write {
+                path: dir.path().join("made.txt").to_string_lossy().into_owned(),
+                contents: b"hello overlay\n".to_vec(),
+            }]
+        );
+
+        // Approval replays it for real.
+        runtime.apply_changes(&result).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("made.txt")).unwrap(),
+            "hello overlay\n"
+        );
+    }
+
+    #[test]
+    fn overlay_diff_covers_modifications_creations_and_deletions() {
+        let base = tempfile::tempdir().unwrap();
+        std::fs::write(base.path().join("keep.txt"), "same").unwrap();
+        std::fs::write(base.path().join("old.txt"), "before").unwrap();
+        std::fs::create_dir(base.path().join("gone")).unwrap();
+        std::fs::write(base.path().join("gone/file"), "x").unwrap();
+
+        let overlay = tempfile::tempdir().unwrap();
+        std::fs::write(overlay.path().join("keep.txt"), "same").unwrap();
+        std::fs::write(overlay.path().join("old.txt"), "after").unwrap();
+        std::fs::create_dir(overlay.path().join("new")).unwrap();
+        std::fs::write(overlay.path().join("new/nested.txt"), "created").unwrap();
+
+        // A third copy of the base plays the real tree: applying the
+        // recorded changes must make it match the overlay.
+        let target = tempfile::tempdir().unwrap();
+        copy_tree(base.path(), target.path()).unwrap();
+        let mut changes = Vec::new();
+        diff_dir(base.path(), overlay.path(), target.path(), &mut changes).unwrap();
+        assert_eq!(changes.len(), 4, "{changes:?}");
+
+        let preview = PreviewResult {
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: 0,
+            filesystem_changes: changes,
+        };
+        WasmRuntime::new().unwrap().apply_changes(&preview).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("keep.txt")).unwrap(),
+            "same"
+        );
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("old.txt")).unwrap(),
+            "after"
+        );
+        assert_eq!(
+            std::fs::read_to_string(target.path().join("new/nested.txt")).unwrap(),
+            "created"
+        );
+        assert!(!target.path().join("gone").exists());
+    }
+}